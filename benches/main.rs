@@ -1,8 +1,12 @@
 use anyhow::Result;
-use crabgrad::engine::{norm, sum, Value};
-use crabgrad::nn::{Module as _, MLP};
+use crabgrad::Tensor;
+use crabgrad::engine::{Value, dot, norm, sum};
+use crabgrad::nn::loss::nll_loss;
+use crabgrad::nn::{Activation, Layer, MLP, Module as _};
 use crabgrad::optim::{Optim as _, SGD};
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{Criterion, criterion_group, criterion_main};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
 
 fn ops_in_loop(n: usize) {
     let mut value = Value::from(1.0);
@@ -13,7 +17,7 @@ fn ops_in_loop(n: usize) {
 
 fn mlp_sgd(n: usize) -> Result<()> {
     {
-        let mut model = MLP::new(10, &[10], 2, true);
+        let mut model = MLP::new(10, &[10], 2, true, Activation::ReLU, &mut StdRng::seed_from_u64(0));
         let data: Vec<Value> = (0..10).map(|_| Value::from(12.345)).collect();
         let mut optim = SGD::new(&model.parameters(), 0.1);
 
@@ -48,6 +52,47 @@ fn norm_b(n: usize) {
     }
 }
 
+fn sum_backward(n: usize) {
+    let values: Vec<Value> = (0..n).map(|i| Value::from(i as f64)).collect();
+    sum(&values).backward();
+}
+
+fn dot_backward(n: usize) {
+    let a: Vec<Value> = (0..n).map(|i| Value::from(i as f64)).collect();
+    let b: Vec<Value> = (0..n).map(|i| Value::from(1.0 / (i as f64 + 1.0))).collect();
+    dot(&a, &b).backward();
+}
+
+fn layer_batch(batch: usize, dim: usize) -> Vec<f64> {
+    (0..batch * dim).map(|i| (i as f64 * 0.37).sin()).collect()
+}
+
+fn layer_scalar(layer: &Layer, batch: &[f64], dim: usize) -> Result<()> {
+    let mut outputs = vec![];
+    for sample in batch.chunks_exact(dim) {
+        let sample: Vec<Value> = sample.iter().map(Value::from).collect();
+        outputs.extend(layer.forward(&sample)?);
+    }
+    sum(&outputs).backward();
+    Ok(())
+}
+
+fn layer_matmul(layer: &Layer, batch: &[f64], dim: usize) -> Result<()> {
+    let x = Tensor::from_data(batch, &[batch.len() / dim, dim])?;
+    layer.forward_tensor(&x)?.sum().backward();
+    Ok(())
+}
+
+/// One `Trainer` step of the mnist example's model on a batch of flattened 28x28 images
+fn mnist_step(model: &MLP, optim: &mut SGD, batch: &[f64], labels: &[usize]) -> Result<()> {
+    let x = Tensor::from_data(batch, &[labels.len(), 784])?;
+    let loss = nll_loss(&model.forward_tensor(&x)?.log_softmax(1)?, labels)?.sum();
+    optim.zero_grad();
+    loss.backward();
+    optim.step();
+    Ok(())
+}
+
 pub fn criterion_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("basic-benchmarks");
     group.sample_size(100);
@@ -55,6 +100,19 @@ pub fn criterion_benchmark(c: &mut Criterion) {
     group.bench_function("mlp_sgd 10_000", |b| b.iter(|| mlp_sgd(10_000)));
     group.bench_function("norm_a 100", |b| b.iter(|| norm_a(100)));
     group.bench_function("norm_b 100", |b| b.iter(|| norm_b(100)));
+    group.bench_function("sum_backward 1_000", |b| b.iter(|| sum_backward(1_000)));
+    group.bench_function("dot_backward 1_000", |b| b.iter(|| dot_backward(1_000)));
+
+    let layer = Layer::new(64, 64, true, Activation::ReLU, &mut StdRng::seed_from_u64(0));
+    let batch = layer_batch(32, 64);
+    group.bench_function("layer_scalar 32x64x64", |b| b.iter(|| layer_scalar(&layer, &batch, 64)));
+    group.bench_function("layer_matmul 32x64x64", |b| b.iter(|| layer_matmul(&layer, &batch, 64)));
+
+    let model = MLP::new(784, &[], 10, true, Activation::ReLU, &mut StdRng::seed_from_u64(0));
+    let mut optim = SGD::new(&model.parameters(), 0.01);
+    let batch = layer_batch(32, 784);
+    let labels: Vec<usize> = (0..32).map(|i| i % 10).collect();
+    group.bench_function("mnist_step 32x784x10", |b| b.iter(|| mnist_step(&model, &mut optim, &batch, &labels)));
 }
 
 criterion_group!(benches, criterion_benchmark);
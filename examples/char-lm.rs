@@ -0,0 +1,97 @@
+use anyhow::Result;
+use crabgrad::Tensor;
+use crabgrad::engine::Value;
+use crabgrad::nn::{Embedding, Module, TransformerEncoderLayer, cross_entropy};
+use crabgrad::optim::{AdamW, Optim};
+use crabgrad::utils::init_logging;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const TEXT: &str = "the quick brown fox jumps over the lazy dog. \
+    the lazy dog sleeps in the sun while the quick fox runs. \
+    a fox and a dog are friends, and the sun shines on them both. ";
+
+/// A one-layer causal transformer over characters: token and position embeddings, an encoder layer that only looks
+/// back, and a projection from each position to logits for the next character
+struct CharLM {
+    token: Embedding,
+    position: Embedding,
+    layer: TransformerEncoderLayer,
+    /// `(embed_dim, vocab)`
+    head: Tensor,
+}
+
+impl CharLM {
+    fn new(vocab: usize, context: usize, embed_dim: usize, rng: &mut impl Rng) -> Result<Self> {
+        let head: Vec<f64> = (0..embed_dim * vocab).map(|_| rng.random_range(-0.1..0.1)).collect();
+        Ok(Self {
+            token: Embedding::new(vocab, embed_dim, rng)?,
+            position: Embedding::new(context, embed_dim, rng)?,
+            layer: TransformerEncoderLayer::new(embed_dim, 2, 4 * embed_dim, rng)?.with_causal_mask().with_norm_first(),
+            head: Tensor::from_data(&head, &[embed_dim, vocab])?,
+        })
+    }
+
+    /// `batch` sequences of `seq` token ids, laid out row-major, to `(batch * seq, vocab)` logits
+    fn forward(&self, tokens: &[usize], batch: usize, seq: usize) -> Result<Tensor> {
+        let embed_dim = self.token.dim();
+        let positions: Vec<usize> = (0..seq).collect();
+        let x = &self.token.lookup(tokens)?.reshape(&[batch, seq, embed_dim])? + &self.position.lookup(&positions)?;
+        let x = self.layer.forward_tensor(&x)?;
+        x.reshape(&[batch * seq, embed_dim])?.matmul(&self.head)
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        let modules: [&dyn Module; 3] = [&self.token, &self.position, &self.layer];
+        modules.into_iter().flat_map(Module::parameters).chain(self.head.values().iter().cloned()).collect()
+    }
+}
+
+fn main() -> Result<()> {
+    init_logging();
+    let (context, embed_dim, batch_size, steps) = (16, 16, 8, 200);
+
+    let vocab: Vec<char> = {
+        let mut chars: Vec<char> = TEXT.chars().collect();
+        chars.sort_unstable();
+        chars.dedup();
+        chars
+    };
+    let encode = |c: char| vocab.binary_search(&c).expect("every character is in the vocabulary");
+    let ids: Vec<usize> = TEXT.chars().map(encode).collect();
+    log::info!("{} characters, vocabulary of {}", ids.len(), vocab.len());
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let model = CharLM::new(vocab.len(), context, embed_dim, &mut rng)?;
+    log::info!("Number parameters: {}", model.parameters().len());
+    let mut optim = AdamW::new(model.parameters(), 1e-2, 0.9, 0.999, 1e-8, 0.0);
+
+    for step in 0..steps {
+        // Random windows, each paired with the same window shifted by one character
+        let (mut inputs, mut targets) = (vec![], vec![]);
+        for _ in 0..batch_size {
+            let start = rng.random_range(0..ids.len() - context);
+            inputs.extend_from_slice(&ids[start..start + context]);
+            targets.extend_from_slice(&ids[start + 1..=start + context]);
+        }
+        let loss = cross_entropy(&model.forward(&inputs, batch_size, context)?, &targets)?;
+
+        optim.zero_grad();
+        loss.backward();
+        optim.step();
+        if step % 20 == 0 || step + 1 == steps {
+            log::info!("step {step}: loss {:.4}", loss.data());
+        }
+    }
+
+    // Greedy decoding, feeding the last `context` characters back in
+    let mut generated: Vec<usize> = "the ".chars().map(encode).collect();
+    for _ in 0..60 {
+        let window = &generated[generated.len().saturating_sub(context)..];
+        let logits = model.forward(window, 1, window.len())?;
+        let last = &logits.values()[(window.len() - 1) * vocab.len()..];
+        generated.push(crabgrad::argmax(last));
+    }
+    log::info!("Sample: {:?}", generated.iter().map(|&id| vocab[id]).collect::<String>());
+    Ok(())
+}
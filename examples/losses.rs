@@ -1,4 +1,4 @@
-use crabgrad::engine::{exp, to_vec, DiscreteLabel, Value};
+use crabgrad::engine::{DiscreteLabel, Value, exp, to_vec};
 use crabgrad::nn::loss::{log_softmax, nll_loss_single};
 use crabgrad::optim::{Optim, SGD};
 
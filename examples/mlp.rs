@@ -1,8 +1,10 @@
 use anyhow::Result;
-use crabgrad::nn::{Module, Trainer, MLP};
+use crabgrad::nn::{Activation, MLP, Module, Trainer};
 // use crabgrad::optim::SGD;
 use crabgrad::optim::AdamW;
 use crabgrad::utils::{init_logging, make_binary_classification};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
 
 fn main() -> Result<()> {
     let n_features = 64;
@@ -17,7 +19,7 @@ fn main() -> Result<()> {
     let (train_dataset, test_dataset) = dataset.train_test_split(0.8, 0.2)?;
 
     // NOTE - performance for this toy problem is fragile and sensitive to hidden dims and weight init
-    let model = MLP::new(n_features, &[32], n_classes, true);
+    let model = MLP::new(n_features, &[32], n_classes, true, Activation::ReLU, &mut StdRng::seed_from_u64(0));
     log::info!("Number parameters: {}", model.parameters().len());
     // let optim = SGD::new(model.parameters(), 1e-3);
     let mut optim = AdamW::new(model.parameters(), 1e-2, 0.9, 0.999, 1e-8, 0.0);
@@ -1,11 +1,13 @@
 use anyhow::Result;
 use crabgrad::{
     engine::{Dataset, DiscreteLabel, FloatDataScalar},
-    nn::{Module, Trainer, MLP},
+    nn::{Activation, MLP, Module, Trainer},
     optim::AdamW,
 };
-use hf_hub::{api::sync::Api, Repo, RepoType};
+use hf_hub::{Repo, RepoType, api::sync::Api};
 use parquet::file::reader::SerializedFileReader;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
 // use parquet::file::reader::FileReader;
 
 fn load_parquet(
@@ -67,7 +69,7 @@ fn main() -> Result<()> {
 
     let epochs = 2;
     let batch_size = 32;
-    let model = MLP::new(28 * 28, &[], 10, true);
+    let model = MLP::new(28 * 28, &[], 10, true, Activation::ReLU, &mut StdRng::seed_from_u64(0));
     let mut optim = AdamW::new(model.parameters(), 1e-3, 0.9, 0.999, 1e-8, 0.0);
     let mut trainer = Trainer::new(&model, &mut optim, epochs, batch_size);
     trainer.fit(train_data_labels, Some(test_data_labels))?;
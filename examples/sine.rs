@@ -0,0 +1,90 @@
+use anyhow::Result;
+use crabgrad::Tensor;
+use crabgrad::engine::{FloatDataScalar, Value};
+use crabgrad::nn::{Conv1d, Conv1dConfig, Module};
+use crabgrad::optim::{AdamW, Optim};
+use crabgrad::utils::init_logging;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// A small temporal convolutional network: stacked causal convolutions with growing dilation, so every output sees
+/// the last 7 inputs and none of the future ones
+struct Forecaster {
+    layers: Vec<Conv1d>,
+}
+
+impl Forecaster {
+    fn new(rng: &mut impl Rng) -> Result<Self> {
+        let causal = |dilation| Conv1dConfig { dilation, causal: true, ..Default::default() };
+        Ok(Self {
+            layers: vec![
+                Conv1d::new(1, 8, 3, causal(1), rng)?,
+                Conv1d::new(8, 8, 3, causal(2), rng)?,
+                Conv1d::new(8, 1, 1, Conv1dConfig::default(), rng)?,
+            ],
+        })
+    }
+
+    /// `(batch, 1, time)` to a one-step-ahead prediction at every time step
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let mut x = x.clone();
+        for (idx, layer) in self.layers.iter().enumerate() {
+            x = layer.forward_tensor(&x)?;
+            if idx + 1 < self.layers.len() {
+                x = x.map(Value::relu);
+            }
+        }
+        Ok(x)
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        self.layers.iter().flat_map(Module::parameters).collect()
+    }
+}
+
+fn wave(t: usize) -> FloatDataScalar {
+    (t as FloatDataScalar * 0.3).sin()
+}
+
+/// Windows starting at `starts`, as `(batch, 1, window)` inputs and the same windows shifted by one step as targets
+fn batch(starts: &[usize], window: usize) -> Result<(Tensor, Tensor)> {
+    let series = |offset: usize| -> Vec<FloatDataScalar> {
+        starts.iter().flat_map(|&start| (start + offset..start + offset + window).map(wave)).collect()
+    };
+    let shape = [starts.len(), 1, window];
+    Ok((Tensor::from_data(&series(0), &shape)?, Tensor::from_data(&series(1), &shape)?))
+}
+
+fn main() -> Result<()> {
+    init_logging();
+    let (window, batch_size, steps) = (24, 16, 300);
+
+    let model = Forecaster::new(&mut StdRng::seed_from_u64(0))?;
+    log::info!("Number parameters: {}", model.parameters().len());
+    let mut optim = AdamW::new(model.parameters(), 1e-2, 0.9, 0.999, 1e-8, 0.0);
+
+    for step in 0..steps {
+        let starts: Vec<usize> = (0..batch_size).map(|i| (step * batch_size + i) * 7 % 200).collect();
+        let (x, target) = batch(&starts, window)?;
+        let error = &model.forward(&x)? - &target;
+        let loss = (&error * &error).mean();
+
+        optim.zero_grad();
+        loss.backward();
+        optim.step();
+        if step % 50 == 0 || step + 1 == steps {
+            log::info!("step {step}: mse {:.6}", loss.data());
+        }
+    }
+
+    // Forecast past the end of an unseen window, feeding each prediction back in as the next input
+    let start = 1000;
+    let mut history: Vec<FloatDataScalar> = (start..start + window).map(wave).collect();
+    for t in start + window..start + window + 10 {
+        let context = Tensor::from_data(&history[history.len() - window..], &[1, 1, window])?;
+        let prediction = *model.forward(&context)?.data().last().expect("window is not empty");
+        log::info!("t = {t}: predicted {prediction:+.4}, actual {:+.4}", wave(t));
+        history.push(prediction);
+    }
+    Ok(())
+}
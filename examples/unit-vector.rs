@@ -1,8 +1,10 @@
 use anyhow::Result;
-use crabgrad::engine::{sum, to_vec, FloatDataScalar, Value};
-use crabgrad::nn::{Layer, Module};
+use crabgrad::engine::{FloatDataScalar, Value, sum, to_vec};
+use crabgrad::nn::{Activation, Layer, Module};
 use crabgrad::optim::{Optim, SGD};
 use crabgrad::{assert_close, assert_vec_close};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
 
 fn main() -> Result<()> {
     fn get_weights(layer: &Layer) -> Vec<FloatDataScalar> {
@@ -13,7 +15,7 @@ fn main() -> Result<()> {
         weights
     }
 
-    let mut layer = Layer::new(3, 1, false, false);
+    let mut layer = Layer::new(3, 1, false, Activation::Identity, &mut StdRng::seed_from_u64(0));
     let data = vec![Value::from(1.0), Value::from(0.0), Value::from(0.0)];
     let mut optim = SGD::new(&layer.parameters(), 0.1);
 
@@ -0,0 +1,217 @@
+use crate::engine::{Value, dot, prod, sum};
+use crate::tensor::{Tensor, strided_indices, strides};
+use anyhow::{Result, bail};
+
+/// Einstein summation over `operands`, e.g. `einsum("bij,bjk->bik", &[&a, &b])` for a batched matrix product.
+///
+/// Each operand gets one letter per dimension. Letters shared between operands must have the same size, and a letter
+/// repeated within one operand takes its diagonal (`"ii->"` is the trace). Letters missing from the output are summed
+/// over. Without `->`, the output is every letter that appears exactly once, in alphabetical order, as in NumPy.
+///
+/// Every output element becomes a single `dot` (two operands), `sum` (one operand) or sum of `prod` nodes, so the
+/// result is differentiable like any other graph of `Value`s
+pub fn einsum(spec: &str, operands: &[&Tensor]) -> Result<Tensor> {
+    let spec: String = spec.chars().filter(|c| !c.is_whitespace()).collect();
+    let (inputs, output) = match spec.split_once("->") {
+        Some((inputs, output)) => (inputs, output.to_string()),
+        None => (spec.as_str(), implicit_output(&spec)),
+    };
+    let inputs: Vec<&str> = inputs.split(',').collect();
+    if let Some(c) = spec.chars().find(|&c| !c.is_ascii_alphabetic() && !",->".contains(c)) {
+        bail!("unsupported character {c:?} in einsum subscripts {spec:?}");
+    }
+    if inputs.len() != operands.len() {
+        bail!("einsum subscripts {spec:?} describe {} operands, but {} were given", inputs.len(), operands.len());
+    }
+
+    // Size of each letter, checked for consistency across and within operands
+    let mut sizes: Vec<(char, usize)> = vec![];
+    for (subscripts, operand) in inputs.iter().zip(operands) {
+        if subscripts.len() != operand.ndim() {
+            bail!("subscripts {subscripts:?} do not match an operand of shape {:?}", operand.shape());
+        }
+        for (letter, &size) in subscripts.chars().zip(operand.shape()) {
+            match sizes.iter().find(|(l, _)| *l == letter) {
+                Some(&(_, known)) if known != size => {
+                    bail!("subscript {letter:?} has size {known} and {size} in einsum {spec:?}")
+                }
+                Some(_) => {}
+                None => sizes.push((letter, size)),
+            }
+        }
+    }
+    for (idx, letter) in output.chars().enumerate() {
+        if !sizes.iter().any(|(l, _)| *l == letter) {
+            bail!("output subscript {letter:?} does not appear in any input of einsum {spec:?}");
+        }
+        if output[idx + 1..].contains(letter) {
+            bail!("output subscript {letter:?} repeats in einsum {spec:?}");
+        }
+    }
+
+    // Iterate over a grid of output letters followed by contracted letters, so that the terms summed into each
+    // output element are contiguous
+    let letters: Vec<char> =
+        output.chars().chain(sizes.iter().map(|(l, _)| *l).filter(|l| !output.contains(*l))).collect();
+    let size_of = |letter: char| sizes.iter().find(|(l, _)| *l == letter).map_or(0, |(_, size)| *size);
+    let grid: Vec<usize> = letters.iter().map(|&l| size_of(l)).collect();
+    let out_shape: Vec<usize> = output.chars().map(size_of).collect();
+    let terms: usize = grid[out_shape.len()..].iter().product();
+
+    // Flat index into each operand for every grid point. A letter repeated within an operand sums its strides
+    let indices: Vec<Vec<usize>> = inputs
+        .iter()
+        .zip(operands)
+        .map(|(subscripts, operand)| {
+            let strides = strides(operand.shape());
+            let letter_strides: Vec<usize> = letters
+                .iter()
+                .map(|&l| subscripts.chars().zip(&strides).filter(|(s, _)| *s == l).map(|(_, stride)| stride).sum())
+                .collect();
+            strided_indices(&grid, &letter_strides, 0)
+        })
+        .collect();
+
+    let numel: usize = out_shape.iter().product();
+    let factors = |term: usize| -> Vec<Value> {
+        indices.iter().zip(operands).map(|(idx, operand)| operand.values()[idx[term]].clone()).collect()
+    };
+    let data = (0..numel)
+        .map(|out| {
+            let range = out * terms..(out + 1) * terms;
+            match operands {
+                [operand] => sum(&range.map(|term| operand.values()[indices[0][term]].clone()).collect::<Vec<_>>()),
+                [lhs, rhs] => {
+                    let lhs_values: Vec<Value> =
+                        range.clone().map(|term| lhs.values()[indices[0][term]].clone()).collect();
+                    let rhs_values: Vec<Value> = range.map(|term| rhs.values()[indices[1][term]].clone()).collect();
+                    dot(&lhs_values, &rhs_values)
+                }
+                _ => sum(&range.map(|term| prod(&factors(term))).collect::<Vec<_>>()),
+            }
+        })
+        .collect();
+    Tensor::new(data, &out_shape)
+}
+
+/// Letters that appear exactly once, sorted
+fn implicit_output(spec: &str) -> String {
+    let mut once: Vec<char> =
+        spec.chars().filter(|&c| c.is_ascii_alphabetic() && spec.matches(c).count() == 1).collect();
+    once.sort_unstable();
+    once.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_close;
+    use crate::engine::FloatDataScalar;
+
+    #[allow(clippy::cast_precision_loss)]
+    fn arange(shape: &[usize], offset: FloatDataScalar) -> Tensor {
+        let numel: usize = shape.iter().product();
+        Tensor::from_data(&(0..numel).map(|i| (i as FloatDataScalar).mul_add(0.5, offset)).collect::<Vec<_>>(), shape)
+            .unwrap()
+    }
+
+    #[test]
+    fn matmul_and_batched() -> Result<()> {
+        let (a, b) = (arange(&[2, 3], 1.0), arange(&[3, 4], -2.0));
+        let expected = a.matmul(&b)?;
+        assert_eq!(einsum("ij,jk->ik", &[&a, &b])?.data(), expected.data());
+        assert_eq!(einsum("ij,jk", &[&a, &b])?.data(), expected.data());
+        assert_eq!(einsum("ij,jk->ki", &[&a, &b])?.data(), expected.transpose(0, 1)?.data());
+
+        let (a, b) = (arange(&[3, 2, 4], 0.5), arange(&[3, 4, 2], -1.0));
+        let c = einsum("bij, bjk -> bik", &[&a, &b])?;
+        assert_eq!(c.shape(), &[3, 2, 2]);
+        assert_eq!(c.data(), a.bmm(&b)?.data());
+        Ok(())
+    }
+
+    #[test]
+    fn trace_diagonal_and_sum() -> Result<()> {
+        let a = arange(&[3, 3], 1.0);
+        let trace = einsum("ii->", &[&a])?;
+        assert_eq!(trace.shape(), &[] as &[usize]);
+        assert_eq!(trace.data(), vec![1.0 + 3.0 + 5.0]);
+        assert_eq!(einsum("ii->i", &[&a])?.data(), vec![1.0, 3.0, 5.0]);
+        assert_eq!(einsum("ij->", &[&a])?.data(), vec![a.sum().data()]);
+        assert_eq!(einsum("ij->j", &[&a])?.data(), a.sum_dim(0, false)?.data());
+
+        // Only the diagonal receives gradient from the trace
+        trace.backward();
+        assert_eq!(a.grad().unwrap(), vec![1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn outer_products() -> Result<()> {
+        let (u, v) = (arange(&[2], 1.0), arange(&[3], 2.0));
+        let outer = einsum("i,j->ij", &[&u, &v])?;
+        assert_eq!(outer.shape(), &[2, 3]);
+        assert_eq!(outer.data(), vec![2.0, 2.5, 3.0, 3.0, 3.75, 4.5]);
+
+        // Batched outer product, then backward: d sum(u_i v_j) / du_i = sum_j v_j
+        let (u, v) = (arange(&[2, 3], 0.0), arange(&[2, 4], 1.0));
+        let outer = einsum("bi,bj->bij", &[&u, &v])?;
+        assert_eq!(outer.shape(), &[2, 3, 4]);
+        outer.sum().backward();
+        let v_row_sums = v.sum_dim(1, false)?.data();
+        for (idx, grad) in u.grad().unwrap().into_iter().enumerate() {
+            assert_close!(grad, v_row_sums[idx / 3]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn three_operands() -> Result<()> {
+        // Bilinear form x^T W y
+        let (x, w, y) = (arange(&[2], 1.0), arange(&[2, 3], -1.0), arange(&[3], 0.5));
+        let bilinear = einsum("i,ij,j->", &[&x, &w, &y])?;
+        let expected = einsum("i,i->", &[&x, &einsum("ij,j->i", &[&w, &y])?])?;
+        assert_close!(bilinear.data()[0], expected.data()[0]);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_specs() {
+        let (a, b) = (arange(&[2, 3], 0.0), arange(&[4, 2], 0.0));
+        assert!(einsum("ij,jk->ik", &[&a, &b]).is_err());
+        assert!(einsum("ij->ik", &[&a]).is_err());
+        assert!(einsum("ij->ii", &[&a]).is_err());
+        assert!(einsum("ijk->i", &[&a]).is_err());
+        assert!(einsum("ii->i", &[&a]).is_err());
+        assert!(einsum("ij,jk->ik", &[&a]).is_err());
+        assert!(einsum("...j->j", &[&a]).is_err());
+    }
+
+    #[test]
+    fn compare_torch_einsum() {
+        let data_a: Vec<FloatDataScalar> = (0..24).map(|i| (f64::from(i) * 0.3).sin()).collect();
+        let data_b: Vec<FloatDataScalar> = (0..12).map(|i| (f64::from(i) * 0.7).cos()).collect();
+        for spec in ["bij,jk->bik", "bij,jk->kb", "bij,ik->bjk", "bii,ik->k"] {
+            let (shape_a, shape_b): (&[usize], &[usize]) =
+                if spec.starts_with("bii") { (&[6, 2, 2], &[2, 6]) } else { (&[2, 3, 4], &[4, 3]) };
+            let shape_b = if spec == "bij,ik->bjk" { &[3, 4][..] } else { shape_b };
+            let a = Tensor::from_data(&data_a, shape_a).unwrap();
+            let b = Tensor::from_data(&data_b, shape_b).unwrap();
+            let y = einsum(spec, &[&a, &b]).unwrap();
+            (&y * &y).sum().backward();
+
+            let dims = |shape: &[usize]| shape.iter().map(|&d| d as i64).collect::<Vec<_>>();
+            let at = tch::Tensor::from_slice(&data_a).reshape(dims(shape_a)).set_requires_grad(true);
+            let bt = tch::Tensor::from_slice(&data_b).reshape(dims(shape_b)).set_requires_grad(true);
+            let yt = tch::Tensor::einsum(spec, &[&at, &bt], None::<&[i64]>);
+            (&yt * &yt).sum(None).backward();
+
+            let flat = |t: &tch::Tensor| Vec::<FloatDataScalar>::try_from(t.reshape([-1])).unwrap();
+            let theirs_shape: Vec<usize> = yt.size().iter().map(|&d| d as usize).collect();
+            assert_eq!(y.shape(), theirs_shape);
+            y.data().iter().zip(flat(&yt)).for_each(|(l, r)| assert_close!(*l, r));
+            a.grad().unwrap().iter().zip(flat(&at.grad())).for_each(|(l, r)| assert_close!(*l, r));
+            b.grad().unwrap().iter().zip(flat(&bt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+        }
+    }
+}
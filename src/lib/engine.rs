@@ -1,4 +1,5 @@
 use crate::impl_binary_op;
+use crate::kernels;
 use anyhow::Result;
 use anyhow::bail;
 use core::f64;
@@ -9,11 +10,12 @@ use rand::SeedableRng;
 use rand::seq::SliceRandom;
 use std::cell::RefCell;
 use std::collections::HashSet;
+use std::fmt;
 use std::hash::Hash;
 use std::hash::Hasher;
 use std::ops::{Add, Deref, Div, Mul, Sub};
 use std::ptr;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 
 pub type FloatDataScalar = f64;
 pub type IntDataScalar = i64;
@@ -76,12 +78,53 @@ impl<X, Y> IntoIterator for Dataset<X, Y> {
     }
 }
 
+/// Which operation produced a node. Recorded alongside `backward_fn` so a graph can be inspected after it is built
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Leaf,
+    Add,
+    Mul,
+    Div,
+    /// Power where the exponent is itself a node in the graph
+    Pow,
+    /// Power with a constant integer exponent
+    Powi(i32),
+    /// Power with a constant float exponent
+    Powf(FloatDataScalar),
+    Log,
+    ReLU,
+    Tanh,
+    Sigmoid,
+    Sum,
+    Mean,
+    Dot,
+    Prod,
+    /// Built with `Value::custom` or `Value::custom_multi`
+    Custom(&'static str),
+    /// The given output of a `Value::custom_multi` node, which is its only ancestor
+    Output(usize),
+}
+
+/// Backward pass of a custom node: given the node's ancestors and its own gradient, return the gradient contribution
+/// for each ancestor, in order
+#[derive(Clone)]
+pub struct CustomBackward(Rc<CustomBackwardFn>);
+type CustomBackwardFn = dyn Fn(&[Value], FloatDataScalar) -> Vec<FloatDataScalar>;
+
+impl fmt::Debug for CustomBackward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CustomBackward")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ValueInner {
     pub data: FloatDataScalar,
     pub grad: Option<FloatDataScalar>,
     pub backward_fn: Option<fn(&ValueInner)>,
     pub prev_nodes: Option<Vec<Value>>,
+    pub op: Op,
+    pub custom_backward: Option<CustomBackward>,
 }
 
 #[derive(Debug)]
@@ -139,35 +182,60 @@ impl Hash for Value {
 }
 
 impl ValueInner {
+    /// A node recorded as `Op::Custom("op")`, see `with_op`
     #[must_use]
     pub fn new(data: FloatDataScalar, prev_nodes: Option<Vec<Value>>, backward_fn: Option<fn(&Self)>) -> Self {
-        Self { data, grad: None, prev_nodes, backward_fn }
+        Self::with_op(data, prev_nodes, backward_fn, Op::Custom("op"))
+    }
+
+    #[must_use]
+    pub fn with_op(
+        data: FloatDataScalar,
+        prev_nodes: Option<Vec<Value>>,
+        backward_fn: Option<fn(&Self)>,
+        op: Op,
+    ) -> Self {
+        Self { data, grad: None, prev_nodes, backward_fn, op, custom_backward: None }
     }
 }
 
 impl From<FloatDataScalar> for ValueInner {
     #[inline]
     fn from(data: FloatDataScalar) -> Self {
-        Self { data, grad: None, backward_fn: None, prev_nodes: None }
+        Self { data, grad: None, backward_fn: None, prev_nodes: None, op: Op::Leaf, custom_backward: None }
     }
 }
 impl From<&FloatDataScalar> for ValueInner {
     #[inline]
     fn from(data: &FloatDataScalar) -> Self {
-        Self { data: *data, grad: None, backward_fn: None, prev_nodes: None }
+        Self { data: *data, grad: None, backward_fn: None, prev_nodes: None, op: Op::Leaf, custom_backward: None }
     }
 }
 
 impl From<IntDataScalar> for ValueInner {
     #[inline]
     fn from(data: IntDataScalar) -> Self {
-        Self { data: data as FloatDataScalar, grad: None, backward_fn: None, prev_nodes: None }
+        Self {
+            data: data as FloatDataScalar,
+            grad: None,
+            backward_fn: None,
+            prev_nodes: None,
+            op: Op::Leaf,
+            custom_backward: None,
+        }
     }
 }
 impl From<&IntDataScalar> for ValueInner {
     #[inline]
     fn from(data: &IntDataScalar) -> Self {
-        Self { data: *data as FloatDataScalar, grad: None, backward_fn: None, prev_nodes: None }
+        Self {
+            data: *data as FloatDataScalar,
+            grad: None,
+            backward_fn: None,
+            prev_nodes: None,
+            op: Op::Leaf,
+            custom_backward: None,
+        }
     }
 }
 
@@ -181,7 +249,7 @@ impl Deref for Value {
 
 // TODO - convert from recursion to iteration
 #[allow(clippy::mutable_key_type)]
-fn build_topo_recursive(node: &Value, visited: &mut HashSet<Value>, topo_rev: &mut Vec<Value>) {
+pub(crate) fn build_topo_recursive(node: &Value, visited: &mut HashSet<Value>, topo_rev: &mut Vec<Value>) {
     // v is the child node and we have a link to our parent nodes
     if !visited.contains(node) {
         // PartialEq and Hash both use address of ValueInner
@@ -227,9 +295,82 @@ fn build_topo_recursive(node: &Value, visited: &mut HashSet<Value>, topo_rev: &m
 // }
 
 impl Value {
+    /// A node whose operation is not recorded: symbolic export prints it as `op(...)` of its ancestors. Use
+    /// `with_op` to record it
     #[must_use]
     pub fn new(data: FloatDataScalar, prev_nodes: Option<Vec<Self>>, backward_fn: Option<fn(&ValueInner)>) -> Self {
-        Self(Rc::new(RefCell::new(ValueInner::new(data, prev_nodes, backward_fn))))
+        Self::with_op(data, prev_nodes, backward_fn, Op::Custom("op"))
+    }
+
+    /// A node produced by `op`. Symbolic export fails if `op` does not match the number of `prev_nodes`, e.g. an
+    /// `Op::Add` with one ancestor
+    #[must_use]
+    pub fn with_op(
+        data: FloatDataScalar,
+        prev_nodes: Option<Vec<Self>>,
+        backward_fn: Option<fn(&ValueInner)>,
+        op: Op,
+    ) -> Self {
+        Self(Rc::new(RefCell::new(ValueInner::with_op(data, prev_nodes, backward_fn, op))))
+    }
+
+    /// A node with an arbitrary backward pass, for ops that are easier to differentiate as a whole than to build
+    /// from primitives. `backward` receives the ancestors and this node's gradient, and returns one gradient
+    /// contribution per ancestor
+    #[must_use]
+    pub fn custom(
+        data: FloatDataScalar,
+        prev_nodes: Vec<Self>,
+        name: &'static str,
+        backward: impl Fn(&[Self], FloatDataScalar) -> Vec<FloatDataScalar> + 'static,
+    ) -> Self {
+        let backward_fn = |our_value_inner: &ValueInner| {
+            let prev_nodes = our_value_inner.prev_nodes.as_deref().unwrap_or_default();
+            let custom = our_value_inner.custom_backward.as_ref().expect("custom node without custom_backward");
+            let grads = (custom.0)(prev_nodes, our_value_inner.grad.unwrap_or(0.0));
+            assert_eq!(grads.len(), prev_nodes.len(), "custom backward must return one gradient per ancestor");
+            for (node, grad) in prev_nodes.iter().zip(grads) {
+                let node_grad = node.grad().unwrap_or(0.0);
+                node.borrow_mut().grad = Some(node_grad + grad);
+            }
+        };
+        let value = Self::with_op(data, Some(prev_nodes), Some(backward_fn), Op::Custom(name));
+        value.borrow_mut().custom_backward = Some(CustomBackward(Rc::new(backward)));
+        value
+    }
+
+    /// Like `custom`, but for an op with several outputs that share one backward pass.
+    ///
+    /// The outputs all hang off a hidden hub node. Since the hub comes before its outputs in topological order, by
+    /// the time the hub's backward runs every output has its final gradient, and `backward` receives them all at once
+    /// (zero for outputs that did not take part in the loss)
+    #[must_use]
+    pub fn custom_multi(
+        data: &[FloatDataScalar],
+        prev_nodes: Vec<Self>,
+        name: &'static str,
+        backward: impl Fn(&[Self], &[FloatDataScalar]) -> Vec<FloatDataScalar> + 'static,
+    ) -> Vec<Self> {
+        // Weak, since outputs hold the hub and the hub must not keep its outputs alive
+        let outputs: Rc<RefCell<Vec<Weak<RefCell<ValueInner>>>>> = Rc::default();
+        let hub_outputs = Rc::clone(&outputs);
+        let hub = Self::custom(0.0, prev_nodes, name, move |prev_nodes, _| {
+            let output_grads: Vec<FloatDataScalar> = hub_outputs
+                .borrow()
+                .iter()
+                .map(|output| output.upgrade().and_then(|output| output.borrow().grad).unwrap_or(0.0))
+                .collect();
+            backward(prev_nodes, &output_grads)
+        });
+
+        // Outputs have no backward_fn; their gradient is left in place for the hub to collect
+        let values: Vec<Self> = data
+            .iter()
+            .enumerate()
+            .map(|(idx, &d)| Self::with_op(d, Some(vec![hub.clone()]), None, Op::Output(idx)))
+            .collect();
+        *outputs.borrow_mut() = values.iter().map(|v| Rc::downgrade(&v.0)).collect();
+        values
     }
 
     #[must_use]
@@ -247,10 +388,22 @@ impl Value {
         self.borrow().backward_fn
     }
 
+    #[must_use]
+    pub fn op(&self) -> Op {
+        self.borrow().op
+    }
+
+    /// The nodes this value was computed from, in the order the op received them
+    #[must_use]
+    pub fn prev_nodes(&self) -> Vec<Self> {
+        self.borrow().prev_nodes.clone().unwrap_or_default()
+    }
+
     pub fn zero_grad(&self) {
         self.borrow_mut().grad = None;
     }
 
+    /// Power with a variable exponent that receives a gradient. Prefer `powi` or `powf` for constant exponents
     pub fn pow<T: Into<Self>>(&self, exponent: T) -> Self {
         let exponent = exponent.into();
 
@@ -280,7 +433,49 @@ impl Value {
             }
         };
 
-        Self::new(data, Some(prev_nodes), Some(backward_fn))
+        Self::with_op(data, Some(prev_nodes), Some(backward_fn), Op::Pow)
+    }
+
+    /// Raise to a constant integer power. Unlike `pow`, the exponent is not a graph node, so no `ln(base)` is needed
+    /// and negative bases are fine
+    #[must_use]
+    pub fn powi(&self, exponent: i32) -> Self {
+        let data = self.data().powi(exponent);
+        let prev_nodes = vec![self.clone()];
+        let backward_fn =
+            |our_value_inner: &ValueInner| match (our_value_inner.prev_nodes.as_deref(), our_value_inner.op) {
+                (Some([base]), Op::Powi(exponent)) => {
+                    let our_grad = our_value_inner.grad.unwrap_or(0.0);
+                    let mut base = base.borrow_mut();
+                    let local_grad =
+                        if exponent == 0 { 0.0 } else { f64::from(exponent) * base.data.powi(exponent - 1) };
+                    base.grad = Some(local_grad.mul_add(our_grad, base.grad.unwrap_or(0.0)));
+                }
+                _ => {
+                    unreachable!("powi must have one ancestor")
+                }
+            };
+        Self::with_op(data, Some(prev_nodes), Some(backward_fn), Op::Powi(exponent))
+    }
+
+    /// Raise to a constant float power. Unlike `pow`, the exponent is not a graph node, so no `ln(base)` is needed
+    #[must_use]
+    pub fn powf(&self, exponent: FloatDataScalar) -> Self {
+        let data = self.data().powf(exponent);
+        let prev_nodes = vec![self.clone()];
+        let backward_fn =
+            |our_value_inner: &ValueInner| match (our_value_inner.prev_nodes.as_deref(), our_value_inner.op) {
+                (Some([base]), Op::Powf(exponent)) => {
+                    let our_grad = our_value_inner.grad.unwrap_or(0.0);
+                    let mut base = base.borrow_mut();
+                    let local_grad = if exponent == 0.0 { 0.0 } else { exponent * base.data.powf(exponent - 1.0) };
+                    base.grad = Some(local_grad.mul_add(our_grad, base.grad.unwrap_or(0.0)));
+                }
+                _ => {
+                    unreachable!("powf must have one ancestor")
+                }
+            };
+        Self::with_op(data, Some(prev_nodes), Some(backward_fn), Op::Powf(exponent))
     }
 
     #[must_use]
@@ -302,7 +497,7 @@ impl Value {
                 unreachable!("log must have one ancestor")
             }
         };
-        Self::new(data, Some(prev_nodes), Some(backward_fn))
+        Self::with_op(data, Some(prev_nodes), Some(backward_fn), Op::Log)
     }
 
     // Recursive way
@@ -351,7 +546,45 @@ impl Value {
                 unreachable!("relu must have one ancestor")
             }
         };
-        Self::new(data, Some(prev_nodes), Some(backward_fn))
+        Self::with_op(data, Some(prev_nodes), Some(backward_fn), Op::ReLU)
+    }
+
+    #[must_use]
+    pub fn tanh(&self) -> Self {
+        let data = self.data().tanh();
+        let prev_nodes = vec![self.clone()];
+        let backward_fn = |our_value_inner: &ValueInner| match our_value_inner.prev_nodes.as_deref() {
+            Some([first]) => {
+                let mut first = first.borrow_mut();
+                let our_grad = our_value_inner.grad.unwrap_or(0.0);
+                let slope = our_value_inner.data.mul_add(-our_value_inner.data, 1.0);
+                first.grad = Some(slope.mul_add(our_grad, first.grad.unwrap_or(0.0)));
+            }
+            _ => {
+                unreachable!("tanh must have one ancestor")
+            }
+        };
+        Self::with_op(data, Some(prev_nodes), Some(backward_fn), Op::Tanh)
+    }
+
+    /// Logistic function `1 / (1 + exp(-x))`, computed without overflow for large negative inputs
+    #[must_use]
+    pub fn sigmoid(&self) -> Self {
+        let x = self.data();
+        let data = if x >= 0.0 { 1.0 / (1.0 + (-x).exp()) } else { x.exp() / (1.0 + x.exp()) };
+        let prev_nodes = vec![self.clone()];
+        let backward_fn = |our_value_inner: &ValueInner| match our_value_inner.prev_nodes.as_deref() {
+            Some([first]) => {
+                let mut first = first.borrow_mut();
+                let our_grad = our_value_inner.grad.unwrap_or(0.0);
+                let slope = our_value_inner.data * (1.0 - our_value_inner.data);
+                first.grad = Some(slope.mul_add(our_grad, first.grad.unwrap_or(0.0)));
+            }
+            _ => {
+                unreachable!("sigmoid must have one ancestor")
+            }
+        };
+        Self::with_op(data, Some(prev_nodes), Some(backward_fn), Op::Sigmoid)
     }
 }
 
@@ -391,7 +624,7 @@ impl_binary_op!(self, rhs, Add, add, _add, +, {
             }
         }
     };
-    Value::new(data, Some(prev_nodes), Some(backward_fn))
+    Value::with_op(data, Some(prev_nodes), Some(backward_fn), Op::Add)
 }
 );
 impl_binary_op!(self, rhs, Mul, mul, _mul, *, {
@@ -411,32 +644,121 @@ impl_binary_op!(self, rhs, Mul, mul, _mul, *, {
             unreachable!("binary op must have two ancestors")
         }
     };
-    Value::new(data, Some(prev_nodes), Some(backward_fn))
+    Value::with_op(data, Some(prev_nodes), Some(backward_fn), Op::Mul)
 });
 impl_binary_op!(self, rhs, Div, div, _div, /, {
-    self * rhs.pow(-1.0)
+    let data = self.data() / rhs.data();
+    let prev_nodes = vec![self.clone(), rhs.clone()];
+    let backward_fn = |our_value_inner: &ValueInner| match our_value_inner.prev_nodes.as_deref() {
+        Some([numerator, denominator]) => {
+            let our_grad = our_value_inner.grad.unwrap_or(0.0);
+            let (num_data, den_data) = (numerator.data(), denominator.data());
+
+            let num_grad = numerator.grad().unwrap_or(0.0);
+            numerator.borrow_mut().grad = Some(den_data.recip().mul_add(our_grad, num_grad));
+
+            let den_grad = denominator.grad().unwrap_or(0.0);
+            denominator.borrow_mut().grad = Some((-num_data / (den_data * den_data)).mul_add(our_grad, den_grad));
+        }
+        _ => {
+            unreachable!("binary op must have two ancestors")
+        }
+    };
+    Value::with_op(data, Some(prev_nodes), Some(backward_fn), Op::Div)
 });
 impl_binary_op!(self, rhs, Sub, sub, _sub, -, {
     self + (-1.0 * rhs)
 });
 
 // TODO - using these functions to implement Neuron.normalize was extremely slow - why?
+/// Sum as a single node, rather than a chain of `n` binary adds. The data is added up by `kernels::sum`, so with the
+/// `parallel` feature long sums are split across threads
 #[must_use]
-#[inline]
 pub fn sum(values: &[Value]) -> Value {
-    values.iter().fold(Value::from(0.0), |acc, val| acc + val)
+    let data = kernels::sum(&to_vec(values));
+    let backward_fn = |our_value_inner: &ValueInner| {
+        let our_grad = our_value_inner.grad.unwrap_or(0.0);
+        for value in our_value_inner.prev_nodes.as_deref().unwrap_or_default() {
+            let mut value = value.borrow_mut();
+            value.grad = Some(value.grad.unwrap_or(0.0) + our_grad);
+        }
+    };
+    Value::with_op(data, Some(values.to_vec()), Some(backward_fn), Op::Sum)
 }
 
+/// Mean as a single node. The mean of an empty slice is NaN
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn mean(values: &[Value]) -> Value {
+    let data = kernels::sum(&to_vec(values)) / values.len() as FloatDataScalar;
+    let backward_fn = |our_value_inner: &ValueInner| {
+        let prev_nodes = our_value_inner.prev_nodes.as_deref().unwrap_or_default();
+        let our_grad = our_value_inner.grad.unwrap_or(0.0) / prev_nodes.len() as FloatDataScalar;
+        for value in prev_nodes {
+            let mut value = value.borrow_mut();
+            value.grad = Some(value.grad.unwrap_or(0.0) + our_grad);
+        }
+    };
+    Value::with_op(data, Some(values.to_vec()), Some(backward_fn), Op::Mean)
+}
+
+/// Dot product as a single node. Ancestors are stored as `lhs` followed by `rhs`. Like `sum`, the float work of the
+/// forward and backward passes runs in `kernels`
+///
+/// # Panics
+/// If `lhs` and `rhs` have different lengths
+#[must_use]
+pub fn dot(lhs: &[Value], rhs: &[Value]) -> Value {
+    assert_eq!(lhs.len(), rhs.len(), "dot product of slices with different lengths");
+    let data = kernels::dot(&to_vec(lhs), &to_vec(rhs));
+    let prev_nodes = lhs.iter().chain(rhs).cloned().collect();
+    let backward_fn = |our_value_inner: &ValueInner| {
+        let prev_nodes = our_value_inner.prev_nodes.as_deref().unwrap_or_default();
+        let (lhs, rhs) = prev_nodes.split_at(prev_nodes.len() / 2);
+        let our_grad = our_value_inner.grad.unwrap_or(0.0);
+        // Local gradients come from a snapshot of the data, so the same Value may appear on both sides (e.g.
+        // `dot(x, x)`). They are accumulated one at a time, as nodes cannot be shared across threads
+        let (l_locals, r_locals) = (kernels::scale(&to_vec(rhs), our_grad), kernels::scale(&to_vec(lhs), our_grad));
+        for ((l, r), (l_local, r_local)) in lhs.iter().zip(rhs).zip(l_locals.into_iter().zip(r_locals)) {
+            let l_grad = l.grad().unwrap_or(0.0);
+            l.borrow_mut().grad = Some(l_grad + l_local);
+
+            let r_grad = r.grad().unwrap_or(0.0);
+            r.borrow_mut().grad = Some(r_grad + r_local);
+        }
+    };
+    Value::with_op(data, Some(prev_nodes), Some(backward_fn), Op::Dot)
+}
+
+/// Product as a single node. The backward pass uses prefix and suffix products, so it is O(n) and handles zeros
 #[must_use]
-#[inline]
 pub fn prod(values: &[Value]) -> Value {
-    values.iter().fold(Value::from(1.0), |acc, val| acc * val)
+    let data = values.iter().fold(1.0, |acc, val| acc * val.data());
+    let backward_fn = |our_value_inner: &ValueInner| {
+        let prev_nodes = our_value_inner.prev_nodes.as_deref().unwrap_or_default();
+        let our_grad = our_value_inner.grad.unwrap_or(0.0);
+
+        // suffix[i] is the product of all values after index i
+        let mut suffix = vec![1.0; prev_nodes.len()];
+        for idx in (1..prev_nodes.len()).rev() {
+            suffix[idx - 1] = suffix[idx] * prev_nodes[idx].data();
+        }
+        let mut prefix = 1.0;
+        for (value, suffix) in prev_nodes.iter().zip(suffix) {
+            let data = value.data();
+            let grad = value.grad().unwrap_or(0.0);
+            value.borrow_mut().grad = Some((prefix * suffix).mul_add(our_grad, grad));
+            prefix *= data;
+        }
+    };
+    Value::with_op(data, Some(values.to_vec()), Some(backward_fn), Op::Prod)
 }
 
+/// Each value raised to a constant power, as `Value::powf` nodes. Use `Value::pow` for an exponent in the graph
 #[must_use]
 #[inline]
-pub fn pow<T: Clone + Into<Value>>(values: &[Value], exponent: &T) -> Vec<Value> {
-    values.iter().map(|value| value.pow(exponent.clone().into())).collect()
+pub fn pow(values: &[Value], exponent: FloatDataScalar) -> Vec<Value> {
+    values.iter().map(|value| value.powf(exponent)).collect()
 }
 
 #[must_use]
@@ -448,7 +770,7 @@ pub fn exp(values: &[Value]) -> Vec<Value> {
 #[must_use]
 #[inline]
 pub fn norm(values: &[Value]) -> Value {
-    sum(&pow(values, &2)).pow(0.5)
+    dot(values, values).powf(0.5)
 }
 
 #[must_use]
@@ -574,13 +896,13 @@ mod tests {
         let a = Value::from(-4.0);
         let b = Value::from(2.0);
         let c = &a + &b;
-        let d = &a * &b + &b.pow(3.0);
+        let d = &a * &b + &b.powi(3);
         let c = &c + &c + 1.0;
         let c = &c + 1 + c + (-1 * &a);
         let d = &d + &d * 2 + (&b + &a).relu();
         let d = &d + 3 * &d + (&b - &a).relu();
         let e = &c - &d;
-        let f = e.pow(2.0);
+        let f = e.powi(2);
         let g = &f / Value::from(2.0);
         let g = &g + 10.0 / f;
         let h = g.log();
@@ -613,6 +935,97 @@ mod tests {
         assert_close!(bmg.grad().unwrap(), bpt.grad().double_value(&[]));
     }
 
+    #[test]
+    fn constant_pow_with_negative_base() {
+        let x = Value::from(-3.0);
+        let y = x.powi(3) + x.powf(2.0) + x.powi(0);
+        y.backward();
+        assert_close!(y.data(), -27.0 + 9.0 + 1.0);
+        assert_close!(x.grad().unwrap(), 3.0 * 9.0 - 2.0 * 3.0);
+
+        // With the exponent as a graph node, its own grad needs ln(base)
+        let exponent = Value::from(2.0);
+        x.pow(exponent.clone()).backward();
+        assert!(exponent.grad().unwrap().is_nan());
+    }
+
+    #[test]
+    fn compare_torch_div_powi_powf() {
+        let a = Value::from(-1.5);
+        let b = Value::from(4.0);
+        let y = (&a / &b).powi(3) + (&b / &a) + b.powf(-0.5) + &a / &a;
+        y.backward();
+
+        let at = Tensor::from(-1.5).set_requires_grad(true);
+        let bt = Tensor::from(4.0).set_requires_grad(true);
+        let yt: Tensor = (&at / &bt).pow_tensor_scalar(3) + (&bt / &at) + bt.pow_tensor_scalar(-0.5) + &at / &at;
+        yt.backward();
+
+        assert_close!(y.data(), yt.double_value(&[]));
+        assert_close!(a.grad().unwrap(), at.grad().double_value(&[]));
+        assert_close!(b.grad().unwrap(), bt.grad().double_value(&[]));
+    }
+
+    #[test]
+    fn compare_torch_tanh_sigmoid() {
+        let x = Value::from(0.7);
+        let y = x.tanh() * (&x * 3.0).sigmoid() + (&x * -800.0).sigmoid();
+        y.backward();
+
+        let xt = Tensor::from(0.7).set_requires_grad(true);
+        let yt = xt.tanh() * (&xt * 3.0).sigmoid() + (&xt * -800.0).sigmoid();
+        yt.backward();
+
+        assert_close!(y.data(), yt.double_value(&[]));
+        assert_close!(x.grad().unwrap(), xt.grad().double_value(&[]));
+    }
+
+    #[test]
+    fn tanh_sigmoid_by_hand() {
+        let x = Value::from(0.0);
+        let y = x.tanh() + x.sigmoid();
+        assert_close!(y.data(), 0.5);
+        y.backward();
+        assert_close!(x.grad().unwrap(), 1.0 + 0.25);
+
+        // No overflow far into either tail
+        assert_close!(Value::from(-1000.0).sigmoid().data(), 0.0);
+        assert_close!(Value::from(1000.0).sigmoid().data(), 1.0);
+    }
+
+    #[test]
+    fn custom_node() {
+        // f(a, b) = a * b, with a hand-written backward
+        let a = Value::from(3.0);
+        let b = Value::from(-2.0);
+        let y = Value::custom(a.data() * b.data(), vec![a.clone(), b.clone()], "mul", |prev, grad| {
+            vec![prev[1].data() * grad, prev[0].data() * grad]
+        });
+        (&y * 2.0).backward();
+        assert_eq!(y.op(), Op::Custom("mul"));
+        assert_close!(a.grad().unwrap(), -4.0);
+        assert_close!(b.grad().unwrap(), 6.0);
+    }
+
+    #[test]
+    fn custom_multi_node() {
+        // (u, v) = (a + b, a * b), only v is used
+        let a = Value::from(3.0);
+        let b = Value::from(-2.0);
+        let outputs = Value::custom_multi(
+            &[a.data() + b.data(), a.data() * b.data()],
+            vec![a.clone(), b.clone()],
+            "pair",
+            |prev, grads| vec![grads[0] + prev[1].data() * grads[1], grads[0] + prev[0].data() * grads[1]],
+        );
+        assert_eq!(to_vec(&outputs), [1.0, -6.0]);
+        assert_eq!(outputs[1].op(), Op::Output(1));
+
+        (&outputs[1] * &outputs[1]).backward();
+        assert_close!(a.grad().unwrap(), 2.0 * -6.0 * -2.0);
+        assert_close!(b.grad().unwrap(), 2.0 * -6.0 * 3.0);
+    }
+
     #[test]
     fn verify_hashset_behavior() {
         use std::collections::HashSet;
@@ -646,10 +1059,85 @@ mod tests {
         assert_close!(prod(&values).data(), -6.0);
     }
 
+    #[test]
+    fn test_mean() {
+        let values = vec![Value::from(2.0), Value::from(3.0), Value::from(-1.0)];
+        assert_close!(mean(&values).data(), 4.0 / 3.0);
+    }
+
+    #[test]
+    fn test_dot() {
+        let a = vec![Value::from(2.0), Value::from(3.0), Value::from(-1.0)];
+        let b = vec![Value::from(0.5), Value::from(-2.0), Value::from(4.0)];
+        assert_close!(dot(&a, &b).data(), -9.0);
+    }
+
+    #[test]
+    fn fused_grads_match_pairwise() {
+        let data = [2.0, 3.0, -1.0, 0.5];
+
+        let fused: Vec<Value> = data.iter().map(Value::from).collect();
+        let y = sum(&fused) * mean(&fused) + prod(&fused) + dot(&fused, &fused);
+        y.backward();
+
+        let pairwise: Vec<Value> = data.iter().map(Value::from).collect();
+        let total = pairwise.iter().fold(Value::from(0.0), |acc, val| acc + val);
+        let product = pairwise.iter().fold(Value::from(1.0), |acc, val| acc * val);
+        let squares = pairwise.iter().fold(Value::from(0.0), |acc, val| acc + val * val);
+        let y_pairwise = &total * (&total / 4.0) + product + squares;
+        y_pairwise.backward();
+
+        assert_close!(y.data(), y_pairwise.data());
+        for (f, p) in fused.iter().zip(&pairwise) {
+            assert_close!(f.grad().unwrap(), p.grad().unwrap());
+        }
+    }
+
+    #[test]
+    fn prod_grad_with_zero() {
+        let values = vec![Value::from(2.0), Value::from(0.0), Value::from(3.0)];
+        prod(&values).backward();
+        assert_eq!(to_vec(&values.iter().map(|v| Value::from(v.grad().unwrap())).collect::<Vec<_>>()), [0.0, 6.0, 0.0]);
+    }
+
+    #[test]
+    fn deep_sum_backward() {
+        // A pairwise fold this long would build a chain deep enough to overflow the stack in `backward`
+        let values: Vec<Value> = (0..100_000).map(|_| Value::from(1.0)).collect();
+        sum(&values).backward();
+        assert!(values.iter().all(|v| v.grad() == Some(1.0)));
+    }
+
+    #[test]
+    fn compare_torch_fused() {
+        let data = [2.0, 3.0, -1.0, 0.5];
+        let weights = [0.1, -0.2, 0.3, -0.4];
+
+        let x: Vec<Value> = data.iter().map(Value::from).collect();
+        let w: Vec<Value> = weights.iter().map(Value::from).collect();
+        let y = dot(&w, &x) * prod(&x) + mean(&x) * sum(&w);
+        y.backward();
+
+        let xt = Tensor::from_slice(&data).set_requires_grad(true);
+        let wt = Tensor::from_slice(&weights).set_requires_grad(true);
+        let yt = wt.dot(&xt) * xt.prod(None) + xt.mean(None) * wt.sum(None);
+        yt.backward();
+
+        assert_close!(y.data(), yt.double_value(&[]));
+        for idx in 0..data.len() {
+            assert_close!(x[idx].grad().unwrap(), xt.grad().double_value(&[idx as i64]));
+            assert_close!(w[idx].grad().unwrap(), wt.grad().double_value(&[idx as i64]));
+        }
+    }
+
     #[test]
     fn test_pow() {
         let values = vec![Value::from(2.0), Value::from(3.0), Value::from(-1.0)];
-        assert_vec_close!(pow(&values, &2.0), vec![Value::from(4.0), Value::from(9.0), Value::from(1.0)]);
+        let squares = pow(&values, 2.0);
+        assert_vec_close!(squares, vec![Value::from(4.0), Value::from(9.0), Value::from(1.0)]);
+        assert!(squares.iter().all(|square| square.op() == Op::Powf(2.0)));
+        sum(&squares).backward();
+        assert_eq!(values.iter().map(|value| value.grad().unwrap()).collect::<Vec<_>>(), [4.0, 6.0, -2.0]);
     }
 
     #[test]
@@ -670,7 +1158,7 @@ mod tests {
         let values = vec![Value::from(2.0), Value::from(3.0), Value::from(-1.0)];
         assert_close!(
             norm(&values).data(),
-            values.iter().map(|v| v.pow(2.0)).fold(0.0, |acc, val| acc + val.data()).sqrt()
+            values.iter().map(|v| v.powi(2)).fold(0.0, |acc, val| acc + val.data()).sqrt()
         );
     }
 
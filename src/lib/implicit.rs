@@ -0,0 +1,211 @@
+use crate::engine::{FloatDataScalar, Value, to_vec};
+use anyhow::{Result, bail};
+
+/// Solver for `x = f(x, params)` that iterates on raw floats and differentiates through the solution with the
+/// implicit function theorem, instead of backpropagating through every iteration.
+///
+/// At the solution `x*`, the Jacobian of `x*` w.r.t. `params` is `(I - df/dx)^-1 df/dparams`, so the backward pass
+/// only needs `f` evaluated once per dimension of `x`, at `x*`.
+#[derive(Debug, Clone)]
+pub struct FixedPointSolver {
+    /// Stop once no coordinate changes by more than this between iterations
+    pub tol: FloatDataScalar,
+    pub max_iter: usize,
+}
+
+impl Default for FixedPointSolver {
+    fn default() -> Self {
+        Self { tol: 1e-10, max_iter: 1000 }
+    }
+}
+
+impl FixedPointSolver {
+    /// Iterate `x <- f(x, params)` from `x0` until convergence.
+    ///
+    /// `f` must return a vector the same length as `x`. It is only ever called on fresh leaf values, so the graphs
+    /// built during the forward iterations are dropped right away. The returned values have `params` as their only
+    /// ancestors.
+    pub fn solve<F>(&self, f: F, params: &[Value], x0: &[FloatDataScalar]) -> Result<Vec<Value>>
+    where
+        F: Fn(&[Value], &[Value]) -> Vec<Value> + 'static,
+    {
+        let theta = to_vec(params);
+        let mut x = x0.to_vec();
+        let mut converged = false;
+        for _ in 0..self.max_iter {
+            let x_next = to_vec(&f(&leaves(&x), &leaves(&theta)));
+            if x_next.len() != x.len() {
+                bail!("f returned {} values for a state of length {}", x_next.len(), x.len());
+            }
+            if x_next.iter().any(|v| !v.is_finite()) {
+                bail!("fixed point iteration diverged: {x_next:?}");
+            }
+            let delta = x.iter().zip(&x_next).fold(0.0, |acc: FloatDataScalar, (a, b)| acc.max((a - b).abs()));
+            x = x_next;
+            if delta <= self.tol {
+                converged = true;
+                break;
+            }
+        }
+        if !converged {
+            bail!("fixed point iteration did not converge within {} iterations", self.max_iter);
+        }
+
+        let solution = x.clone();
+        let backward = move |_params: &[Value], output_grads: &[FloatDataScalar]| {
+            let (jac_x, jac_theta) = jacobians(&f, &solution, &theta);
+
+            // The vector-Jacobian product needs w = (I - df/dx)^-T output_grads
+            let n = solution.len();
+            let system: Vec<Vec<FloatDataScalar>> = (0..n)
+                .map(|row| (0..n).map(|col| if row == col { 1.0 } else { 0.0 } - jac_x[col][row]).collect())
+                .collect();
+            let Some(w) = solve_linear(system, output_grads.to_vec()) else {
+                log::warn!("I - df/dx is singular at the fixed point; gradients are NaN");
+                return vec![FloatDataScalar::NAN; theta.len()];
+            };
+
+            (0..theta.len()).map(|p| (0..n).fold(0.0, |acc, i| w[i].mul_add(jac_theta[i][p], acc))).collect()
+        };
+        Ok(Value::custom_multi(&x, params.to_vec(), "fixed_point", backward))
+    }
+}
+
+/// Solve `x = f(x, params)` with the default `FixedPointSolver`
+pub fn fixed_point<F>(f: F, params: &[Value], x0: &[FloatDataScalar]) -> Result<Vec<Value>>
+where
+    F: Fn(&[Value], &[Value]) -> Vec<Value> + 'static,
+{
+    FixedPointSolver::default().solve(f, params, x0)
+}
+
+fn leaves(data: &[FloatDataScalar]) -> Vec<Value> {
+    data.iter().map(Value::from).collect()
+}
+
+/// Rows of `df/dx` and `df/dparams` at `(x, theta)`, one backward pass per output of `f`.
+/// Each pass builds a fresh graph, since grads of intermediate nodes would otherwise accumulate across passes
+fn jacobians(
+    f: &impl Fn(&[Value], &[Value]) -> Vec<Value>,
+    x: &[FloatDataScalar],
+    theta: &[FloatDataScalar],
+) -> (Vec<Vec<FloatDataScalar>>, Vec<Vec<FloatDataScalar>>) {
+    let grads = |values: &[Value]| values.iter().map(|v| v.grad().unwrap_or(0.0)).collect::<Vec<_>>();
+    (0..x.len())
+        .map(|row| {
+            let (x, theta) = (leaves(x), leaves(theta));
+            f(&x, &theta)[row].backward();
+            (grads(&x), grads(&theta))
+        })
+        .unzip()
+}
+
+/// Gaussian elimination with partial pivoting. Returns `None` if `a` is singular
+fn solve_linear(mut a: Vec<Vec<FloatDataScalar>>, mut b: Vec<FloatDataScalar>) -> Option<Vec<FloatDataScalar>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < f64::EPSILON {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let pivot_row = a[col].clone();
+        for row in col + 1..n {
+            let factor = a[row][col] / pivot_row[col];
+            for (dst, src) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *dst = factor.mul_add(-src, *dst);
+            }
+            b[row] = factor.mul_add(-b[col], b[row]);
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let rest = (row + 1..n).fold(0.0, |acc, k| a[row][k].mul_add(x[k], acc));
+        x[row] = (b[row] - rest) / a[row][row];
+    }
+    Some(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_close;
+    use crate::engine::{dot, sum};
+
+    #[test]
+    fn scalar_fixed_point() -> Result<()> {
+        // x = a / (1 + x)  =>  x^2 + x - a = 0  =>  x* = (sqrt(1 + 4a) - 1) / 2
+        let params = [Value::from(2.0)];
+        let x = fixed_point(|x, p| vec![&p[0] / (&x[0] + 1.0)], &params, &[1.0])?;
+        assert_close!(x[0].data(), 1.0);
+
+        x[0].backward();
+        assert_close!(params[0].grad().unwrap(), 1.0 / 9.0f64.sqrt());
+        Ok(())
+    }
+
+    #[test]
+    fn matches_unrolled_backprop() -> Result<()> {
+        // Babylonian square root: x = (x + a / x) / 2  =>  x* = sqrt(a)
+        let f = |x: &[Value], p: &[Value]| vec![(&x[0] + &p[0] / &x[0]) * 0.5];
+
+        let params = [Value::from(5.0)];
+        let x = fixed_point(f, &params, &[1.0])?;
+        (&x[0] * &x[0] * 3.0).backward();
+
+        let params_unrolled = [Value::from(5.0)];
+        let mut x_unrolled = vec![Value::from(1.0)];
+        for _ in 0..50 {
+            x_unrolled = f(&x_unrolled, &params_unrolled);
+        }
+        (&x_unrolled[0] * &x_unrolled[0] * 3.0).backward();
+
+        assert_close!(x[0].data(), 5.0f64.sqrt());
+        assert_close!(params[0].grad().unwrap(), 3.0);
+        assert_close!(params[0].grad().unwrap(), params_unrolled[0].grad().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn linear_system() -> Result<()> {
+        // x = A x + b  =>  x* = (I - A)^-1 b, and d sum(x*) / db = (I - A)^-T 1
+        let params: Vec<Value> = [0.2, 0.1, -0.3, 0.4, 1.0, 2.0].iter().map(Value::from).collect();
+        let f = |x: &[Value], p: &[Value]| vec![dot(&p[0..2], x) + &p[4], dot(&p[2..4], x) + &p[5]];
+        let x = fixed_point(f, &params, &[0.0, 0.0])?;
+
+        // I - A = [[0.8, -0.1], [0.3, 0.6]], det = 0.51
+        let det = 0.8f64.mul_add(0.6, 0.1 * 0.3);
+        assert_close!(x[0].data(), 0.6f64.mul_add(1.0, 0.1 * 2.0) / det);
+        assert_close!(x[1].data(), 0.8f64.mul_add(2.0, -0.3 * 1.0) / det);
+
+        sum(&x).backward();
+        assert_close!(params[4].grad().unwrap(), (0.6 - 0.3) / det);
+        assert_close!(params[5].grad().unwrap(), (0.1 + 0.8) / det);
+        // d x* / dA_ij = (I - A)^-T 1 [i] * x*_j
+        assert_close!(params[1].grad().unwrap(), params[4].grad().unwrap() * x[1].data());
+        assert_close!(params[2].grad().unwrap(), params[5].grad().unwrap() * x[0].data());
+        Ok(())
+    }
+
+    #[test]
+    fn only_used_outputs_contribute() -> Result<()> {
+        // Independent coordinates: x0 = a / 2 + x0 / 2, x1 = b / 2 + x1 / 2  =>  x* = (a, b)
+        let params = [Value::from(3.0), Value::from(4.0)];
+        let f = |x: &[Value], p: &[Value]| vec![(&p[0] + &x[0]) * 0.5, (&p[1] + &x[1]) * 0.5];
+        let x = fixed_point(f, &params, &[0.0, 0.0])?;
+        x[1].powi(2).backward();
+        assert_close!(params[0].grad().unwrap(), 0.0);
+        assert_close!(params[1].grad().unwrap(), 8.0);
+        Ok(())
+    }
+
+    #[test]
+    fn divergence_is_an_error() {
+        let params = [Value::from(2.0)];
+        assert!(fixed_point(|x, p| vec![&x[0] * &p[0]], &params, &[1.0]).is_err());
+
+        let solver = FixedPointSolver { tol: 1e-12, max_iter: 3 };
+        assert!(solver.solve(|x, p| vec![(&x[0] + &p[0]) * 0.5], &params, &[0.0]).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+//! Dense kernels over contiguous row-major `f64` buffers. These sit underneath the tensor ops that are
+//! differentiated as a whole, rather than element by element through the scalar graph.
+
+use crate::engine::FloatDataScalar;
+use std::ops::Range;
+
+// Block sizes, chosen so that a block of B (KC x NC) plus a row of C stay in L2, and the innermost loop walks
+// contiguous rows of B and C, which the compiler can vectorize
+const MC: usize = 64;
+const KC: usize = 256;
+const NC: usize = 512;
+
+/// Products with fewer multiply-adds than this stay on the calling thread, where spawning tasks would cost more than
+/// it saves
+#[cfg(feature = "parallel")]
+const PARALLEL_MIN_FLOPS: usize = 1 << 16;
+
+/// Reductions and elementwise kernels over fewer elements than this stay on the calling thread
+#[cfg(feature = "parallel")]
+const PARALLEL_MIN_LEN: usize = 1 << 15;
+
+/// Reductions sum blocks of this many elements, then add up the block sums in order. The blocks are the same on the
+/// serial and parallel paths, so a reduction does not depend on the feature or the number of threads. Inputs of one
+/// block are summed left to right, exactly as a plain fold would
+const REDUCE_BLOCK: usize = 1 << 12;
+
+/// `c += a · b`, where `a` is `m x k`, `b` is `k x n` and `c` is `m x n`
+///
+/// With the `parallel` feature, large products are split into one band of rows of `c` per thread. Every element of
+/// `c` is still accumulated in the same order as on the serial path, so results are identical bit for bit, whatever
+/// the number of threads
+///
+/// # Panics
+/// If a buffer length does not match its dimensions
+pub(crate) fn gemm(
+    a: &[FloatDataScalar],
+    b: &[FloatDataScalar],
+    c: &mut [FloatDataScalar],
+    m: usize,
+    k: usize,
+    n: usize,
+) {
+    assert_eq!(a.len(), m * k, "lhs buffer does not match its shape");
+    assert_eq!(b.len(), k * n, "rhs buffer does not match its shape");
+    assert_eq!(c.len(), m * n, "output buffer does not match its shape");
+
+    #[cfg(feature = "parallel")]
+    if m > 1 && m * k * n >= PARALLEL_MIN_FLOPS {
+        use rayon::prelude::*;
+        let band = m.div_ceil(rayon::current_num_threads());
+        c.par_chunks_mut(band * n)
+            .zip(a.par_chunks(band * k))
+            .for_each(|(c_band, a_band)| gemm_serial(a_band, b, c_band, c_band.len() / n, k, n));
+        return;
+    }
+    gemm_serial(a, b, c, m, k, n);
+}
+
+fn gemm_serial(a: &[FloatDataScalar], b: &[FloatDataScalar], c: &mut [FloatDataScalar], m: usize, k: usize, n: usize) {
+    for j0 in (0..n).step_by(NC) {
+        let j1 = (j0 + NC).min(n);
+        for p0 in (0..k).step_by(KC) {
+            let p1 = (p0 + KC).min(k);
+            for i0 in (0..m).step_by(MC) {
+                for i in i0..(i0 + MC).min(m) {
+                    let c_row = &mut c[i * n + j0..i * n + j1];
+                    for p in p0..p1 {
+                        let a_ip = a[i * k + p];
+                        let b_row = &b[p * n + j0..p * n + j1];
+                        for (c_ij, b_pj) in c_row.iter_mut().zip(b_row) {
+                            *c_ij = a_ip.mul_add(*b_pj, *c_ij);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sum of `block` over consecutive ranges of `REDUCE_BLOCK` indices below `len`, in order
+fn reduce(len: usize, block: impl Fn(Range<usize>) -> FloatDataScalar + Send + Sync) -> FloatDataScalar {
+    let blocks = (0..len).step_by(REDUCE_BLOCK).map(|start| start..(start + REDUCE_BLOCK).min(len));
+    #[cfg(feature = "parallel")]
+    if len >= PARALLEL_MIN_LEN {
+        use rayon::prelude::*;
+        let partials: Vec<FloatDataScalar> = blocks.collect::<Vec<_>>().into_par_iter().map(block).collect();
+        return partials.into_iter().fold(0.0, |acc, partial| acc + partial);
+    }
+    blocks.map(block).fold(0.0, |acc, partial| acc + partial)
+}
+
+pub(crate) fn sum(a: &[FloatDataScalar]) -> FloatDataScalar {
+    reduce(a.len(), |range| a[range].iter().fold(0.0, |acc, value| acc + value))
+}
+
+/// # Panics
+/// If `a` and `b` have different lengths
+pub(crate) fn dot(a: &[FloatDataScalar], b: &[FloatDataScalar]) -> FloatDataScalar {
+    assert_eq!(a.len(), b.len(), "dot product of buffers with different lengths");
+    reduce(a.len(), |range| a[range.clone()].iter().zip(&b[range]).fold(0.0, |acc, (a, b)| a.mul_add(*b, acc)))
+}
+
+/// `alpha * a`, elementwise
+pub(crate) fn scale(a: &[FloatDataScalar], alpha: FloatDataScalar) -> Vec<FloatDataScalar> {
+    #[cfg(feature = "parallel")]
+    if a.len() >= PARALLEL_MIN_LEN {
+        use rayon::prelude::*;
+        return a.par_iter().map(|value| value * alpha).collect();
+    }
+    a.iter().map(|value| value * alpha).collect()
+}
+
+/// Transpose of the `rows x cols` matrix `a`
+pub(crate) fn transpose(a: &[FloatDataScalar], rows: usize, cols: usize) -> Vec<FloatDataScalar> {
+    let mut out = vec![0.0; a.len()];
+    for (r, row) in a.chunks_exact(cols.max(1)).take(rows).enumerate() {
+        for (c, &value) in row.iter().enumerate() {
+            out[c * rows + r] = value;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_close;
+
+    fn naive(a: &[f64], b: &[f64], m: usize, k: usize, n: usize) -> Vec<f64> {
+        (0..m * n).map(|idx| (0..k).fold(0.0, |acc, p| a[idx / n * k + p].mul_add(b[p * n + idx % n], acc))).collect()
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn blocked_matches_naive() {
+        // Sizes straddle every block boundary
+        for (m, k, n) in [(1, 1, 1), (3, 5, 2), (70, 300, 65), (MC + 1, KC + 3, NC + 7)] {
+            let a: Vec<f64> = (0..m * k).map(|i| (i as f64 * 0.37).sin()).collect();
+            let b: Vec<f64> = (0..k * n).map(|i| (i as f64 * 0.11).cos()).collect();
+            let mut c = vec![1.0; m * n];
+            gemm(&a, &b, &mut c, m, k, n);
+            for (got, expected) in c.iter().zip(naive(&a, &b, m, k, n)) {
+                assert_close!(*got, expected + 1.0, 1e-9, 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn deterministic_across_paths() {
+        // Large enough to take the parallel path when the feature is enabled
+        let (m, k, n) = (MC + 5, 129, 97);
+        let a: Vec<f64> = (0..m * k).map(|i| (i as f64 * 0.013).sin()).collect();
+        let b: Vec<f64> = (0..k * n).map(|i| (i as f64 * 0.029).cos()).collect();
+        let (mut c, mut c_serial) = (vec![0.0; m * n], vec![0.0; m * n]);
+        gemm(&a, &b, &mut c, m, k, n);
+        gemm_serial(&a, &b, &mut c_serial, m, k, n);
+        assert_eq!(c, c_serial);
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn reductions_are_blocked() {
+        // A single block is a plain left-to-right fold
+        let small: Vec<f64> = (0..100).map(|i| (i as f64 * 0.3).sin()).collect();
+        assert_eq!(sum(&small), small.iter().fold(0.0, |acc, value| acc + value));
+        assert_eq!(dot(&small, &small), small.iter().fold(0.0, |acc, value| value.mul_add(*value, acc)));
+        assert_eq!(sum(&[]), 0.0);
+
+        // Large enough to take the parallel path when the feature is enabled, with a partial last block
+        let len = 9 * REDUCE_BLOCK + 17;
+        let a: Vec<f64> = (0..len).map(|i| (i as f64 * 0.013).sin()).collect();
+        let b: Vec<f64> = (0..len).map(|i| (i as f64 * 0.029).cos()).collect();
+        let blocks = |f: &dyn Fn(usize) -> f64| {
+            let partials = (0..len)
+                .step_by(REDUCE_BLOCK)
+                .map(|start| (start..(start + REDUCE_BLOCK).min(len)).fold(0.0, |acc, idx| acc + f(idx)));
+            partials.fold(0.0, |acc, partial| acc + partial)
+        };
+        assert_eq!(sum(&a), blocks(&|idx| a[idx]));
+        assert_close!(dot(&a, &b), blocks(&|idx| a[idx] * b[idx]), 1e-9, 1e-12);
+        assert_eq!(scale(&a, 0.5), a.iter().map(|value| value * 0.5).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn transpose_roundtrip() {
+        let a = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        let t = transpose(&a, 2, 3);
+        assert_eq!(t, vec![1.0, 4.0, 2.0, 5.0, 3.0, 6.0]);
+        assert_eq!(transpose(&t, 3, 2), a);
+        assert!(transpose(&[], 0, 4).is_empty());
+    }
+}
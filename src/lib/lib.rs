@@ -1,9 +1,17 @@
 pub mod engine;
-pub use engine::{DiscreteLabel, FloatDataScalar, IntDataScalar, Value, argmax, norm, pow, prod, sum};
+pub use engine::{DiscreteLabel, FloatDataScalar, IntDataScalar, Op, Value, argmax, dot, mean, norm, pow, prod, sum};
 
+pub mod einsum;
+pub use einsum::einsum;
+pub mod implicit;
+mod kernels;
 pub mod nn;
+pub mod ode;
 
 pub mod ops;
+pub mod symbolic;
+pub mod tensor;
+pub use tensor::Tensor;
 pub mod utils;
 
 pub mod optim;
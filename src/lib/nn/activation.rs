@@ -0,0 +1,161 @@
+use crate::engine::{FloatDataScalar, Value};
+use crate::nn::DenseOp;
+use crate::nn::loss::log_softmax;
+use crate::nn::models::Module;
+use crate::tensor::Tensor;
+use anyhow::{Result, bail};
+use core::f64;
+use serde::{Deserialize, Serialize};
+
+/// Nonlinearity applied to the output of a layer. As a `Module` it has no parameters, so it can also sit between
+/// layers of a `Sequential`.
+///
+/// Every variant is elementwise except `Softmax`, which normalizes over a whole vector: the slice passed to `forward`,
+/// or the last dimension of a tensor
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Activation {
+    Identity,
+    ReLU,
+    /// `x` for positive inputs, `slope * x` otherwise
+    LeakyReLU(FloatDataScalar),
+    Tanh,
+    Sigmoid,
+    /// The tanh approximation `0.5 x (1 + tanh(sqrt(2 / pi) (x + 0.044715 x^3)))`, as torch's `approximate="tanh"`
+    GELU,
+    /// `x * sigmoid(x)`, also known as swish
+    SiLU,
+    Softmax,
+}
+
+impl Activation {
+    /// Apply an elementwise activation to one value. `Softmax` of a single value is always 1
+    #[must_use]
+    pub fn apply(self, value: &Value) -> Value {
+        match self {
+            Self::Identity => value.clone(),
+            Self::ReLU => value.relu(),
+            Self::LeakyReLU(slope) => {
+                if value.data() > 0.0 {
+                    value.clone()
+                } else {
+                    value * slope
+                }
+            }
+            Self::Tanh => value.tanh(),
+            Self::Sigmoid => value.sigmoid(),
+            Self::GELU => {
+                let inner = (value + value.powi(3) * 0.044_715) * (2.0 / f64::consts::PI).sqrt();
+                value * 0.5 * (inner.tanh() + 1.0)
+            }
+            Self::SiLU => value * value.sigmoid(),
+            Self::Softmax => self.forward_slice(std::slice::from_ref(value)).remove(0),
+        }
+    }
+
+    fn forward_slice(self, data: &[Value]) -> Vec<Value> {
+        match self {
+            Self::Softmax => log_softmax(data).iter().map(Value::exp).collect(),
+            _ => data.iter().map(|value| self.apply(value)).collect(),
+        }
+    }
+}
+
+impl Module for Activation {
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        Ok(self.forward_slice(data))
+    }
+
+    /// Elementwise activations accept any shape. `Softmax` is taken over the last dimension
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Softmax if data.ndim() == 0 => bail!("softmax needs at least one dimension"),
+            Self::Softmax => data.softmax(data.ndim() - 1),
+            _ => Ok(data.map(|value| self.apply(value))),
+        }
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        vec![]
+    }
+
+    fn dense_ops(&self) -> Option<Vec<DenseOp>> {
+        Some(if *self == Self::Identity { vec![] } else { vec![DenseOp::Activation(*self)] })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_close;
+
+    #[test]
+    fn elementwise_on_any_shape() -> Result<()> {
+        let x = Tensor::from_data(&[-1.0, 0.0, 2.0, -3.0, 0.5, 1.0], &[1, 2, 3])?;
+        let y = Activation::ReLU.forward_tensor(&x)?;
+        assert_eq!(y.shape(), x.shape());
+        assert_eq!(y.data(), vec![0.0, 0.0, 2.0, 0.0, 0.5, 1.0]);
+
+        let flat = Activation::Tanh.forward(x.values())?;
+        for (value, x) in flat.iter().zip(x.data()) {
+            assert_close!(value.data(), x.tanh());
+        }
+        assert_close!(Activation::Sigmoid.forward(&[Value::from(0.0)])?[0].data(), 0.5);
+        assert!(Activation::Sigmoid.parameters().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn variants_by_hand() -> Result<()> {
+        let x = [Value::from(-2.0), Value::from(0.0), Value::from(3.0)];
+        let data = |activation: Activation| activation.forward(&x).unwrap().iter().map(Value::data).collect::<Vec<_>>();
+        assert_eq!(data(Activation::Identity), vec![-2.0, 0.0, 3.0]);
+        assert_eq!(data(Activation::LeakyReLU(0.1)), vec![-0.2, 0.0, 3.0]);
+        assert_close!(data(Activation::SiLU)[2], 3.0 / (1.0 + (-3.0f64).exp()));
+        assert_close!(data(Activation::GELU)[1], 0.0);
+
+        let probs = data(Activation::Softmax);
+        assert_close!(probs.iter().sum::<f64>(), 1.0);
+        assert_close!(probs[2] / probs[1], 3.0f64.exp());
+        assert_close!(Activation::Softmax.apply(&x[0]).data(), 1.0);
+
+        // Softmax over the last dimension of a tensor
+        let t = Tensor::from_data(&[1.0, 2.0, 3.0, -1.0, 0.0, 5.0], &[2, 3])?;
+        let rows = Activation::Softmax.forward_tensor(&t)?.sum_dim(1, false)?;
+        rows.data().iter().for_each(|sum| assert_close!(*sum, 1.0));
+        assert!(Activation::Softmax.forward_tensor(&Tensor::scalar(1.0)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn compare_torch_activations() {
+        let data = [-2.5, -0.3, 0.0, 0.4, 1.7, 3.2];
+        let weights = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        let xt = || tch::Tensor::from_slice(&data).set_requires_grad(true);
+        type TorchFn = fn(&tch::Tensor) -> tch::Tensor;
+        let cases: [(Activation, TorchFn); 8] = [
+            (Activation::Identity, |t| t * 1.0),
+            (Activation::ReLU, tch::Tensor::relu),
+            (Activation::LeakyReLU(0.01), tch::Tensor::leaky_relu),
+            (Activation::Tanh, tch::Tensor::tanh),
+            (Activation::Sigmoid, tch::Tensor::sigmoid),
+            (Activation::GELU, |t| t.gelu("tanh")),
+            (Activation::SiLU, tch::Tensor::silu),
+            (Activation::Softmax, |t| t.softmax(-1, tch::Kind::Double)),
+        ];
+        for (activation, torch) in cases {
+            let x: Vec<Value> = data.iter().map(Value::from).collect();
+            let y = activation.forward(&x).unwrap();
+            // Weight the outputs so the softmax gradient is not identically zero
+            let loss = y.iter().zip(weights).fold(Value::from(0.0), |acc, (y, weight)| acc + y * y * weight);
+            loss.backward();
+
+            let xt = xt();
+            let yt = torch(&xt);
+            (&yt * &yt * tch::Tensor::from_slice(&weights)).sum(None).backward();
+
+            let flat = |t: &tch::Tensor| Vec::<f64>::try_from(t.reshape([-1])).unwrap();
+            y.iter().zip(flat(&yt)).for_each(|(l, r)| assert_close!(l.data(), r));
+            x.iter().zip(flat(&xt.grad())).for_each(|(l, r)| assert_close!(l.grad().unwrap_or(0.0), r));
+        }
+    }
+}
@@ -0,0 +1,369 @@
+use crate::engine::FloatDataScalar;
+use crate::nn::Activation;
+use crate::nn::models::StateDict;
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Leading bytes of a binary checkpoint
+const MAGIC: &[u8; 8] = b"CRABGRAD";
+/// Bumped whenever the layout of either format changes
+pub const VERSION: u32 = 1;
+
+/// Element type tags in the binary header. Only `F64` can be loaded, since `FloatDataScalar` is `f64`
+const DTYPE_F32: u8 = 1;
+const DTYPE_F64: u8 = 2;
+
+/// Layer sizes and activations of an `MLP`, enough to rebuild it before loading its parameters
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Architecture {
+    pub in_dim: usize,
+    pub hidden_dims: Vec<usize>,
+    pub out_dim: usize,
+    pub bias: bool,
+    /// One per layer, including the output layer
+    pub activations: Vec<Activation>,
+}
+
+/// Serialization format of a checkpoint file, chosen from its extension by `Module::save` and `Module::load`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A versioned header followed by each parameter's name and little-endian `f64` data
+    Binary,
+    /// Human-readable, with the same contents as `Binary`
+    Json,
+}
+
+impl Format {
+    /// `Json` for a `.json` extension, `Binary` for anything else
+    #[must_use]
+    pub fn from_path(path: &Path) -> Self {
+        if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json")) { Self::Json } else { Self::Binary }
+    }
+}
+
+/// Everything `Module::save` writes: the architecture, if the module can describe one, and its state dict
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub architecture: Option<Architecture>,
+    pub state: StateDict,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonCheckpoint {
+    format: String,
+    version: u32,
+    dtype: String,
+    architecture: Option<Architecture>,
+    parameters: StateDict,
+}
+
+impl Checkpoint {
+    pub fn read(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("reading checkpoint {}", path.display()))?;
+        match Format::from_path(path) {
+            Format::Binary => Self::from_bytes(&bytes),
+            Format::Json => Self::from_json(std::str::from_utf8(&bytes)?),
+        }
+        .with_context(|| format!("loading checkpoint {}", path.display()))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let bytes = match Format::from_path(path) {
+            Format::Binary => self.to_bytes(),
+            Format::Json => self.to_json()?.into_bytes(),
+        };
+        std::fs::write(path, bytes).with_context(|| format!("writing checkpoint {}", path.display()))
+    }
+
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend(VERSION.to_le_bytes());
+        out.push(DTYPE_F64);
+        match &self.architecture {
+            None => out.push(0),
+            Some(architecture) => {
+                out.push(1);
+                let dims = std::iter::once(architecture.in_dim)
+                    .chain([architecture.hidden_dims.len()])
+                    .chain(architecture.hidden_dims.iter().copied())
+                    .chain([architecture.out_dim, architecture.activations.len()]);
+                dims.for_each(|dim| out.extend((dim as u64).to_le_bytes()));
+                out.push(u8::from(architecture.bias));
+                for activation in &architecture.activations {
+                    let (tag, param) = activation_tag(*activation);
+                    out.push(tag);
+                    out.extend(param.to_le_bytes());
+                }
+            }
+        }
+        out.extend((self.state.len() as u64).to_le_bytes());
+        for (name, data) in &self.state {
+            let len = u32::try_from(name.len()).expect("parameter names are shorter than 4 GiB");
+            out.extend(len.to_le_bytes());
+            out.extend(name.as_bytes());
+            out.extend(data.to_le_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            bail!("not a crabgrad checkpoint");
+        }
+        let version = u32::from_le_bytes(reader.array()?);
+        if version != VERSION {
+            bail!("unsupported checkpoint version {version}, expected {VERSION}");
+        }
+        match reader.byte()? {
+            DTYPE_F64 => {}
+            DTYPE_F32 => bail!("checkpoint stores f32 values, but parameters are f64"),
+            dtype => bail!("unknown dtype tag {dtype}"),
+        }
+        let architecture = match reader.byte()? {
+            0 => None,
+            1 => {
+                let in_dim = reader.usize()?;
+                let hidden_dims = (0..reader.usize()?).map(|_| reader.usize()).collect::<Result<_>>()?;
+                let out_dim = reader.usize()?;
+                let n_activations = reader.usize()?;
+                let bias = reader.byte()? != 0;
+                let activations = (0..n_activations)
+                    .map(|_| activation_from_tag(reader.byte()?, FloatDataScalar::from_le_bytes(reader.array()?)))
+                    .collect::<Result<_>>()?;
+                Some(Architecture { in_dim, hidden_dims, out_dim, bias, activations })
+            }
+            flag => bail!("invalid architecture flag {flag}"),
+        };
+        let n_params = reader.usize()?;
+        let mut state = Vec::with_capacity(n_params.min(bytes.len()));
+        for _ in 0..n_params {
+            let len = u32::from_le_bytes(reader.array()?) as usize;
+            let name = String::from_utf8(reader.take(len)?.to_vec())?;
+            state.push((name, FloatDataScalar::from_le_bytes(reader.array()?)));
+        }
+        if reader.pos != bytes.len() {
+            bail!("{} unexpected bytes after the last parameter", bytes.len() - reader.pos);
+        }
+        Ok(Self { architecture, state })
+    }
+
+    /// Fails on NaN or infinite parameters, which JSON cannot represent
+    pub fn to_json(&self) -> Result<String> {
+        if let Some((name, data)) = self.state.iter().find(|(_, data)| !data.is_finite()) {
+            bail!("parameter {name} is {data}, which cannot be written as JSON");
+        }
+        let checkpoint = JsonCheckpoint {
+            format: "crabgrad".to_string(),
+            version: VERSION,
+            dtype: "f64".to_string(),
+            architecture: self.architecture.clone(),
+            parameters: self.state.clone(),
+        };
+        Ok(serde_json::to_string_pretty(&checkpoint)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let checkpoint: JsonCheckpoint = serde_json::from_str(json)?;
+        if checkpoint.format != "crabgrad" {
+            bail!("not a crabgrad checkpoint");
+        }
+        if checkpoint.version != VERSION {
+            bail!("unsupported checkpoint version {}, expected {VERSION}", checkpoint.version);
+        }
+        if checkpoint.dtype != "f64" {
+            bail!("checkpoint stores {} values, but parameters are f64", checkpoint.dtype);
+        }
+        Ok(Self { architecture: checkpoint.architecture, state: checkpoint.parameters })
+    }
+}
+
+/// Tag and parameter of an activation in the binary format
+const fn activation_tag(activation: Activation) -> (u8, FloatDataScalar) {
+    match activation {
+        Activation::Identity => (0, 0.0),
+        Activation::ReLU => (1, 0.0),
+        Activation::LeakyReLU(slope) => (2, slope),
+        Activation::Tanh => (3, 0.0),
+        Activation::Sigmoid => (4, 0.0),
+        Activation::GELU => (5, 0.0),
+        Activation::SiLU => (6, 0.0),
+        Activation::Softmax => (7, 0.0),
+    }
+}
+
+fn activation_from_tag(tag: u8, param: FloatDataScalar) -> Result<Activation> {
+    Ok(match tag {
+        0 => Activation::Identity,
+        1 => Activation::ReLU,
+        2 => Activation::LeakyReLU(param),
+        3 => Activation::Tanh,
+        4 => Activation::Sigmoid,
+        5 => Activation::GELU,
+        6 => Activation::SiLU,
+        7 => Activation::Softmax,
+        _ => bail!("unknown activation tag {tag}"),
+    })
+}
+
+/// Cursor over the bytes of a binary checkpoint
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let Some(chunk) = self.bytes.get(self.pos..self.pos.saturating_add(n)) else {
+            bail!("checkpoint is truncated");
+        };
+        self.pos += n;
+        Ok(chunk)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("took exactly N bytes"))
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn usize(&mut self) -> Result<usize> {
+        Ok(usize::try_from(u64::from_le_bytes(self.array()?))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Tensor;
+    use crate::nn::{BatchNorm1d, Layer, LayerNorm, MLP, Module, Sequential};
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("crabgrad-{}-{name}", std::process::id()))
+    }
+
+    fn model(seed: u64) -> MLP {
+        MLP::new(3, &[5, 4], 2, true, Activation::LeakyReLU(0.1), &mut StdRng::seed_from_u64(seed))
+            .with_output_activation(Activation::Softmax)
+    }
+
+    fn predictions(model: &impl Module) -> Vec<u64> {
+        let x = Tensor::from_data(&[0.3, -1.2, 2.5, 0.0, 1.0, -0.7], &[2, 3]).unwrap();
+        model.forward_tensor(&x).unwrap().data().iter().map(|y| y.to_bits()).collect()
+    }
+
+    #[test]
+    fn mlp_round_trip_is_bit_identical() -> Result<()> {
+        let trained = model(0);
+        for name in ["mlp.ckpt", "mlp.json"] {
+            let path = temp_path(name);
+            trained.save(&path)?;
+
+            let rebuilt = MLP::from_checkpoint(&path)?;
+            assert_eq!(rebuilt.architecture(), trained.architecture());
+            assert_eq!(predictions(&rebuilt), predictions(&trained));
+
+            let loaded = model(1);
+            assert_ne!(predictions(&loaded), predictions(&trained));
+            loaded.load(&path)?;
+            assert_eq!(predictions(&loaded), predictions(&trained));
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn architecture_is_recorded() -> Result<()> {
+        let checkpoint =
+            Checkpoint::from_bytes(&Checkpoint { architecture: model(0).architecture(), state: vec![] }.to_bytes())?;
+        let expected = Architecture {
+            in_dim: 3,
+            hidden_dims: vec![5, 4],
+            out_dim: 2,
+            bias: true,
+            activations: vec![Activation::LeakyReLU(0.1), Activation::LeakyReLU(0.1), Activation::Softmax],
+        };
+        assert_eq!(checkpoint.architecture, Some(expected));
+        assert!(checkpoint.state.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn modules_without_architecture_load_in_place() -> Result<()> {
+        let norm = LayerNorm::new(3)?;
+        norm.weight.values().iter().zip([0.5, 1.5, -2.0]).for_each(|(w, v)| w.borrow_mut().data = v);
+        let path = temp_path("norm.ckpt");
+        norm.save(&path)?;
+        let loaded = LayerNorm::new(3)?;
+        loaded.load(&path)?;
+        assert_eq!(loaded.weight.data(), [0.5, 1.5, -2.0]);
+        assert!(MLP::from_checkpoint(&path).is_err());
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn batch_norm_running_stats_round_trip() -> Result<()> {
+        let model = || -> Result<Sequential> {
+            let layer = Layer::new(3, 2, true, Activation::Identity, &mut StdRng::seed_from_u64(0));
+            Ok(Sequential::new().add(layer).add(BatchNorm1d::new(2)?))
+        };
+        // Identical parameters, but only one model has moved its running statistics in training mode
+        let trained = model()?;
+        trained.forward_tensor(&Tensor::from_data(&[1.0, -2.0, 0.5, 3.0, 0.0, -1.5, 2.0, 2.0, 1.0], &[3, 3])?)?;
+        trained.eval();
+        let loaded = model()?;
+        loaded.eval();
+        assert_eq!(trained.state_dict().len(), trained.parameters().len() + 4);
+        assert!(trained.state_dict().iter().any(|(name, _)| name == "1.running_var.1"));
+        assert_ne!(predictions(&loaded), predictions(&trained));
+
+        let path = temp_path("batch-norm.ckpt");
+        trained.save(&path)?;
+        loaded.load(&path)?;
+        assert_eq!(predictions(&loaded), predictions(&trained));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn mismatches_are_rejected() -> Result<()> {
+        let trained = model(0);
+        let path = temp_path("mismatch.ckpt");
+        trained.save(&path)?;
+
+        // Hidden layers swapped, which the recorded architecture catches
+        let other = MLP::new(3, &[4, 5], 2, true, Activation::LeakyReLU(0.1), &mut StdRng::seed_from_u64(0));
+        assert!(other.load(&path).is_err());
+        assert!(LayerNorm::new(3)?.load(&path).is_err());
+
+        let bytes = std::fs::read(&path)?;
+        let mut f32_dtype = bytes.clone();
+        f32_dtype[12] = DTYPE_F32;
+        assert!(Checkpoint::from_bytes(&f32_dtype).unwrap_err().to_string().contains("f32"));
+        let mut newer = bytes.clone();
+        newer[8] = 2;
+        assert!(Checkpoint::from_bytes(&newer).is_err());
+        assert!(Checkpoint::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Checkpoint::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        assert!(Checkpoint::from_bytes(b"not a checkpoint").is_err());
+
+        let json = Checkpoint::from_bytes(&bytes)?.to_json()?;
+        assert!(Checkpoint::from_json(&json.replace("\"f64\"", "\"f32\"")).is_err());
+        assert!(Checkpoint { architecture: None, state: vec![("w".to_string(), f64::NAN)] }.to_json().is_err());
+        std::fs::remove_file(path)?;
+
+        // Loading failures leave the parameters alone
+        let before = predictions(&other);
+        assert!(other.load(&temp_path("missing.ckpt")).is_err());
+        assert_eq!(predictions(&other), before);
+        Ok(())
+    }
+}
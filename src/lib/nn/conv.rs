@@ -0,0 +1,814 @@
+use crate::engine::{FloatDataScalar, Value, mean, sum};
+use crate::nn::init::Init;
+use crate::nn::models::Module;
+use crate::tensor::Tensor;
+use anyhow::{Result, bail};
+use rand::Rng;
+
+/// Stride, padding, dilation and grouping of a `Conv2d`. The default is a plain convolution with a bias
+#[derive(Debug, Clone, Copy)]
+pub struct Conv2dConfig {
+    pub stride: (usize, usize),
+    /// Zero padding added on both sides of each spatial dimension
+    pub padding: (usize, usize),
+    pub dilation: (usize, usize),
+    /// Input and output channels are split into this many groups, each convolved separately
+    pub groups: usize,
+    pub bias: bool,
+}
+
+impl Default for Conv2dConfig {
+    fn default() -> Self {
+        Self { stride: (1, 1), padding: (0, 0), dilation: (1, 1), groups: 1, bias: true }
+    }
+}
+
+/// 2D convolution over `(batch, channels, height, width)` or unbatched `(channels, height, width)` tensors.
+///
+/// Computed as one matrix product per group between the unfolded receptive fields and the weights, so the backward
+/// pass goes through `Tensor::matmul`
+#[derive(Debug)]
+pub struct Conv2d {
+    /// `(out_channels, in_channels / groups, kernel_height, kernel_width)`
+    pub weight: Tensor,
+    /// `(out_channels,)`
+    pub bias: Option<Tensor>,
+    pub config: Conv2dConfig,
+    /// `(channels, height, width)` of one flattened sample, see `with_input_shape`
+    pub input_shape: Option<(usize, usize, usize)>,
+}
+
+impl Conv2d {
+    /// Kaiming normal weights, N(0, sqrt(2 / fan_in)) with `fan_in = in_channels / groups * kernel area`, and zero bias
+    pub fn new(
+        in_channels: usize,
+        out_channels: usize,
+        kernel_size: (usize, usize),
+        config: Conv2dConfig,
+        rng: &mut impl Rng,
+    ) -> Result<Self> {
+        let Conv2dConfig { stride, dilation, groups, bias, .. } = config;
+        if kernel_size.0 == 0
+            || kernel_size.1 == 0
+            || stride.0 == 0
+            || stride.1 == 0
+            || dilation.0 == 0
+            || dilation.1 == 0
+        {
+            bail!("kernel size, stride and dilation must be positive");
+        }
+        let kernel = [kernel_size.0, kernel_size.1];
+        let (weight, bias) = kaiming_init(in_channels, out_channels, &kernel, groups, bias, rng)?;
+        Ok(Self { weight, bias, config, input_shape: None })
+    }
+
+    /// Also take flattened `(channels, height, width)` samples: one in `forward`, or `(batch, features)` rows in
+    /// `forward_tensor`. The output is flattened the same way, so the layer can sit in a `Sequential` between dense
+    /// layers
+    #[must_use]
+    pub fn with_input_shape(self, input_shape: (usize, usize, usize)) -> Self {
+        Self { input_shape: Some(input_shape), ..self }
+    }
+}
+
+impl Module for Conv2d {
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        Ok(self.forward_tensor(&unflatten(data, self.input_shape, "Conv2d")?)?.values().to_vec())
+    }
+
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        forward_rows(data, self.input_shape, |data| {
+            let (input, unbatched) = as_batch(data)?;
+            unbatch(conv2d(&input, &self.weight, self.bias.as_ref(), &self.config)?, unbatched)
+        })
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        self.weight.values().iter().chain(self.bias.iter().flat_map(Tensor::values)).cloned().collect()
+    }
+}
+
+/// Stride, padding, dilation and grouping of a `Conv1d`. The default is a plain convolution with a bias
+#[derive(Debug, Clone, Copy)]
+pub struct Conv1dConfig {
+    pub stride: usize,
+    /// Zero padding added on both sides of the time dimension. Must be 0 for causal convolutions
+    pub padding: usize,
+    pub dilation: usize,
+    /// Input and output channels are split into this many groups, each convolved separately
+    pub groups: usize,
+    pub bias: bool,
+    /// Pad only on the left, by `dilation * (kernel_size - 1)`, so the output at time `t` depends only on inputs up to
+    /// `t`, and a stride of 1 keeps the sequence length
+    pub causal: bool,
+}
+
+impl Default for Conv1dConfig {
+    fn default() -> Self {
+        Self { stride: 1, padding: 0, dilation: 1, groups: 1, bias: true, causal: false }
+    }
+}
+
+/// 1D convolution over `(batch, channels, time)` or unbatched `(channels, time)` tensors, computed as a `Conv2d` with
+/// a height of 1
+#[derive(Debug)]
+pub struct Conv1d {
+    /// `(out_channels, in_channels / groups, kernel_size)`
+    pub weight: Tensor,
+    /// `(out_channels,)`
+    pub bias: Option<Tensor>,
+    pub config: Conv1dConfig,
+}
+
+impl Conv1d {
+    /// Kaiming normal weights, N(0, sqrt(2 / fan_in)) with `fan_in = in_channels / groups * kernel_size`, and zero bias
+    pub fn new(
+        in_channels: usize,
+        out_channels: usize,
+        kernel_size: usize,
+        config: Conv1dConfig,
+        rng: &mut impl Rng,
+    ) -> Result<Self> {
+        let Conv1dConfig { stride, padding, dilation, groups, bias, causal } = config;
+        if kernel_size == 0 || stride == 0 || dilation == 0 {
+            bail!("kernel size, stride and dilation must be positive");
+        }
+        if causal && padding != 0 {
+            bail!("causal convolutions pad on the left by themselves, so padding must be 0, got {padding}");
+        }
+        let (weight, bias) = kaiming_init(in_channels, out_channels, &[kernel_size], groups, bias, rng)?;
+        Ok(Self { weight, bias, config })
+    }
+
+    fn in_channels(&self) -> usize {
+        self.weight.shape()[1] * self.config.groups
+    }
+}
+
+impl Module for Conv1d {
+    /// Treats `data` as a flattened `(in_channels, time)` sequence and returns the flattened `(out_channels, time)`
+    /// output
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        let channels = self.in_channels();
+        if data.is_empty() || !data.len().is_multiple_of(channels) {
+            bail!("{} values cannot be split into {channels} input channels", data.len());
+        }
+        let input = Tensor::new(data.to_vec(), &[channels, data.len() / channels])?;
+        Ok(self.forward_tensor(&input)?.values().to_vec())
+    }
+
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        let (mut input, unbatched) = match data.ndim() {
+            2 => (data.unsqueeze(0)?, true),
+            3 => (data.clone(), false),
+            _ => bail!("expected a (batch, channels, time) or (channels, time) input, got shape {:?}", data.shape()),
+        };
+        let Conv1dConfig { stride, padding, dilation, groups, bias, causal } = self.config;
+        let kernel_size = self.weight.shape()[2];
+        if causal && kernel_size > 1 {
+            let &[batch, channels, _] = input.shape() else { unreachable!("input has 3 dimensions") };
+            let left = Tensor::scalar(0.0).broadcast_to(&[batch, channels, dilation * (kernel_size - 1)])?;
+            input = Tensor::concat(&[left, input], 2)?;
+        }
+        let config = Conv2dConfig { stride: (1, stride), padding: (0, padding), dilation: (1, dilation), groups, bias };
+        let out = conv2d(&input.unsqueeze(2)?, &self.weight.unsqueeze(2)?, self.bias.as_ref(), &config)?;
+        let &[batch, out_channels, _, time] = out.shape() else { unreachable!("conv2d returns 4 dimensions") };
+        unbatch(out.reshape(&[batch, out_channels, time])?, unbatched)
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        self.weight.values().iter().chain(self.bias.iter().flat_map(Tensor::values)).cloned().collect()
+    }
+}
+
+/// Max over each window. Padding never wins the max, as if it were negative infinity
+#[derive(Debug, Clone, Copy)]
+pub struct MaxPool2d {
+    pub kernel_size: (usize, usize),
+    pub stride: (usize, usize),
+    pub padding: (usize, usize),
+    /// `(channels, height, width)` of one flattened sample, see `with_input_shape`
+    pub input_shape: Option<(usize, usize, usize)>,
+}
+
+impl MaxPool2d {
+    /// Non-overlapping windows: the stride defaults to the kernel size
+    #[must_use]
+    pub fn new(kernel_size: (usize, usize)) -> Self {
+        Self { kernel_size, stride: kernel_size, padding: (0, 0), input_shape: None }
+    }
+
+    /// See `Conv2d::with_input_shape`
+    #[must_use]
+    pub fn with_input_shape(self, input_shape: (usize, usize, usize)) -> Self {
+        Self { input_shape: Some(input_shape), ..self }
+    }
+}
+
+impl Module for MaxPool2d {
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        Ok(self.forward_tensor(&unflatten(data, self.input_shape, "MaxPool2d")?)?.values().to_vec())
+    }
+
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        forward_rows(data, self.input_shape, |data| {
+            pool(data, self.kernel_size, self.stride, self.padding, |window, _| {
+                let mut best = &window[0];
+                for value in &window[1..] {
+                    if value.data() > best.data() {
+                        best = value;
+                    }
+                }
+                best.clone()
+            })
+        })
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        vec![]
+    }
+}
+
+/// Mean over each window. Padding counts towards the window size, as with torch's default `count_include_pad`
+#[derive(Debug, Clone, Copy)]
+pub struct AvgPool2d {
+    pub kernel_size: (usize, usize),
+    pub stride: (usize, usize),
+    pub padding: (usize, usize),
+    /// `(channels, height, width)` of one flattened sample, see `with_input_shape`
+    pub input_shape: Option<(usize, usize, usize)>,
+}
+
+impl AvgPool2d {
+    /// Non-overlapping windows: the stride defaults to the kernel size
+    #[must_use]
+    pub fn new(kernel_size: (usize, usize)) -> Self {
+        Self { kernel_size, stride: kernel_size, padding: (0, 0), input_shape: None }
+    }
+
+    /// See `Conv2d::with_input_shape`
+    #[must_use]
+    pub fn with_input_shape(self, input_shape: (usize, usize, usize)) -> Self {
+        Self { input_shape: Some(input_shape), ..self }
+    }
+}
+
+impl Module for AvgPool2d {
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        Ok(self.forward_tensor(&unflatten(data, self.input_shape, "AvgPool2d")?)?.values().to_vec())
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        forward_rows(data, self.input_shape, |data| {
+            pool(data, self.kernel_size, self.stride, self.padding, |window, area| {
+                sum(window) / area as FloatDataScalar
+            })
+        })
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        vec![]
+    }
+}
+
+/// Mean over windows chosen so that the output has a fixed size, whatever the input size. Windows span
+/// `floor(i * in / out)..ceil((i + 1) * in / out)` along each dimension, as in torch
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveAvgPool2d {
+    pub output_size: (usize, usize),
+    /// `(channels, height, width)` of one flattened sample, see `with_input_shape`
+    pub input_shape: Option<(usize, usize, usize)>,
+}
+
+impl AdaptiveAvgPool2d {
+    #[must_use]
+    pub fn new(output_size: (usize, usize)) -> Self {
+        Self { output_size, input_shape: None }
+    }
+
+    /// See `Conv2d::with_input_shape`
+    #[must_use]
+    pub fn with_input_shape(self, input_shape: (usize, usize, usize)) -> Self {
+        Self { input_shape: Some(input_shape), ..self }
+    }
+}
+
+impl Module for AdaptiveAvgPool2d {
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        Ok(self.forward_tensor(&unflatten(data, self.input_shape, "AdaptiveAvgPool2d")?)?.values().to_vec())
+    }
+
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        forward_rows(data, self.input_shape, |data| self.pool(data))
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        vec![]
+    }
+}
+
+impl AdaptiveAvgPool2d {
+    fn pool(&self, data: &Tensor) -> Result<Tensor> {
+        let (input, unbatched) = as_batch(data)?;
+        let &[batch, channels, height, width] = input.shape() else { unreachable!("as_batch returns 4 dimensions") };
+        let (out_h, out_w) = self.output_size;
+        if out_h == 0 || out_w == 0 || height == 0 || width == 0 {
+            bail!("cannot pool shape {:?} to {:?}", data.shape(), self.output_size);
+        }
+        let span = |idx: usize, len: usize, out: usize| (idx * len / out)..((idx + 1) * len).div_ceil(out);
+
+        let values = input.values();
+        let mut out = Vec::with_capacity(batch * channels * out_h * out_w);
+        for plane in 0..batch * channels {
+            for oy in 0..out_h {
+                for ox in 0..out_w {
+                    let window: Vec<Value> = span(oy, height, out_h)
+                        .flat_map(|y| span(ox, width, out_w).map(move |x| (y, x)))
+                        .map(|(y, x)| values[(plane * height + y) * width + x].clone())
+                        .collect();
+                    out.push(mean(&window));
+                }
+            }
+        }
+        unbatch(Tensor::new(out, &[batch, channels, out_h, out_w])?, unbatched)
+    }
+}
+
+/// Convolve a `(batch, channels, height, width)` input with `(out_channels, channels / groups, kh, kw)` weights
+fn conv2d(input: &Tensor, weight: &Tensor, bias: Option<&Tensor>, config: &Conv2dConfig) -> Result<Tensor> {
+    let &[batch, channels, height, width] = input.shape() else {
+        bail!("expected a (batch, channels, height, width) input, got shape {:?}", input.shape());
+    };
+    let &[out_channels, group_channels, kernel_h, kernel_w] = weight.shape() else {
+        bail!("convolution weight must have 4 dimensions, got shape {:?}", weight.shape());
+    };
+    let Conv2dConfig { stride, padding, dilation, groups, .. } = *config;
+    if channels != group_channels * groups {
+        bail!("convolution expects {} input channels, got {channels}", group_channels * groups);
+    }
+    let out_h = out_size(height, kernel_h, stride.0, padding.0, dilation.0)?;
+    let out_w = out_size(width, kernel_w, stride.1, padding.1, dilation.1)?;
+
+    // Unfold each group's receptive fields into rows of (batch * out_h * out_w, group_channels * kernel area),
+    // reading zero for positions in the padding
+    let zero = Value::from(0.0);
+    let values = input.values();
+    let group_out = out_channels / groups;
+    let mut outputs = Vec::with_capacity(groups);
+    for group in 0..groups {
+        let mut cols = Vec::with_capacity(batch * out_h * out_w * group_channels * kernel_h * kernel_w);
+        for b in 0..batch {
+            for oy in 0..out_h {
+                for ox in 0..out_w {
+                    for c in group * group_channels..(group + 1) * group_channels {
+                        for ky in 0..kernel_h {
+                            let y = input_pos(oy, ky, stride.0, padding.0, dilation.0, height);
+                            for kx in 0..kernel_w {
+                                let x = input_pos(ox, kx, stride.1, padding.1, dilation.1, width);
+                                cols.push(match (y, x) {
+                                    (Some(y), Some(x)) => values[((b * channels + c) * height + y) * width + x].clone(),
+                                    _ => zero.clone(),
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let cols = Tensor::new(cols, &[batch * out_h * out_w, group_channels * kernel_h * kernel_w])?;
+        let group_weight = weight.slice(0, group * group_out, (group + 1) * group_out)?;
+        let group_weight = group_weight.reshape(&[group_out, group_channels * kernel_h * kernel_w])?.transpose(0, 1)?;
+        outputs.push(cols.matmul(&group_weight)?);
+    }
+
+    let mut out = Tensor::concat(&outputs, 1)?;
+    if let Some(bias) = bias {
+        out = out + bias;
+    }
+    out.reshape(&[batch, out_h, out_w, out_channels])?.permute(&[0, 3, 1, 2])
+}
+
+/// Weights of shape `(out_channels, in_channels / groups, *kernel)` with Kaiming normal init, and an optional
+/// `(out_channels,)` bias of zeros
+fn kaiming_init(
+    in_channels: usize,
+    out_channels: usize,
+    kernel: &[usize],
+    groups: usize,
+    bias: bool,
+    rng: &mut impl Rng,
+) -> Result<(Tensor, Option<Tensor>)> {
+    if groups == 0 || !in_channels.is_multiple_of(groups) || !out_channels.is_multiple_of(groups) {
+        bail!("{in_channels} input and {out_channels} output channels cannot be split into {groups} groups");
+    }
+    let shape = [&[out_channels, in_channels / groups], kernel].concat();
+    let weight = Tensor::from_data(&Init::KaimingNormal.sample(&shape, rng)?, &shape)?;
+    let bias = if bias { Some(Tensor::from_data(&vec![0.0; out_channels], &[out_channels])?) } else { None };
+    Ok((weight, bias))
+}
+
+/// Apply `reduce` to every window of a pooling layer. `reduce` receives the values inside the input (never
+/// padding) and the full kernel area
+fn pool(
+    data: &Tensor,
+    kernel: (usize, usize),
+    stride: (usize, usize),
+    padding: (usize, usize),
+    reduce: impl Fn(&[Value], usize) -> Value,
+) -> Result<Tensor> {
+    let (input, unbatched) = as_batch(data)?;
+    let &[batch, channels, height, width] = input.shape() else { unreachable!("as_batch returns 4 dimensions") };
+    if kernel.0 == 0 || kernel.1 == 0 || stride.0 == 0 || stride.1 == 0 {
+        bail!("kernel size and stride must be positive");
+    }
+    if padding.0 > kernel.0 / 2 || padding.1 > kernel.1 / 2 {
+        bail!("padding {padding:?} must be at most half of the kernel size {kernel:?}");
+    }
+    let out_h = out_size(height, kernel.0, stride.0, padding.0, 1)?;
+    let out_w = out_size(width, kernel.1, stride.1, padding.1, 1)?;
+
+    let values = input.values();
+    let mut out = Vec::with_capacity(batch * channels * out_h * out_w);
+    for plane in 0..batch * channels {
+        for oy in 0..out_h {
+            for ox in 0..out_w {
+                let window: Vec<Value> = (0..kernel.0)
+                    .filter_map(|ky| input_pos(oy, ky, stride.0, padding.0, 1, height))
+                    .flat_map(|y| {
+                        (0..kernel.1)
+                            .filter_map(move |kx| input_pos(ox, kx, stride.1, padding.1, 1, width).map(|x| (y, x)))
+                    })
+                    .map(|(y, x)| values[(plane * height + y) * width + x].clone())
+                    .collect();
+                out.push(reduce(&window, kernel.0 * kernel.1));
+            }
+        }
+    }
+    unbatch(Tensor::new(out, &[batch, channels, out_h, out_w])?, unbatched)
+}
+
+/// Add a batch dimension to `(channels, height, width)` inputs, so layers only handle the batched case
+fn as_batch(data: &Tensor) -> Result<(Tensor, bool)> {
+    match data.ndim() {
+        3 => Ok((data.unsqueeze(0)?, true)),
+        4 => Ok((data.clone(), false)),
+        _ => bail!(
+            "expected a (batch, channels, height, width) or (channels, height, width) input, got shape {:?}",
+            data.shape()
+        ),
+    }
+}
+
+fn unbatch(out: Tensor, unbatched: bool) -> Result<Tensor> {
+    if unbatched { out.reshape(&out.shape()[1..]) } else { Ok(out) }
+}
+
+/// Reshape one flattened sample to a `(1, channels, height, width)` batch, for `Module::forward`
+fn unflatten(data: &[Value], input_shape: Option<(usize, usize, usize)>, name: &str) -> Result<Tensor> {
+    let Some((channels, height, width)) = input_shape else {
+        bail!("{name} needs an input shape to take flattened samples; see with_input_shape, or use forward_tensor");
+    };
+    Tensor::new(data.to_vec(), &[1, channels, height, width])
+}
+
+/// With an input shape, `(batch, features)` rows are unflattened before `forward` and its output is flattened back
+/// to rows. Other inputs go to `forward` as they are
+fn forward_rows(
+    data: &Tensor,
+    input_shape: Option<(usize, usize, usize)>,
+    forward: impl Fn(&Tensor) -> Result<Tensor>,
+) -> Result<Tensor> {
+    let (Some((channels, height, width)), &[batch, _]) = (input_shape, data.shape()) else {
+        return forward(data);
+    };
+    let out = forward(&data.reshape(&[batch, channels, height, width])?)?;
+    let features = out.numel().checked_div(batch).unwrap_or(0);
+    out.reshape(&[batch, features])
+}
+
+/// Output length along a dimension of length `len`, as in torch
+fn out_size(len: usize, kernel: usize, stride: usize, padding: usize, dilation: usize) -> Result<usize> {
+    let span = dilation * (kernel - 1) + 1;
+    match (len + 2 * padding).checked_sub(span) {
+        Some(room) => Ok(room / stride + 1),
+        None => bail!("kernel spanning {span} does not fit an input of length {len} with padding {padding}"),
+    }
+}
+
+/// Input position read by output position `out` at kernel offset `k`, or `None` if it falls in the padding
+fn input_pos(out: usize, k: usize, stride: usize, padding: usize, dilation: usize, len: usize) -> Option<usize> {
+    (out * stride + k * dilation).checked_sub(padding).filter(|&pos| pos < len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_close;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn rng() -> StdRng {
+        StdRng::seed_from_u64(0)
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn input(shape: &[usize]) -> Tensor {
+        let numel: usize = shape.iter().product();
+        Tensor::from_data(&(0..numel).map(|i| (i as FloatDataScalar * 0.37).sin()).collect::<Vec<_>>(), shape).unwrap()
+    }
+
+    #[test]
+    fn conv_shapes_and_init() -> Result<()> {
+        let config = Conv2dConfig { stride: (2, 1), padding: (1, 2), dilation: (1, 2), groups: 2, bias: false };
+        let conv = Conv2d::new(4, 6, (3, 3), config, &mut rng())?;
+        assert_eq!(conv.weight.shape(), &[6, 2, 3, 3]);
+        assert_eq!(conv.parameters().len(), 6 * 2 * 3 * 3);
+        assert!(conv.bias.is_none());
+
+        // (7 + 2 - 3) / 2 + 1 = 4 and (5 + 4 - 5) / 1 + 1 = 5
+        assert_eq!(conv.forward_tensor(&input(&[2, 4, 7, 5]))?.shape(), &[2, 6, 4, 5]);
+        assert_eq!(conv.forward_tensor(&input(&[4, 7, 5]))?.shape(), &[6, 4, 5]);
+
+        let std = (conv.weight.data().iter().map(|w| w * w).sum::<f64>() / 108.0).sqrt();
+        assert_close!(std, (2.0f64 / 18.0).sqrt(), 0.3, 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn conv_errors() -> Result<()> {
+        assert!(Conv2d::new(3, 4, (3, 3), Conv2dConfig { groups: 2, ..Default::default() }, &mut rng()).is_err());
+        assert!(Conv2d::new(3, 4, (0, 3), Conv2dConfig::default(), &mut rng()).is_err());
+        let conv = Conv2d::new(3, 4, (3, 3), Conv2dConfig::default(), &mut rng())?;
+        assert!(conv.forward(&[Value::from(1.0)]).is_err());
+        assert_eq!(conv.forward_tensor(&input(&[2, 3, 3, 3]))?.shape(), &[2, 4, 1, 1]);
+        assert!(conv.forward_tensor(&input(&[2, 2, 5, 5])).is_err());
+        assert!(conv.forward_tensor(&input(&[2, 3, 2, 5])).is_err());
+        assert!(conv.forward_tensor(&input(&[3, 5])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn convs_start_distinct_and_reproducible() -> Result<()> {
+        let mut shared = rng();
+        let first = Conv2d::new(8, 8, (3, 3), Conv2dConfig::default(), &mut shared)?;
+        let second = Conv2d::new(8, 8, (3, 3), Conv2dConfig::default(), &mut shared)?;
+        assert_ne!(first.weight.data(), second.weight.data());
+        let again = Conv2d::new(8, 8, (3, 3), Conv2dConfig::default(), &mut rng())?;
+        assert_eq!(first.weight.data(), again.weight.data());
+
+        let causal = Conv1dConfig { causal: true, ..Default::default() };
+        let first = Conv1d::new(8, 8, 3, causal, &mut shared)?;
+        let second = Conv1d::new(8, 8, 3, Conv1dConfig { dilation: 2, ..causal }, &mut shared)?;
+        assert_ne!(first.weight.data(), second.weight.data());
+        Ok(())
+    }
+
+    #[test]
+    fn conv_by_hand() -> Result<()> {
+        // A single 2x2 kernel of ones sums each window, and padding reads zeros
+        let mut conv =
+            Conv2d::new(1, 1, (2, 2), Conv2dConfig { padding: (1, 0), bias: false, ..Default::default() }, &mut rng())?;
+        conv.weight = Tensor::from_data(&[1.0; 4], &[1, 1, 2, 2])?;
+        let x = Tensor::from_data(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[1, 2, 3])?;
+        let y = conv.forward_tensor(&x)?;
+        assert_eq!(y.shape(), &[1, 3, 2]);
+        assert_eq!(y.data(), vec![3.0, 5.0, 12.0, 16.0, 9.0, 11.0]);
+
+        // Each input lies in two windows vertically, and in one or two horizontally
+        y.sum().backward();
+        assert_eq!(x.grad().unwrap(), vec![2.0, 4.0, 2.0, 2.0, 4.0, 2.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn conv1d_causal_by_hand() -> Result<()> {
+        // y_t = x_{t-2} + 2 x_t, reading zero before the start of the sequence
+        let config = Conv1dConfig { dilation: 2, bias: false, causal: true, ..Default::default() };
+        let mut conv = Conv1d::new(1, 1, 2, config, &mut rng())?;
+        assert_eq!(conv.weight.shape(), &[1, 1, 2]);
+        conv.weight = Tensor::from_data(&[1.0, 2.0], &[1, 1, 2])?;
+        let x = Tensor::from_data(&[1.0, 2.0, 3.0, 4.0, 5.0], &[1, 5])?;
+        let y = conv.forward_tensor(&x)?;
+        assert_eq!(y.shape(), &[1, 5]);
+        assert_eq!(y.data(), vec![2.0, 4.0, 7.0, 10.0, 13.0]);
+        y.values()[2].backward();
+        assert_eq!(x.grad().unwrap(), vec![1.0, 0.0, 2.0, 0.0, 0.0]);
+
+        let flat = conv.forward(x.values())?;
+        assert_eq!(flat.iter().map(Value::data).collect::<Vec<_>>(), y.data());
+        Ok(())
+    }
+
+    #[test]
+    fn conv1d_is_causal() -> Result<()> {
+        let config = Conv1dConfig { dilation: 3, groups: 2, causal: true, ..Default::default() };
+        let conv = Conv1d::new(4, 2, 3, config, &mut rng())?;
+        let x = input(&[2, 4, 9]);
+        assert_eq!(conv.forward_tensor(&x)?.shape(), &[2, 2, 9]);
+        for t in 0..9 {
+            // A fresh graph each time, since backward accumulates into intermediate nodes
+            x.zero_grad();
+            conv.forward_tensor(&x)?.values()[9 + t].backward();
+            let grad = x.grad().unwrap();
+            for (idx, g) in grad.iter().enumerate() {
+                let (batch, time) = (idx / 36, idx % 9);
+                if batch != 0 || time > t {
+                    assert_eq!(*g, 0.0, "output at {t} depends on input {idx}");
+                }
+            }
+            // The dilated kernel reaches back to t - 3 and t - 6
+            assert!(grad[9 * 2 + t] != 0.0 || grad[9 * 3 + t] != 0.0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn conv1d_errors() -> Result<()> {
+        assert!(
+            Conv1d::new(2, 2, 3, Conv1dConfig { causal: true, padding: 1, ..Default::default() }, &mut rng()).is_err()
+        );
+        assert!(Conv1d::new(3, 2, 3, Conv1dConfig { groups: 2, ..Default::default() }, &mut rng()).is_err());
+        assert!(Conv1d::new(2, 2, 3, Conv1dConfig { stride: 0, ..Default::default() }, &mut rng()).is_err());
+        let conv = Conv1d::new(2, 3, 3, Conv1dConfig::default(), &mut rng())?;
+        assert_eq!(conv.forward_tensor(&input(&[2, 5]))?.shape(), &[3, 3]);
+        assert!(conv.forward_tensor(&input(&[3, 5])).is_err());
+        assert!(conv.forward_tensor(&input(&[2, 2])).is_err());
+        assert!(conv.forward_tensor(&input(&[1, 2, 2, 5])).is_err());
+        assert!(conv.forward(input(&[5]).values()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pools_by_hand() -> Result<()> {
+        let x = Tensor::from_data(
+            &[1.0, 5.0, 2.0, 0.0, 3.0, 4.0, 8.0, 1.0, 0.0, 2.0, 1.0, 1.0, 6.0, 0.0, 3.0, 9.0],
+            &[1, 4, 4],
+        )?;
+        let max = MaxPool2d::new((2, 2)).forward_tensor(&x)?;
+        assert_eq!(max.data(), vec![5.0, 8.0, 6.0, 9.0]);
+        let avg = AvgPool2d::new((2, 2)).forward_tensor(&x)?;
+        assert_eq!(avg.data(), vec![13.0 / 4.0, 11.0 / 4.0, 8.0 / 4.0, 14.0 / 4.0]);
+        let adaptive = AdaptiveAvgPool2d::new((1, 1)).forward_tensor(&x)?;
+        assert_eq!(adaptive.shape(), &[1, 1, 1]);
+        assert_close!(adaptive.data()[0], 46.0 / 16.0);
+
+        // Max pooling routes the gradient only to the winner of each window
+        max.sum().backward();
+        let grad = x.grad().unwrap();
+        assert_eq!(grad.iter().sum::<f64>(), 4.0);
+        assert_eq!((grad[1], grad[6], grad[12], grad[15]), (1.0, 1.0, 1.0, 1.0));
+
+        assert!(MaxPool2d { stride: (1, 1), padding: (2, 0), ..MaxPool2d::new((2, 2)) }.forward_tensor(&x).is_err());
+        assert!(MaxPool2d::new((5, 5)).forward_tensor(&x).is_err());
+        assert!(AdaptiveAvgPool2d::new((0, 2)).forward_tensor(&x).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn flattened_samples() -> Result<()> {
+        let conv = Conv2d::new(2, 3, (3, 3), Conv2dConfig::default(), &mut rng())?.with_input_shape((2, 5, 4));
+        let images = input(&[2, 2, 5, 4]);
+        let expected = conv.forward_tensor(&images)?;
+        let rows = conv.forward_tensor(&images.reshape(&[2, 40])?)?;
+        assert_eq!(rows.shape(), &[2, 3 * 3 * 2]);
+        assert_eq!(rows.data(), expected.data());
+        let sample: Vec<FloatDataScalar> = conv.forward(&images.values()[..40])?.iter().map(Value::data).collect();
+        assert_eq!(sample, expected.data()[..18]);
+        assert!(conv.forward(&images.values()[..39]).is_err());
+        assert!(conv.forward_tensor(&images.reshape(&[4, 20])?).is_err());
+
+        let x = input(&[3, 4, 4]);
+        let flat = x.values();
+        let pools: [Box<dyn Module>; 3] = [
+            Box::new(MaxPool2d::new((2, 2)).with_input_shape((3, 4, 4))),
+            Box::new(AvgPool2d::new((2, 2)).with_input_shape((3, 4, 4))),
+            Box::new(AdaptiveAvgPool2d::new((1, 2)).with_input_shape((3, 4, 4))),
+        ];
+        for pool in pools {
+            let out: Vec<FloatDataScalar> = pool.forward(flat)?.iter().map(Value::data).collect();
+            assert_eq!(out, pool.forward_tensor(&x)?.data());
+        }
+        assert!(MaxPool2d::new((2, 2)).forward(flat).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+    fn compare_torch_conv2d() {
+        let configs = [
+            ((3, 3), Conv2dConfig::default()),
+            ((3, 2), Conv2dConfig { stride: (2, 1), padding: (1, 2), ..Default::default() }),
+            ((2, 3), Conv2dConfig { dilation: (2, 1), padding: (1, 1), bias: false, ..Default::default() }),
+            ((3, 3), Conv2dConfig { groups: 2, stride: (1, 2), dilation: (1, 2), padding: (2, 1), bias: true }),
+        ];
+        for (kernel, config) in configs {
+            let conv = Conv2d::new(4, 6, kernel, config, &mut rng()).unwrap();
+            let x = input(&[2, 4, 7, 6]);
+            let y = conv.forward_tensor(&x).unwrap();
+            (&y * &y).sum().backward();
+
+            let to_tch = |t: &Tensor| {
+                let dims: Vec<i64> = t.shape().iter().map(|&d| d as i64).collect();
+                tch::Tensor::from_slice(&t.data()).reshape(dims).set_requires_grad(true)
+            };
+            let (xt, wt) = (to_tch(&x), to_tch(&conv.weight));
+            let bt = conv.bias.as_ref().map(to_tch);
+            let pair = |p: (usize, usize)| [p.0 as i64, p.1 as i64];
+            let yt = xt.conv2d(
+                &wt,
+                bt.as_ref(),
+                pair(config.stride),
+                pair(config.padding),
+                pair(config.dilation),
+                config.groups as i64,
+            );
+            (&yt * &yt).sum(None).backward();
+
+            let flat = |t: &tch::Tensor| Vec::<FloatDataScalar>::try_from(t.reshape([-1])).unwrap();
+            let yt_shape: Vec<usize> = yt.size().iter().map(|&d| d as usize).collect();
+            assert_eq!(y.shape(), yt_shape);
+            y.data().iter().zip(flat(&yt)).for_each(|(l, r)| assert_close!(*l, r));
+            x.grad().unwrap().iter().zip(flat(&xt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+            conv.weight.grad().unwrap().iter().zip(flat(&wt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+            if let (Some(b), Some(bt)) = (&conv.bias, &bt) {
+                b.grad().unwrap().iter().zip(flat(&bt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+            }
+        }
+    }
+
+    #[test]
+    #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+    fn compare_torch_conv1d() {
+        let configs = [
+            (3, Conv1dConfig::default()),
+            (2, Conv1dConfig { stride: 2, padding: 1, bias: false, ..Default::default() }),
+            (3, Conv1dConfig { dilation: 2, groups: 2, causal: true, ..Default::default() }),
+            (4, Conv1dConfig { stride: 2, causal: true, ..Default::default() }),
+        ];
+        for (kernel, config) in configs {
+            let conv = Conv1d::new(4, 6, kernel, config, &mut rng()).unwrap();
+            let x = input(&[2, 4, 11]);
+            let y = conv.forward_tensor(&x).unwrap();
+            (&y * &y).sum().backward();
+
+            let to_tch = |t: &Tensor| {
+                let dims: Vec<i64> = t.shape().iter().map(|&d| d as i64).collect();
+                tch::Tensor::from_slice(&t.data()).reshape(dims).set_requires_grad(true)
+            };
+            let (xt, wt) = (to_tch(&x), to_tch(&conv.weight));
+            let bt = conv.bias.as_ref().map(to_tch);
+            // torch has no causal mode, so pad the input on the left by hand
+            let left = if config.causal { (config.dilation * (kernel - 1)) as i64 } else { 0 };
+            let yt = xt.constant_pad_nd([left, 0]).conv1d(
+                &wt,
+                bt.as_ref(),
+                [config.stride as i64],
+                [config.padding as i64],
+                [config.dilation as i64],
+                config.groups as i64,
+            );
+            (&yt * &yt).sum(None).backward();
+
+            let flat = |t: &tch::Tensor| Vec::<FloatDataScalar>::try_from(t.reshape([-1])).unwrap();
+            let yt_shape: Vec<usize> = yt.size().iter().map(|&d| d as usize).collect();
+            assert_eq!(y.shape(), yt_shape);
+            y.data().iter().zip(flat(&yt)).for_each(|(l, r)| assert_close!(*l, r));
+            x.grad().unwrap().iter().zip(flat(&xt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+            conv.weight.grad().unwrap().iter().zip(flat(&wt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+            if let (Some(b), Some(bt)) = (&conv.bias, &bt) {
+                b.grad().unwrap().iter().zip(flat(&bt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+            }
+        }
+    }
+
+    #[test]
+    #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+    fn compare_torch_pools() {
+        let x = input(&[2, 3, 7, 6]);
+        let dims: Vec<i64> = x.shape().iter().map(|&d| d as i64).collect();
+        let xt = tch::Tensor::from_slice(&x.data()).reshape(dims).set_requires_grad(true);
+        let flat = |t: &tch::Tensor| Vec::<FloatDataScalar>::try_from(t.reshape([-1])).unwrap();
+
+        let max = MaxPool2d { stride: (2, 1), padding: (1, 1), ..MaxPool2d::new((3, 2)) };
+        let avg = AvgPool2d { stride: (2, 2), padding: (1, 1), ..AvgPool2d::new((3, 3)) };
+        let adaptive = AdaptiveAvgPool2d::new((3, 4));
+        let y = [max.forward_tensor(&x), avg.forward_tensor(&x), adaptive.forward_tensor(&x)].map(Result::unwrap);
+        let yt = [
+            xt.max_pool2d([3, 2], [2, 1], [1, 1], [1, 1], false),
+            xt.avg_pool2d([3, 3], [2, 2], [1, 1], false, true, None::<i64>),
+            xt.adaptive_avg_pool2d([3, 4]),
+        ];
+        for (y, yt) in y.iter().zip(&yt) {
+            let yt_shape: Vec<usize> = yt.size().iter().map(|&d| d as usize).collect();
+            assert_eq!(y.shape(), yt_shape);
+            y.data().iter().zip(flat(yt)).for_each(|(l, r)| assert_close!(*l, r));
+        }
+
+        let loss: Value = y.iter().map(|y| (y * y).sum()).fold(Value::from(0.0), |acc, l| acc + l);
+        loss.backward();
+        let loss_t = yt.iter().map(|yt| (yt * yt).sum(None)).fold(tch::Tensor::from(0.0), |acc, l| acc + l);
+        loss_t.backward();
+        x.grad().unwrap().iter().zip(flat(&xt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+    }
+}
@@ -0,0 +1,126 @@
+use crate::engine::{FloatDataScalar, Value};
+use crate::nn::DenseOp;
+use crate::nn::models::Module;
+use crate::tensor::Tensor;
+use anyhow::{Result, bail};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::{Cell, RefCell};
+
+/// Zeroes each input with probability `p` while training, and scales the rest by `1 / (1 - p)` so the expected output
+/// matches the input. In evaluation mode the input passes through unchanged.
+///
+/// Starts in training mode. The mask is drawn from its own seeded RNG, so runs are reproducible
+#[derive(Debug)]
+pub struct Dropout {
+    p: FloatDataScalar,
+    training: Cell<bool>,
+    rng: RefCell<StdRng>,
+}
+
+impl Dropout {
+    pub fn new(p: FloatDataScalar, seed: u64) -> Result<Self> {
+        if !(0.0..=1.0).contains(&p) {
+            bail!("dropout probability must be in [0, 1], got {p}");
+        }
+        Ok(Self { p, training: Cell::new(true), rng: RefCell::new(StdRng::seed_from_u64(seed)) })
+    }
+
+    #[must_use]
+    pub fn p(&self) -> FloatDataScalar {
+        self.p
+    }
+
+    #[must_use]
+    pub fn is_training(&self) -> bool {
+        self.training.get()
+    }
+
+    fn mask(&self, value: &Value) -> Value {
+        let mut rng = self.rng.borrow_mut();
+        let scale = if self.p < 1.0 && rng.random::<FloatDataScalar>() >= self.p { 1.0 / (1.0 - self.p) } else { 0.0 };
+        value * scale
+    }
+}
+
+impl Module for Dropout {
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        if !self.training.get() || self.p == 0.0 {
+            return Ok(data.to_vec());
+        }
+        Ok(data.iter().map(|value| self.mask(value)).collect())
+    }
+
+    /// Applied elementwise, so any shape is accepted
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        if !self.training.get() || self.p == 0.0 {
+            return Ok(data.clone());
+        }
+        Ok(data.map(|value| self.mask(value)))
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        vec![]
+    }
+
+    fn set_training(&self, training: bool) {
+        self.training.set(training);
+    }
+
+    /// Nothing, as dropout is the identity at inference
+    fn dense_ops(&self) -> Option<Vec<DenseOp>> {
+        Some(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_close;
+
+    #[test]
+    fn drops_and_rescales_while_training() -> Result<()> {
+        let dropout = Dropout::new(0.25, 0)?;
+        let x = Tensor::from_data(&vec![2.0; 4000], &[40, 100])?;
+        let y = dropout.forward_tensor(&x)?;
+        assert_eq!(y.shape(), x.shape());
+        let data = y.data();
+        assert!(data.iter().all(|v| *v == 0.0 || *v == 2.0 / 0.75));
+        #[allow(clippy::cast_precision_loss)]
+        let kept = data.iter().filter(|v| **v != 0.0).count() as f64 / 4000.0;
+        assert_close!(kept, 0.75, 0.05, 0.0);
+        // Inverted scaling keeps the mean
+        assert_close!(y.mean().data(), 2.0, 0.05, 0.0);
+
+        // Dropped inputs receive no gradient, kept ones receive the scale
+        y.sum().backward();
+        for (grad, out) in x.grad().unwrap().iter().zip(&data) {
+            assert_eq!(*grad, if *out == 0.0 { 0.0 } else { 1.0 / 0.75 });
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn eval_is_identity_and_seed_is_reproducible() -> Result<()> {
+        let x: Vec<Value> = (0..50).map(|i| Value::from(f64::from(i))).collect();
+        let masks = |seed| {
+            let dropout = Dropout::new(0.5, seed).unwrap();
+            dropout.forward(&x).unwrap().iter().map(Value::data).collect::<Vec<_>>()
+        };
+        assert_eq!(masks(7), masks(7));
+        assert_ne!(masks(7), masks(8));
+
+        let dropout = Dropout::new(0.5, 0)?;
+        assert!(dropout.is_training());
+        dropout.eval();
+        assert!(!dropout.is_training());
+        assert_eq!(dropout.forward(&x)?, x);
+        dropout.train();
+        assert_ne!(dropout.forward(&x)?, x);
+
+        assert!(Dropout::new(1.0, 0)?.forward(&x)?.iter().all(|v| v.data() == 0.0));
+        assert!(Dropout::new(-0.1, 0).is_err());
+        assert!(Dropout::new(1.5, 0).is_err());
+        Ok(())
+    }
+}
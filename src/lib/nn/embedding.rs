@@ -0,0 +1,181 @@
+use crate::engine::{FloatDataScalar, Value};
+use crate::nn::models::Module;
+use crate::tensor::Tensor;
+use anyhow::{Result, bail};
+use rand::Rng;
+use rand_distr::{Distribution, StandardNormal};
+
+/// Lookup table from indices to learnable vectors.
+///
+/// A lookup only connects the selected rows to the graph, so after `backward` the other rows have no gradient, and
+/// optimizers skip them: each step only touches the rows that were used
+#[derive(Debug)]
+pub struct Embedding {
+    /// `(num_embeddings, dim)`
+    pub weight: Tensor,
+    /// Index whose vector stays at zero and never receives a gradient, e.g. for padding tokens
+    pub padding_idx: Option<usize>,
+    /// Rows longer than this are scaled down to this L2 norm in place when they are looked up, as in torch
+    pub max_norm: Option<FloatDataScalar>,
+}
+
+impl Embedding {
+    /// Weights drawn from N(0, 1), as in torch
+    pub fn new(num_embeddings: usize, dim: usize, rng: &mut impl Rng) -> Result<Self> {
+        let weights: Vec<FloatDataScalar> = StandardNormal.sample_iter(rng).take(num_embeddings * dim).collect();
+        Ok(Self { weight: Tensor::from_data(&weights, &[num_embeddings, dim])?, padding_idx: None, max_norm: None })
+    }
+
+    /// Zeroes the row at `padding_idx`, which then stays out of the graph
+    pub fn with_padding_idx(mut self, padding_idx: usize) -> Result<Self> {
+        self.row(padding_idx)?.iter().for_each(|value| value.borrow_mut().data = 0.0);
+        self.padding_idx = Some(padding_idx);
+        Ok(self)
+    }
+
+    #[must_use]
+    pub fn with_max_norm(mut self, max_norm: FloatDataScalar) -> Self {
+        self.max_norm = Some(max_norm);
+        self
+    }
+
+    #[must_use]
+    pub fn num_embeddings(&self) -> usize {
+        self.weight.shape()[0]
+    }
+
+    #[must_use]
+    pub fn dim(&self) -> usize {
+        self.weight.shape()[1]
+    }
+
+    fn row(&self, idx: usize) -> Result<&[Value]> {
+        if idx >= self.num_embeddings() {
+            bail!("index {idx} is out of range for {} embeddings", self.num_embeddings());
+        }
+        Ok(&self.weight.values()[idx * self.dim()..(idx + 1) * self.dim()])
+    }
+
+    /// Vectors for `indices`, as a `(indices.len(), dim)` tensor
+    pub fn lookup(&self, indices: &[usize]) -> Result<Tensor> {
+        let mut out = Vec::with_capacity(indices.len() * self.dim());
+        for &idx in indices {
+            let row = self.row(idx)?;
+            if self.padding_idx == Some(idx) {
+                out.extend((0..self.dim()).map(|_| Value::from(0.0)));
+                continue;
+            }
+            if let Some(max_norm) = self.max_norm {
+                renormalize(row, max_norm);
+            }
+            out.extend_from_slice(row);
+        }
+        Tensor::new(out, &[indices.len(), self.dim()])
+    }
+}
+
+/// Scale `row` down to `max_norm` if it is longer, like `Neuron::normalize` but only past the threshold
+fn renormalize(row: &[Value], max_norm: FloatDataScalar) {
+    let norm = row.iter().fold(0.0, |acc, val| val.data().mul_add(val.data(), acc)).sqrt();
+    if norm > max_norm {
+        // Same small offset as torch, so the result lands just inside the threshold
+        let scale = max_norm / (norm + 1e-7);
+        row.iter().for_each(|p| p.borrow_mut().data = p.data() * scale);
+    }
+}
+
+/// Integer index stored in a `Value`
+fn index_of(value: &Value) -> Result<usize> {
+    let data = value.data();
+    if data < 0.0 || data.fract() != 0.0 || data > FloatDataScalar::from(u32::MAX) {
+        bail!("embedding indices must be non-negative integers, got {data}");
+    }
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    Ok(data as usize)
+}
+
+impl Module for Embedding {
+    /// Each value is an index, so categorical IDs can be fed as ordinary features. Returns the looked-up vectors
+    /// concatenated
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        let indices = data.iter().map(index_of).collect::<Result<Vec<_>>>()?;
+        Ok(self.lookup(&indices)?.values().to_vec())
+    }
+
+    /// Indices of any shape, to vectors of that shape followed by `dim`, e.g. `(batch, seq)` to `(batch, seq, dim)`
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        let indices = data.values().iter().map(index_of).collect::<Result<Vec<_>>>()?;
+        self.lookup(&indices)?.reshape(&[data.shape(), &[self.dim()]].concat())
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        self.weight.values().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_close;
+    use crate::optim::{AdamW, Optim};
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn lookup_shapes_and_values() -> Result<()> {
+        let embedding = Embedding::new(5, 3, &mut StdRng::seed_from_u64(0))?;
+        assert_eq!(embedding.parameters().len(), 15);
+        let weight = embedding.weight.data();
+
+        let out = embedding.lookup(&[4, 0, 4])?;
+        assert_eq!(out.shape(), &[3, 3]);
+        assert_eq!(out.data()[..3], weight[12..]);
+        assert_eq!(out.data()[3..6], weight[..3]);
+
+        let ids = Tensor::from_data(&[1.0, 2.0, 3.0, 1.0], &[2, 2])?;
+        let out = embedding.forward_tensor(&ids)?;
+        assert_eq!(out.shape(), &[2, 2, 3]);
+        assert_eq!(out.data()[9..], weight[3..6]);
+        assert_eq!(embedding.forward(&[Value::from(2.0)])?.len(), 3);
+
+        assert!(embedding.lookup(&[5]).is_err());
+        assert!(embedding.forward(&[Value::from(1.5)]).is_err());
+        assert!(embedding.forward(&[Value::from(-1.0)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn only_used_rows_are_updated() -> Result<()> {
+        let embedding = Embedding::new(4, 2, &mut StdRng::seed_from_u64(0))?.with_padding_idx(0)?;
+        let before = embedding.weight.data();
+        assert_eq!(before[..2], [0.0, 0.0]);
+        let mut optim = AdamW::new(embedding.parameters(), 0.1, 0.9, 0.999, 1e-8, 0.0);
+
+        // Row 2 is used twice, so its gradient accumulates; rows 1 and 3 are untouched
+        optim.zero_grad();
+        let out = embedding.lookup(&[2, 0, 2])?;
+        out.sum().backward();
+        let grad: Vec<Option<f64>> = embedding.parameters().iter().map(Value::grad).collect();
+        assert_eq!(grad, vec![None, None, None, None, Some(2.0), Some(2.0), None, None]);
+        optim.step();
+
+        let after = embedding.weight.data();
+        for (idx, (before, after)) in before.iter().zip(&after).enumerate() {
+            assert_eq!(before == after, !(4..6).contains(&idx), "row {}", idx / 2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn max_norm_renormalizes_looked_up_rows() -> Result<()> {
+        let mut embedding = Embedding::new(3, 2, &mut StdRng::seed_from_u64(0))?.with_max_norm(0.5);
+        embedding.weight = Tensor::from_data(&[3.0, 4.0, 0.1, 0.2, 6.0, 8.0], &[3, 2])?;
+        let out = embedding.lookup(&[0, 1])?;
+        let norm = |v: &[f64]| v.iter().map(|x| x * x).sum::<f64>().sqrt();
+        assert_close!(norm(&out.data()[..2]), 0.5, 1e-6, 0.0);
+        assert_eq!(out.data()[2..], [0.1, 0.2]);
+        // Rows that were not looked up keep their norm
+        assert_close!(norm(&embedding.weight.data()[4..]), 10.0);
+        Ok(())
+    }
+}
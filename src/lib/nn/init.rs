@@ -0,0 +1,159 @@
+use crate::engine::FloatDataScalar;
+use anyhow::{Result, bail};
+use rand::Rng;
+use rand_distr::{Distribution, Normal, Uniform};
+
+/// Scheme for drawing initial weights.
+///
+/// Fans follow torch: for a `(out, in, *kernel)` shape, `fan_in = in * kernel size` and `fan_out = out * kernel size`.
+/// The Kaiming variants use the ReLU gain of `sqrt(2)`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Init {
+    /// N(0, sqrt(2 / fan_in))
+    KaimingNormal,
+    /// U(-sqrt(6 / fan_in), sqrt(6 / fan_in))
+    KaimingUniform,
+    /// N(0, sqrt(2 / (fan_in + fan_out)))
+    XavierNormal,
+    /// U(-sqrt(6 / (fan_in + fan_out)), sqrt(6 / (fan_in + fan_out)))
+    XavierUniform,
+    /// N(0, sqrt(1 / fan_in))
+    LeCunNormal,
+    /// U(-sqrt(3 / fan_in), sqrt(3 / fan_in))
+    LeCunUniform,
+    /// A matrix with orthonormal rows or columns, whichever are fewer, after flattening all but the first dimension
+    Orthogonal,
+    Constant(FloatDataScalar),
+    Zeros,
+}
+
+impl Init {
+    /// Row-major values for a tensor of `shape`. All schemes except `Constant` and `Zeros` need at least 2 dimensions
+    pub fn sample(self, shape: &[usize], rng: &mut impl Rng) -> Result<Vec<FloatDataScalar>> {
+        let numel: usize = shape.iter().product();
+        match self {
+            Self::Constant(value) => return Ok(vec![value; numel]),
+            Self::Zeros => return Ok(vec![0.0; numel]),
+            _ if numel == 0 => return Ok(vec![]),
+            _ => {}
+        }
+        let &[out_dim, in_dim, ..] = shape else {
+            bail!("{self:?} init needs at least 2 dimensions, got shape {shape:?}");
+        };
+        let receptive: usize = shape[2..].iter().product();
+        #[allow(clippy::cast_precision_loss)]
+        let (fan_in, fan_out) = ((in_dim * receptive) as FloatDataScalar, (out_dim * receptive) as FloatDataScalar);
+
+        let normal = |std: FloatDataScalar, rng: &mut _| -> Result<Vec<_>> {
+            Ok(Normal::new(0.0, std)?.sample_iter(rng).take(numel).collect())
+        };
+        let uniform = |bound: FloatDataScalar, rng: &mut _| -> Result<Vec<_>> {
+            Ok(Uniform::new_inclusive(-bound, bound)?.sample_iter(rng).take(numel).collect())
+        };
+        match self {
+            Self::KaimingNormal => normal((2.0 / fan_in).sqrt(), rng),
+            Self::KaimingUniform => uniform((6.0 / fan_in).sqrt(), rng),
+            Self::XavierNormal => normal((2.0 / (fan_in + fan_out)).sqrt(), rng),
+            Self::XavierUniform => uniform((6.0 / (fan_in + fan_out)).sqrt(), rng),
+            Self::LeCunNormal => normal((1.0 / fan_in).sqrt(), rng),
+            Self::LeCunUniform => uniform((3.0 / fan_in).sqrt(), rng),
+            Self::Orthogonal => Ok(orthogonal(out_dim, numel / out_dim, rng)),
+            Self::Constant(_) | Self::Zeros => unreachable!("handled above"),
+        }
+    }
+}
+
+/// `rows x cols` matrix with orthonormal rows (if `rows <= cols`) or columns, from Gram-Schmidt on a Gaussian matrix.
+/// This is the Q of its QR decomposition with a positive diagonal in R, as in torch
+fn orthogonal(rows: usize, cols: usize, rng: &mut impl Rng) -> Vec<FloatDataScalar> {
+    // Orthonormalize the shorter side, stored as `n` vectors of length `len`
+    let (n, len) = (rows.min(cols), rows.max(cols));
+    let gaussian = Normal::new(0.0, 1.0).expect("unit variance is valid");
+    let mut vectors: Vec<Vec<FloatDataScalar>> =
+        (0..n).map(|_| gaussian.sample_iter(&mut *rng).take(len).collect()).collect();
+    for i in 0..n {
+        let (done, rest) = vectors.split_at_mut(i);
+        let v = &mut rest[0];
+        // Modified Gram-Schmidt, run twice so the result stays orthogonal to working precision
+        for _ in 0..2 {
+            for q in done.iter() {
+                let proj: FloatDataScalar = q.iter().zip(v.iter()).map(|(a, b)| a * b).sum();
+                v.iter_mut().zip(q).for_each(|(x, q)| *x -= proj * q);
+            }
+        }
+        let norm = v.iter().map(|x| x * x).sum::<FloatDataScalar>().sqrt();
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+
+    if rows <= cols { vectors.concat() } else { (0..rows).flat_map(|r| vectors.iter().map(move |v| v[r])).collect() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_close;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn std_and_max(values: &[FloatDataScalar]) -> (FloatDataScalar, FloatDataScalar) {
+        #[allow(clippy::cast_precision_loss)]
+        let std = (values.iter().map(|v| v * v).sum::<FloatDataScalar>() / values.len() as FloatDataScalar).sqrt();
+        (std, values.iter().fold(0.0, |acc: FloatDataScalar, v| acc.max(v.abs())))
+    }
+
+    #[test]
+    fn scales_match_fans() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(0);
+        // fan_in = 50 * 4 = 200, fan_out = 100 * 4 = 400
+        let shape = [100, 50, 2, 2];
+        let cases = [
+            (Init::KaimingNormal, (2.0f64 / 200.0).sqrt()),
+            (Init::KaimingUniform, (2.0f64 / 200.0).sqrt()),
+            (Init::XavierNormal, (2.0f64 / 600.0).sqrt()),
+            (Init::XavierUniform, (2.0f64 / 600.0).sqrt()),
+            (Init::LeCunNormal, (1.0f64 / 200.0).sqrt()),
+            (Init::LeCunUniform, (1.0f64 / 200.0).sqrt()),
+        ];
+        for (init, expected_std) in cases {
+            let values = init.sample(&shape, &mut rng)?;
+            assert_eq!(values.len(), 20_000);
+            let (std, max) = std_and_max(&values);
+            assert_close!(std, expected_std, 0.03, 0.0);
+            if matches!(init, Init::KaimingUniform | Init::XavierUniform | Init::LeCunUniform) {
+                // Uniform on [-b, b] has std b / sqrt(3)
+                assert!(max <= expected_std * 3.0f64.sqrt());
+            }
+        }
+        assert_eq!(Init::Constant(0.5).sample(&[2, 3], &mut rng)?, vec![0.5; 6]);
+        assert_eq!(Init::Zeros.sample(&[4], &mut rng)?, vec![0.0; 4]);
+        assert!(Init::KaimingNormal.sample(&[4], &mut rng).is_err());
+        assert!(Init::XavierUniform.sample(&[0, 3], &mut rng)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn orthogonal_is_orthonormal() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(0);
+        for (rows, cols) in [(4, 7), (7, 4), (5, 5)] {
+            let q = Init::Orthogonal.sample(&[rows, cols], &mut rng)?;
+            // Gram matrix of the shorter side is the identity
+            let (n, len) = (rows.min(cols), rows.max(cols));
+            let at = |i: usize, k: usize| if rows <= cols { q[i * cols + k] } else { q[k * cols + i] };
+            for i in 0..n {
+                for j in 0..n {
+                    let dot: FloatDataScalar = (0..len).map(|k| at(i, k) * at(j, k)).sum();
+                    assert_close!(dot, if i == j { 1.0 } else { 0.0 }, 1e-9, 1e-12);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn same_seed_same_weights() -> Result<()> {
+        let draw = |seed| Init::XavierNormal.sample(&[3, 4], &mut StdRng::seed_from_u64(seed)).unwrap();
+        assert_eq!(draw(1), draw(1));
+        assert_ne!(draw(1), draw(2));
+        Ok(())
+    }
+}
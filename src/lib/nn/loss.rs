@@ -1,4 +1,6 @@
-use crate::engine::{DiscreteLabel, Value};
+use crate::engine::{DiscreteLabel, Value, sum};
+use crate::tensor::Tensor;
+use anyhow::{Result, bail};
 use core::f64;
 
 #[must_use]
@@ -14,6 +16,23 @@ pub fn nll_loss_single(label: DiscreteLabel, log_probs: &[Value]) -> Value {
     -1.0 * log_probs.get(label).expect(&msg).clone()
 }
 
+/// Negative log-likelihood of each sample, given `(batch, classes)` log probabilities. Returns a `(batch,)` tensor,
+/// so the caller picks the reduction
+pub fn nll_loss(log_probs: &Tensor, labels: &[DiscreteLabel]) -> Result<Tensor> {
+    let &[batch, _] = log_probs.shape() else {
+        bail!("expected (batch, classes) log probabilities, got shape {:?}", log_probs.shape());
+    };
+    if labels.len() != batch {
+        bail!("{} labels for a batch of {batch}", labels.len());
+    }
+    Ok(log_probs.gather(1, labels)?.reshape(&[batch])? * -1.0)
+}
+
+/// Mean cross-entropy over a batch of `(batch, classes)` logits
+pub fn cross_entropy(logits: &Tensor, labels: &[DiscreteLabel]) -> Result<Value> {
+    Ok(nll_loss(&logits.log_softmax(1)?, labels)?.mean())
+}
+
 #[must_use]
 pub fn log_softmax(logits: &[Value]) -> Vec<Value> {
     let lse = logsumexp(logits);
@@ -40,7 +59,7 @@ pub fn logsumexp(logits: &[Value]) -> Value {
     let offset = max_val(logits);
     let shifted_logits = logits.iter().map(|v| v - offset);
 
-    shifted_logits.map(|v| v.exp()).fold(Value::from(0.0), |acc, val| acc + val).log() + offset
+    sum(&shifted_logits.map(|v| v.exp()).collect::<Vec<_>>()).log() + offset
 }
 
 #[cfg(test)]
@@ -114,6 +133,41 @@ mod tests {
         assert_close!(loss3.data(), 1.0);
     }
 
+    #[test]
+    fn batched_cross_entropy_matches_single() -> Result<()> {
+        let logits = crate::Tensor::from_data(&[1.0, -2.0, 0.5, 3.0, 0.0, -1.0], &[2, 3])?;
+        let labels = [2, 0];
+        let batched = cross_entropy(&logits, &labels)?;
+
+        let rows: Vec<Vec<Value>> = logits.values().chunks(3).map(<[Value]>::to_vec).collect();
+        let single = (cross_entropy_single(2, &rows[0]) + cross_entropy_single(0, &rows[1])) * 0.5;
+        assert_close!(batched.data(), single.data());
+
+        assert!(cross_entropy(&logits, &[0]).is_err());
+        assert!(cross_entropy(&logits, &[0, 3]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn compare_torch_batched_cross_entropy() -> Result<()> {
+        let data = [0.3, -1.2, 2.0, 0.7, 1.1, 0.0, -0.4, 0.9, 2.5, -3.0, 0.2, 0.1];
+        let logits = crate::Tensor::from_data(&data, &[4, 3])?;
+        let loss = cross_entropy(&logits, &[1, 0, 2, 2])?;
+        loss.backward();
+
+        let logits_t = Tensor::from_slice(&data).reshape([4, 3]).set_requires_grad(true);
+        let labels_t = Tensor::from_slice(&[1i64, 0, 2, 2]);
+        let loss_t = logits_t.log_softmax(-1, tch::Kind::Double).nll_loss(&labels_t);
+        loss_t.backward();
+
+        assert_close!(loss.data(), loss_t.double_value(&[]));
+        let grad_t = Vec::<f64>::try_from(logits_t.grad().reshape([-1]))?;
+        for (ours, theirs) in logits.grad().unwrap().iter().zip(grad_t) {
+            assert_close!(*ours, theirs);
+        }
+        Ok(())
+    }
+
     #[test]
     fn losses1() -> Result<()> {
         // Try once with ours
@@ -1,8 +1,40 @@
+pub mod activation;
+pub use activation::Activation;
+
+pub mod checkpoint;
+pub use checkpoint::{Architecture, Checkpoint, Format};
+
+pub mod conv;
+pub use conv::{AdaptiveAvgPool2d, AvgPool2d, Conv1d, Conv1dConfig, Conv2d, Conv2dConfig, MaxPool2d};
+
+pub mod dropout;
+pub use dropout::Dropout;
+
+pub mod embedding;
+pub use embedding::Embedding;
+
+pub mod init;
+pub use init::Init;
+
 pub mod loss;
-pub use loss::cross_entropy_single;
+pub use loss::{cross_entropy, cross_entropy_single};
 
 pub mod models;
-pub use models::{Layer, MLP, Module};
+pub use models::{Layer, MLP, Module, Sequential, StateDict};
+
+pub mod norm;
+pub use norm::{BatchNorm1d, LayerNorm};
+
+pub mod onnx;
+pub use onnx::DenseOp;
+
+pub mod recurrent;
+pub use recurrent::{GRU, GRUCell, LSTM, LSTMCell, RNN, RNNCell, Recurrent, RecurrentCell};
+
+pub mod safetensors;
 
 pub mod trainer;
 pub use trainer::Trainer;
+
+pub mod transformer;
+pub use transformer::{FeedForward, MultiHeadAttention, TransformerEncoderLayer};
@@ -1,8 +1,23 @@
-use crate::{argmax, engine::Value};
-use anyhow::{Result, bail};
+use crate::{
+    argmax,
+    engine::{FloatDataScalar, Value, dot},
+    nn::{Activation, Architecture, Checkpoint, DenseOp, Init, loss::log_softmax},
+    tensor::Tensor,
+};
+use anyhow::{Context, Result, anyhow, bail};
 use itertools::Itertools;
-use rand::SeedableRng;
-use rand_distr::{Distribution, Normal};
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::Path;
+
+/// Parameter data by name, in the order of `Module::named_parameters`
+pub type StateDict = Vec<(String, FloatDataScalar)>;
+
+/// `named` with each name put under `prefix`
+fn prefixed(prefix: impl Display, named: Vec<(String, Value)>) -> impl Iterator<Item = (String, Value)> {
+    named.into_iter().map(move |(name, param)| (format!("{prefix}.{name}"), param))
+}
 
 pub trait Module {
     fn zero_grad(&self) {
@@ -14,20 +29,144 @@ pub trait Module {
 
     fn parameters(&self) -> Vec<Value>;
 
+    /// Parameters in the same order as `parameters`, each with a dotted name saying where it lives, e.g.
+    /// `layers.1.neurons.3.weights.7`. By default they are only numbered
+    fn named_parameters(&self) -> Vec<(String, Value)> {
+        self.parameters().into_iter().enumerate().map(|(idx, param)| (idx.to_string(), param)).collect()
+    }
+
+    /// State that is not trained but is still needed to reproduce the module, such as running statistics, named like
+    /// `named_parameters`. Optimizers never see these. Empty by default
+    fn named_buffers(&self) -> Vec<(String, Value)> {
+        vec![]
+    }
+
+    /// A copy of every parameter's data, under its name from `named_parameters`, followed by the buffers from
+    /// `named_buffers`
+    fn state_dict(&self) -> StateDict {
+        let named = self.named_parameters().into_iter().chain(self.named_buffers());
+        named.map(|(name, value)| (name, value.data())).collect()
+    }
+
+    /// Overwrite the parameters' and buffers' data from `state`, which must hold each name of `named_parameters` and
+    /// `named_buffers` exactly once, in any order. Nothing is written unless the whole of `state` matches
+    fn load_state_dict(&self, state: &[(String, FloatDataScalar)]) -> Result<()> {
+        let named: Vec<_> = self.named_parameters().into_iter().chain(self.named_buffers()).collect();
+        if state.len() != named.len() {
+            bail!("expected {} parameters and buffers, got {}", named.len(), state.len());
+        }
+        let mut by_name: HashMap<&str, FloatDataScalar> = HashMap::with_capacity(state.len());
+        for (name, data) in state {
+            if by_name.insert(name, *data).is_some() {
+                bail!("parameter {name} appears more than once");
+            }
+        }
+        let data = named
+            .iter()
+            .map(|(name, _)| by_name.get(name.as_str()).copied().ok_or_else(|| anyhow!("missing parameter {name}")))
+            .collect::<Result<Vec<_>>>()?;
+        for ((_, param), data) in named.iter().zip(data) {
+            param.borrow_mut().data = data;
+        }
+        Ok(())
+    }
+
+    /// Layer sizes and activations, recorded in checkpoints so the module can be rebuilt from one. `None` for modules
+    /// that can only be loaded into an existing instance
+    fn architecture(&self) -> Option<Architecture> {
+        None
+    }
+
+    /// Write the architecture and state dict to `path`, as JSON if it ends in `.json` and in the binary format
+    /// otherwise
+    fn save(&self, path: &Path) -> Result<()> {
+        Checkpoint { architecture: self.architecture(), state: self.state_dict() }.write(path)
+    }
+
+    /// Overwrite the parameters from a checkpoint written by `save`. Fails, without writing anything, if the
+    /// checkpoint records a different architecture or its state does not match `named_parameters` and `named_buffers`
+    fn load(&self, path: &Path) -> Result<()> {
+        let checkpoint = Checkpoint::read(path)?;
+        if let (Some(saved), Some(ours)) = (&checkpoint.architecture, self.architecture())
+            && *saved != ours
+        {
+            bail!("checkpoint {} has architecture {saved:?}, expected {ours:?}", path.display());
+        }
+        self.load_state_dict(&checkpoint.state).with_context(|| format!("loading checkpoint {}", path.display()))
+    }
+
+    /// The module as dense layers and activations applied in order, for export with `onnx::export`. `None` for
+    /// modules that are not such a stack
+    fn dense_ops(&self) -> Option<Vec<DenseOp>> {
+        None
+    }
+
     fn forward(&self, data: &[Value]) -> Result<Vec<Value>>;
+
+    /// Switch between training and evaluation behavior, for modules such as `Dropout`. Containers pass the mode on to
+    /// their children, and modules that behave the same in both modes ignore it
+    fn set_training(&self, _training: bool) {}
+
+    fn train(&self) {
+        self.set_training(true);
+    }
+
+    fn eval(&self) {
+        self.set_training(false);
+    }
+
+    /// Forward a batch whose leading dimension indexes samples. By default each sample is flattened and passed to
+    /// `forward`, and the outputs are stacked into a `(batch, out_dim)` tensor
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        let Some((&batch, _)) = data.shape().split_first().filter(|_| data.ndim() >= 2) else {
+            bail!("expected a batch with at least 2 dimensions, got shape {:?}", data.shape());
+        };
+        if batch == 0 {
+            bail!("cannot infer the output size of an empty batch");
+        }
+        if data.numel() == 0 {
+            bail!("cannot forward samples without features, got shape {:?}", data.shape());
+        }
+        let mut out = Vec::new();
+        for sample in data.values().chunks_exact(data.numel() / batch) {
+            out.extend(self.forward(sample)?);
+        }
+        let out_dim = out.len() / batch;
+        Tensor::new(out, &[batch, out_dim])
+    }
 }
 
+/// Samples per `forward_tensor` call when scoring
+const SCORE_BATCH_SIZE: usize = 256;
+
 pub trait Classifier: Module {
+    /// Fraction of samples whose largest logit is at their label. Fails for no samples, samples without features, or
+    /// a model that outputs no logits
     fn score(&self, data_labels: &[(Vec<Value>, usize)]) -> Result<f64> {
         let mut n_correct = 0usize;
         let mut n_total = 0usize;
 
-        for (data, label) in data_labels {
-            let logits = self.forward(data)?;
-            let pred = argmax(&logits);
-            n_total += 1;
-            if pred == *label {
-                n_correct += 1;
+        let Some((first, _)) = data_labels.first() else {
+            bail!("cannot score an empty dataset");
+        };
+        if first.is_empty() {
+            bail!("cannot score samples without features");
+        }
+        if let Some(idx) = data_labels.iter().position(|(data, _)| data.len() != first.len()) {
+            bail!("sample {idx} has {} features, expected {}", data_labels[idx].0.len(), first.len());
+        }
+        for chunk in data_labels.chunks(SCORE_BATCH_SIZE) {
+            let data = chunk.iter().flat_map(|(data, _)| data.iter().cloned()).collect();
+            let logits = self.forward_tensor(&Tensor::new(data, &[chunk.len(), first.len()])?)?;
+            let n_logits = logits.numel() / chunk.len();
+            if n_logits == 0 {
+                bail!("model produced no logits");
+            }
+            for (logits, (_, label)) in logits.values().chunks(n_logits).zip(chunk) {
+                n_total += 1;
+                if argmax(logits) == *label {
+                    n_correct += 1;
+                }
             }
         }
 
@@ -35,22 +174,36 @@ pub trait Classifier: Module {
     }
 }
 impl Classifier for MLP {}
+impl Classifier for Sequential {}
 
 #[derive(Debug)]
 pub struct Neuron {
     pub weights: Vec<Value>,
     pub bias: Option<Value>,
-    pub relu: bool,
+    pub activation: Activation,
 }
 impl Neuron {
-    fn new(in_dim: usize, bias: bool, relu: bool) -> Self {
-        // Kaiming init: N(0, sqrt(2/num_inputs))
-        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
-        let sigma = (2.0 / in_dim as f64).sqrt();
-        let gaussian = Normal::new(0.0, sigma).expect("create gaussian");
-        let weights: Vec<Value> = gaussian.sample_iter(&mut rng).take(in_dim).map(Value::from).collect();
-        let bias = if bias { Some(Value::from(gaussian.sample(&mut rng))) } else { None };
-        Self { weights, bias, relu }
+    /// Kaiming normal weights and a zero bias
+    #[must_use]
+    pub fn new(in_dim: usize, bias: bool, activation: Activation, rng: &mut impl Rng) -> Self {
+        let weights = Init::KaimingNormal.sample(&[1, in_dim], rng).expect("a (1, in_dim) shape has 2 dimensions");
+        Self::from_weights(&weights, bias, activation)
+    }
+
+    fn from_weights(weights: &[f64], bias: bool, activation: Activation) -> Self {
+        Self { weights: weights.iter().map(Value::from).collect(), bias: bias.then(|| Value::from(0.0)), activation }
+    }
+
+    /// `w · x + b`, before the activation
+    fn pre_activation(&self, data: &[Value]) -> Result<Value> {
+        if self.weights.len() != data.len() {
+            bail!("shape mismatch")
+        }
+        let result = dot(&self.weights, data);
+        Ok(match &self.bias {
+            Some(b) => result + b.clone(),
+            None => result,
+        })
     }
 
     pub fn normalize(&mut self) {
@@ -65,27 +218,17 @@ impl Neuron {
 
 impl Module for Neuron {
     fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
-        if self.weights.len() != data.len() {
-            bail!("shape mismatch")
-        }
-        let mut result = self
-            .weights
-            .iter()
-            .zip(data)
-            .map(|(wi, xi)| wi.clone() * xi.clone()) // TODO - avoid the cloning, even if lightweight?
-            .fold(Value::from(0.0), |acc, value| acc + value);
-        if let Some(b) = &self.bias {
-            result = result + b.clone();
-        }
-        if self.relu {
-            result = result.relu();
-        }
-        Ok(vec![result])
+        Ok(vec![self.activation.apply(&self.pre_activation(data)?)])
     }
 
     fn parameters(&self) -> Vec<Value> {
         self.weights.iter().chain(self.bias.as_ref()).cloned().collect()
     }
+
+    fn named_parameters(&self) -> Vec<(String, Value)> {
+        let weights = self.weights.iter().enumerate().map(|(idx, weight)| (format!("weights.{idx}"), weight.clone()));
+        weights.chain(self.bias.iter().map(|bias| ("bias".to_string(), bias.clone()))).collect()
+    }
 }
 
 #[derive(Debug)]
@@ -93,9 +236,49 @@ pub struct Layer {
     pub neurons: Vec<Neuron>,
 }
 impl Layer {
+    /// Kaiming normal weights, see `with_init`
     #[must_use]
-    pub fn new(in_dim: usize, out_dim: usize, bias: bool, relu: bool) -> Self {
-        Self { neurons: (0..out_dim).map(|_| Neuron::new(in_dim, bias, relu)).collect() }
+    pub fn new(in_dim: usize, out_dim: usize, bias: bool, activation: Activation, rng: &mut impl Rng) -> Self {
+        Self::with_init(in_dim, out_dim, bias, activation, Init::KaimingNormal, rng)
+            .expect("Kaiming init of a (out_dim, in_dim) matrix cannot fail")
+    }
+
+    /// Weights drawn by `init` as one `(out_dim, in_dim)` matrix, one row per neuron, so the neurons start out
+    /// different from each other. Biases start at zero
+    pub fn with_init(
+        in_dim: usize,
+        out_dim: usize,
+        bias: bool,
+        activation: Activation,
+        init: Init,
+        rng: &mut impl Rng,
+    ) -> Result<Self> {
+        let weights = init.sample(&[out_dim, in_dim], rng)?;
+        let neurons = (0..out_dim)
+            .map(|row| Neuron::from_weights(&weights[row * in_dim..(row + 1) * in_dim], bias, activation))
+            .collect();
+        Ok(Self { neurons })
+    }
+
+    /// Apply each neuron's activation to its pre-activation. Neurons using `Softmax` are normalized together
+    fn activate(&self, pre_activations: &[Value]) -> Vec<Value> {
+        let mut out: Vec<Value> = pre_activations
+            .iter()
+            .zip(&self.neurons)
+            .map(|(value, neuron)| match neuron.activation {
+                Activation::Softmax => value.clone(),
+                activation => activation.apply(value),
+            })
+            .collect();
+        let softmax: Vec<usize> =
+            (0..out.len()).filter(|&idx| self.neurons[idx].activation == Activation::Softmax).collect();
+        if !softmax.is_empty() {
+            let logits: Vec<Value> = softmax.iter().map(|&idx| out[idx].clone()).collect();
+            for (&idx, log_prob) in softmax.iter().zip(log_softmax(&logits)) {
+                out[idx] = log_prob.exp();
+            }
+        }
+        out
     }
 
     pub fn normalize(&mut self) {
@@ -107,12 +290,69 @@ impl Layer {
 
 impl Module for Layer {
     fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
-        self.neurons.iter().map(|neuron| neuron.forward(data)).flatten_ok().collect()
+        let pre_activations: Vec<Value> =
+            self.neurons.iter().map(|neuron| neuron.pre_activation(data)).collect::<Result<_>>()?;
+        Ok(self.activate(&pre_activations))
+    }
+
+    /// Forward a `(batch, in_dim)` tensor as a single matrix product with the weights, rather than one dot product
+    /// per neuron and sample
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        let &[_, in_dim] = data.shape() else {
+            bail!("expected a (batch, in_dim) tensor, got shape {:?}", data.shape());
+        };
+        let out_dim = self.neurons.len();
+        if self.neurons.iter().any(|neuron| neuron.weights.len() != in_dim) {
+            bail!("shape mismatch")
+        }
+
+        // Weights transposed to (in_dim, out_dim), sharing the neurons' values
+        let weights = (0..in_dim * out_dim).map(|idx| self.neurons[idx % out_dim].weights[idx / out_dim].clone());
+        let mut out = data.matmul(&Tensor::new(weights.collect(), &[in_dim, out_dim])?)?;
+        if self.neurons.iter().any(|neuron| neuron.bias.is_some()) {
+            let bias = self.neurons.iter().map(|neuron| neuron.bias.clone().unwrap_or_else(|| Value::from(0.0)));
+            out = out + Tensor::from(bias.collect::<Vec<_>>());
+        }
+        if self.neurons.iter().any(|neuron| neuron.activation != Activation::Identity) {
+            let values = out.values().chunks(out_dim).flat_map(|row| self.activate(row));
+            out = Tensor::new(values.collect(), out.shape())?;
+        }
+        Ok(out)
     }
 
     fn parameters(&self) -> Vec<Value> {
         self.neurons.iter().flat_map(Module::parameters).collect()
     }
+
+    fn named_parameters(&self) -> Vec<(String, Value)> {
+        let neurons = self.neurons.iter().enumerate();
+        neurons.flat_map(|(idx, neuron)| prefixed(format!("neurons.{idx}"), neuron.named_parameters())).collect()
+    }
+
+    /// A `Linear` followed by the activation, unless it is `Identity`. Missing biases are exported as zero. `None` if
+    /// the neurons differ in activation, as an ONNX node applies one activation to the whole layer
+    fn dense_ops(&self) -> Option<Vec<DenseOp>> {
+        let (out_dim, in_dim) = (self.neurons.len(), self.neurons.first()?.weights.len());
+        let activation = self.neurons[0].activation;
+        if self.neurons.iter().any(|neuron| neuron.activation != activation || neuron.weights.len() != in_dim) {
+            return None;
+        }
+        let weight = self.neurons.iter().flat_map(|neuron| neuron.weights.iter().map(Value::data)).collect();
+        let bias = self
+            .neurons
+            .iter()
+            .any(|neuron| neuron.bias.is_some())
+            .then(|| self.neurons.iter().map(|neuron| neuron.bias.as_ref().map_or(0.0, Value::data)).collect());
+        let mut ops = vec![DenseOp::Linear { in_dim, out_dim, weight, bias }];
+        if activation != Activation::Identity {
+            ops.push(DenseOp::Activation(activation));
+        }
+        Some(ops)
+    }
+
+    fn set_training(&self, training: bool) {
+        self.neurons.iter().for_each(|neuron| neuron.set_training(training));
+    }
 }
 
 #[derive(Debug)]
@@ -120,8 +360,32 @@ pub struct MLP {
     layers: Vec<Layer>,
 }
 impl MLP {
+    /// Hidden layers use `activation`. The output layer is left linear, so it produces unconstrained logits for
+    /// `cross_entropy`; see `with_output_activation` to change that. Weights are Kaiming normal, see `with_init`
     #[must_use]
-    pub fn new(in_dim: usize, hidden_dims: &[usize], out_dim: usize, bias: bool) -> Self {
+    pub fn new(
+        in_dim: usize,
+        hidden_dims: &[usize],
+        out_dim: usize,
+        bias: bool,
+        activation: Activation,
+        rng: &mut impl Rng,
+    ) -> Self {
+        Self::with_init(in_dim, hidden_dims, out_dim, bias, activation, Init::KaimingNormal, rng)
+            .expect("Kaiming init of a (out_dim, in_dim) matrix cannot fail")
+    }
+
+    /// Every layer draws its weights from `init`, in order, with the same `rng`, so a seeded `rng` makes the whole
+    /// model reproducible
+    pub fn with_init(
+        in_dim: usize,
+        hidden_dims: &[usize],
+        out_dim: usize,
+        bias: bool,
+        activation: Activation,
+        init: Init,
+        rng: &mut impl Rng,
+    ) -> Result<Self> {
         // API ensures at least one layer
 
         // For all_dims.len() == n, always n-1 layers total
@@ -131,11 +395,19 @@ impl MLP {
         let mut layers: Vec<Layer> = vec![];
 
         for (idx, (d1, d2)) in all_dims.tuple_windows().enumerate() {
-            let relu = idx < n;
-            layers.push(Layer::new(d1, d2, bias, relu));
+            let activation = if idx + 1 < n { activation } else { Activation::Identity };
+            layers.push(Layer::with_init(d1, d2, bias, activation, init, rng)?);
         }
 
-        Self { layers }
+        Ok(Self { layers })
+    }
+
+    #[must_use]
+    pub fn with_output_activation(mut self, activation: Activation) -> Self {
+        if let Some(output) = self.layers.last_mut() {
+            output.neurons.iter_mut().for_each(|neuron| neuron.activation = activation);
+        }
+        self
     }
 
     pub fn normalize(&mut self) {
@@ -143,6 +415,31 @@ impl MLP {
             layer.normalize();
         }
     }
+
+    #[must_use]
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    /// Rebuild an `MLP` saved with `Module::save`, from the architecture in its checkpoint
+    pub fn from_checkpoint(path: &Path) -> Result<Self> {
+        let checkpoint = Checkpoint::read(path)?;
+        let Some(Architecture { in_dim, hidden_dims, out_dim, bias, activations }) = &checkpoint.architecture else {
+            bail!("checkpoint {} does not record an architecture", path.display());
+        };
+        if activations.len() != hidden_dims.len() + 1 {
+            bail!("expected {} activations, got {}", hidden_dims.len() + 1, activations.len());
+        }
+        // Every weight is overwritten below, so the init does not matter
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut mlp =
+            Self::with_init(*in_dim, hidden_dims, *out_dim, *bias, Activation::Identity, Init::Zeros, &mut rng)?;
+        for (layer, &activation) in mlp.layers.iter_mut().zip(activations) {
+            layer.neurons.iter_mut().for_each(|neuron| neuron.activation = activation);
+        }
+        mlp.load_state_dict(&checkpoint.state)?;
+        Ok(mlp)
+    }
 }
 
 impl Module for MLP {
@@ -156,22 +453,133 @@ impl Module for MLP {
         Ok(out)
     }
 
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        let mut out = data.clone();
+        for layer in &self.layers {
+            out = layer.forward_tensor(&out)?;
+        }
+        Ok(out)
+    }
+
     fn parameters(&self) -> Vec<Value> {
         self.layers.iter().flat_map(Module::parameters).collect()
     }
+
+    fn named_parameters(&self) -> Vec<(String, Value)> {
+        let layers = self.layers.iter().enumerate();
+        layers.flat_map(|(idx, layer)| prefixed(format!("layers.{idx}"), layer.named_parameters())).collect()
+    }
+
+    fn set_training(&self, training: bool) {
+        self.layers.iter().for_each(|layer| layer.set_training(training));
+    }
+
+    /// `None` if the neurons of a layer differ in activation or in having a bias, which a checkpoint cannot record
+    fn architecture(&self) -> Option<Architecture> {
+        let (first, last) = (self.layers.first()?, self.layers.last()?);
+        let neurons = || self.layers.iter().flat_map(|layer| &layer.neurons);
+        let bias = neurons().next()?.bias.is_some();
+        if neurons().any(|neuron| neuron.bias.is_some() != bias) {
+            return None;
+        }
+        let activations = self
+            .layers
+            .iter()
+            .map(|layer| {
+                let activation = layer.neurons.first()?.activation;
+                layer.neurons.iter().all(|neuron| neuron.activation == activation).then_some(activation)
+            })
+            .collect::<Option<_>>()?;
+        Some(Architecture {
+            in_dim: first.neurons[0].weights.len(),
+            hidden_dims: self.layers[..self.layers.len() - 1].iter().map(|layer| layer.neurons.len()).collect(),
+            out_dim: last.neurons.len(),
+            bias,
+            activations,
+        })
+    }
+
+    fn dense_ops(&self) -> Option<Vec<DenseOp>> {
+        Some(self.layers.iter().map(Module::dense_ops).collect::<Option<Vec<_>>>()?.concat())
+    }
+}
+
+/// Modules applied in order, each receiving the previous one's output. Built with `add`, e.g.
+/// `Sequential::new().add(Layer::new(4, 8, true, Activation::Identity, &mut rng)).add(Activation::Tanh)`
+#[derive(Default)]
+pub struct Sequential(pub Vec<Box<dyn Module>>);
+
+impl Sequential {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    #[allow(clippy::should_implement_trait)]
+    pub fn add(mut self, module: impl Module + 'static) -> Self {
+        self.0.push(Box::new(module));
+        self
+    }
+}
+
+impl Module for Sequential {
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        let mut out = data.to_vec();
+        for module in &self.0 {
+            out = module.forward(&out)?;
+        }
+        Ok(out)
+    }
+
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        let mut out = data.clone();
+        for module in &self.0 {
+            out = module.forward_tensor(&out)?;
+        }
+        Ok(out)
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        self.0.iter().flat_map(|module| module.parameters()).collect()
+    }
+
+    /// Each module's names under its index, as in torch
+    fn named_parameters(&self) -> Vec<(String, Value)> {
+        self.0.iter().enumerate().flat_map(|(idx, module)| prefixed(idx, module.named_parameters())).collect()
+    }
+
+    fn named_buffers(&self) -> Vec<(String, Value)> {
+        self.0.iter().enumerate().flat_map(|(idx, module)| prefixed(idx, module.named_buffers())).collect()
+    }
+
+    fn set_training(&self, training: bool) {
+        self.0.iter().for_each(|module| module.set_training(training));
+    }
+
+    fn dense_ops(&self) -> Option<Vec<DenseOp>> {
+        Some(self.0.iter().map(|module| module.dense_ops()).collect::<Option<Vec<_>>>()?.concat())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::engine::{norm, sum};
+    use crate::engine::{norm, sum, to_vec};
+    use crate::nn::Dropout;
     use crate::{Optim, optim::SGD};
     use crate::{assert_close, assert_not_close};
     use anyhow::Result;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn rng() -> StdRng {
+        StdRng::seed_from_u64(0)
+    }
 
     #[test]
     fn test_neuron_normalize() {
-        let mut n = Neuron::new(3, false, false);
+        let mut n = Neuron::new(3, false, Activation::Identity, &mut rng());
         assert_not_close!(norm(&n.weights).data(), 1.0);
         n.normalize();
         assert_close!(norm(&n.weights).data(), 1.0);
@@ -179,7 +587,7 @@ mod tests {
 
     #[test]
     fn test_layer_normalize() {
-        let mut layer = Layer::new(3, 2, false, false);
+        let mut layer = Layer::new(3, 2, false, Activation::Identity, &mut rng());
         for n in &layer.neurons {
             assert_not_close!(norm(&n.weights).data(), 1.0);
         }
@@ -191,7 +599,7 @@ mod tests {
 
     #[test]
     fn test_mlp_normalize() {
-        let mut mlp = MLP::new(3, &[3, 2], 2, false);
+        let mut mlp = MLP::new(3, &[3, 2], 2, false, Activation::ReLU, &mut rng());
         for layer in &mlp.layers {
             for n in &layer.neurons {
                 assert_not_close!(norm(&n.weights).data(), 1.0);
@@ -210,7 +618,7 @@ mod tests {
         // Check that a single neuron will move as expected
         // We multiply with a fixed vector, and keep re-normalizing the layer weights to a unit vector
         // The neuron's weights should move towards being aligned
-        let mut n = Neuron::new(3, false, false);
+        let mut n = Neuron::new(3, false, Activation::Identity, &mut rng());
         // n.normalize();
 
         let x = [Value::from(1.0), Value::from(0.0), Value::from(0.0)];
@@ -249,7 +657,7 @@ mod tests {
     fn test_layer_sgd() -> Result<()> {
         // Check that each neuron in a single layer will move as expected
         // Same strategy as used for single neuron case
-        let mut layer = Layer::new(3, 2, false, false);
+        let mut layer = Layer::new(3, 2, false, Activation::Identity, &mut rng());
         layer.normalize();
 
         let x = vec![Value::from(1.0), Value::from(0.0), Value::from(0.0)];
@@ -293,4 +701,234 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn forward_tensor_matches_forward() -> Result<()> {
+        let model = MLP::new(3, &[4], 2, true, Activation::ReLU, &mut rng());
+        let batch = [[0.5, -1.0, 2.0], [1.5, 0.0, -0.3]];
+        let x = Tensor::from_data(batch.as_flattened(), &[2, 3])?;
+
+        let out = model.forward_tensor(&x)?;
+        assert_eq!(out.shape(), &[2, 2]);
+        out.sum().backward();
+        let batched_grads: Vec<_> = model.parameters().iter().map(|p| p.grad().unwrap_or(0.0)).collect();
+
+        model.zero_grad();
+        let mut outputs = vec![];
+        for sample in batch {
+            let sample: Vec<Value> = sample.iter().map(Value::from).collect();
+            outputs.extend(model.forward(&sample)?);
+        }
+        sum(&outputs).backward();
+
+        for (batched, scalar) in out.values().iter().zip(&outputs) {
+            assert_close!(batched.data(), scalar.data());
+        }
+        for (batched, param) in batched_grads.iter().zip(model.parameters()) {
+            assert_close!(*batched, param.grad().unwrap_or(0.0));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn batched_score_matches_per_sample() -> Result<()> {
+        let model = MLP::new(2, &[4], 3, true, Activation::ReLU, &mut rng());
+        #[allow(clippy::cast_precision_loss)]
+        let data_labels: Vec<(Vec<Value>, usize)> = (0..300)
+            .map(|i| (vec![Value::from((i as f64 * 0.1).sin()), Value::from((i as f64 * 0.7).cos())], i % 3))
+            .collect();
+        let mut n_correct = 0;
+        for (data, label) in &data_labels {
+            if argmax(&model.forward(data)?) == *label {
+                n_correct += 1;
+            }
+        }
+        assert_close!(model.score(&data_labels)?, f64::from(n_correct) / 300.0);
+        Ok(())
+    }
+
+    #[test]
+    fn score_rejects_empty_inputs() {
+        let model = MLP::new(2, &[4], 3, true, Activation::ReLU, &mut rng());
+        assert!(model.score(&[]).is_err());
+        assert!(model.score(&[(vec![], 0)]).is_err());
+        // Ragged samples that add up to a whole number of rows
+        let ragged = [(vec![Value::from(1.0); 2], 0), (vec![Value::from(1.0); 1], 0), (vec![Value::from(1.0); 3], 0)];
+        assert!(model.score(&ragged).is_err());
+        let no_features = Sequential::new().add(Activation::ReLU);
+        assert!(no_features.score(&[(vec![], 0), (vec![], 1)]).is_err());
+        // The default `forward_tensor`, which `Neuron` keeps
+        let neuron = Neuron::new(2, true, Activation::Identity, &mut rng());
+        assert!(neuron.forward_tensor(&Tensor::new(vec![], &[2, 0]).unwrap()).is_err());
+        let no_logits = Sequential::new().add(Layer::new(2, 0, true, Activation::Identity, &mut rng()));
+        assert!(no_logits.score(&[(vec![Value::from(1.0), Value::from(2.0)], 0)]).is_err());
+    }
+
+    #[test]
+    fn neurons_start_distinct_and_reproducible() -> Result<()> {
+        let weights = |model: &MLP| model.parameters().iter().map(Value::data).collect::<Vec<_>>();
+        let model = MLP::new(3, &[4], 2, true, Activation::ReLU, &mut rng());
+        let first = &model.layers[0].neurons;
+        assert_ne!(to_vec(&first[0].weights), to_vec(&first[1].weights));
+        assert_eq!(weights(&model), weights(&MLP::new(3, &[4], 2, true, Activation::ReLU, &mut rng())));
+        assert_ne!(
+            weights(&model),
+            weights(&MLP::new(3, &[4], 2, true, Activation::ReLU, &mut StdRng::seed_from_u64(1)))
+        );
+
+        let zeros = MLP::with_init(3, &[4], 2, true, Activation::Tanh, Init::Zeros, &mut rng())?;
+        assert!(weights(&zeros).iter().all(|w| *w == 0.0));
+        let layer = Layer::with_init(5, 3, false, Activation::Identity, Init::Orthogonal, &mut rng())?;
+        assert_close!(dot(&layer.neurons[0].weights, &layer.neurons[1].weights).data(), 0.0, 1e-9, 1e-12);
+        Ok(())
+    }
+
+    #[test]
+    fn mlp_output_layer_is_linear() -> Result<()> {
+        let model = MLP::new(2, &[8, 8], 4, true, Activation::Tanh, &mut rng());
+        let activations: Vec<Activation> = model.layers.iter().map(|layer| layer.neurons[0].activation).collect();
+        assert_eq!(activations, vec![Activation::Tanh, Activation::Tanh, Activation::Identity]);
+
+        let model = MLP::new(2, &[], 4, true, Activation::ReLU, &mut rng()).with_output_activation(Activation::Softmax);
+        let x = Tensor::from_data(&[0.5, -1.0, 2.0, 0.3], &[2, 2])?;
+        let probs = model.forward_tensor(&x)?;
+        probs.sum_dim(1, false)?.data().iter().for_each(|sum| assert_close!(*sum, 1.0));
+        let single = model.forward(&x.values()[2..])?;
+        for (batched, single) in probs.values()[4..].iter().zip(&single) {
+            assert_close!(batched.data(), single.data());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sequential_chains_modules() -> Result<()> {
+        let model = Sequential::new()
+            .add(Layer::new(3, 4, true, Activation::Identity, &mut rng()))
+            .add(Activation::Tanh)
+            .add(Layer::new(4, 2, true, Activation::Identity, &mut rng()));
+        assert_eq!(model.0.len(), 3);
+        assert_eq!(model.parameters().len(), 3 * 4 + 4 + 4 * 2 + 2);
+
+        // Same result as applying each module by hand
+        let x: Vec<Value> = [0.5, -1.0, 2.0].iter().map(Value::from).collect();
+        let hidden = Activation::Tanh.forward(&model.0[0].forward(&x)?)?;
+        let expected = model.0[2].forward(&hidden)?;
+        let out = model.forward(&x)?;
+        for (out, expected) in out.iter().zip(&expected) {
+            assert_close!(out.data(), expected.data());
+        }
+
+        let batch = Tensor::from_data(&[0.5, -1.0, 2.0, 1.5, 0.0, -0.3], &[2, 3])?;
+        let batched = model.forward_tensor(&batch)?;
+        assert_eq!(batched.shape(), &[2, 2]);
+        for (batched, single) in batched.values().iter().zip(&out) {
+            assert_close!(batched.data(), single.data());
+        }
+
+        let empty = Sequential::new();
+        assert_eq!(empty.forward(&x)?, x);
+        assert!(empty.parameters().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn eval_mode_reaches_nested_modules() -> Result<()> {
+        let inner = Sequential::new().add(Dropout::new(0.5, 0)?);
+        let model = Sequential::new().add(Layer::new(3, 4, true, Activation::ReLU, &mut rng())).add(inner);
+        let x = Tensor::from_data(&[0.5, -1.0, 2.0, 1.5, 0.0, -0.3], &[2, 3])?;
+        let deterministic = model.0[0].forward_tensor(&x)?.data();
+
+        model.eval();
+        assert_eq!(model.forward_tensor(&x)?.data(), deterministic);
+        model.train();
+        assert_ne!(model.forward_tensor(&x)?.data(), deterministic);
+        Ok(())
+    }
+
+    #[test]
+    fn sequential_is_a_classifier() -> Result<()> {
+        let model =
+            Sequential::new().add(Layer::new(2, 3, true, Activation::Identity, &mut rng())).add(Activation::ReLU);
+        let mut optim = SGD::new(&model.parameters(), 0.1);
+        let _trainer = crate::nn::Trainer::new(&model, &mut optim, 1, 4);
+
+        let data_labels =
+            vec![(vec![Value::from(1.0), Value::from(-1.0)], 0), (vec![Value::from(0.5), Value::from(2.0)], 2)];
+        let mut n_correct = 0;
+        for (data, label) in &data_labels {
+            if argmax(&model.forward(data)?) == *label {
+                n_correct += 1;
+            }
+        }
+        assert_close!(model.score(&data_labels)?, f64::from(n_correct) / 2.0);
+        Ok(())
+    }
+
+    #[test]
+    fn default_forward_tensor_maps_rows() -> Result<()> {
+        let neuron = Neuron::new(3, true, Activation::Identity, &mut rng());
+        let x = Tensor::from_data(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3])?;
+        let out = neuron.forward_tensor(&x)?;
+        assert_eq!(out.shape(), &[2, 1]);
+        let second: Vec<Value> = x.values()[3..].to_vec();
+        assert_close!(out.values()[1].data(), neuron.forward(&second)?[0].data());
+
+        assert!(neuron.forward_tensor(&Tensor::from_data(&[1.0, 2.0, 3.0], &[3])?).is_err());
+        assert!(
+            Layer::new(3, 2, true, Activation::ReLU, &mut rng())
+                .forward_tensor(&Tensor::from_data(&[1.0; 4], &[2, 2])?)
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn named_parameters_are_hierarchical() {
+        let mlp = MLP::new(3, &[2], 2, true, Activation::ReLU, &mut rng());
+        let named = mlp.named_parameters();
+        assert_eq!(named.len(), mlp.parameters().len());
+        assert_eq!(named[0].0, "layers.0.neurons.0.weights.0");
+        assert_eq!(named[3].0, "layers.0.neurons.0.bias");
+        assert_eq!(named.last().unwrap().0, "layers.1.neurons.1.bias");
+        assert!(named.iter().zip(mlp.parameters()).all(|((_, named), param)| *named == param));
+
+        let sequential =
+            Sequential::new().add(Activation::Tanh).add(Layer::new(2, 1, false, Activation::ReLU, &mut rng()));
+        let names: Vec<String> = sequential.named_parameters().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["1.neurons.0.weights.0", "1.neurons.0.weights.1"]);
+    }
+
+    #[test]
+    fn state_dict_round_trip() -> Result<()> {
+        let source = MLP::new(3, &[4], 2, true, Activation::ReLU, &mut StdRng::seed_from_u64(1));
+        let target = MLP::new(3, &[4], 2, true, Activation::ReLU, &mut StdRng::seed_from_u64(2));
+        let x = [Value::from(0.5), Value::from(-1.0), Value::from(2.0)];
+        assert_ne!(to_vec(&source.forward(&x)?), to_vec(&target.forward(&x)?));
+
+        // Any order is accepted
+        let mut state = source.state_dict();
+        state.reverse();
+        target.load_state_dict(&state)?;
+        assert_eq!(to_vec(&source.forward(&x)?), to_vec(&target.forward(&x)?));
+        Ok(())
+    }
+
+    #[test]
+    fn load_state_dict_validates_names() {
+        let mlp = MLP::new(2, &[], 1, true, Activation::ReLU, &mut rng());
+        let before = mlp.state_dict();
+        let entry = |name: &str| (name.to_string(), 1.0);
+
+        assert!(mlp.load_state_dict(&before[1..]).is_err());
+        let mut renamed = before.clone();
+        renamed[2] = entry("layers.0.neurons.0.weights.9");
+        assert!(mlp.load_state_dict(&renamed).is_err());
+        let mut duplicated = before.clone();
+        duplicated[2] = entry("layers.0.neurons.0.weights.0");
+        assert!(mlp.load_state_dict(&duplicated).is_err());
+
+        // A failed load writes nothing
+        assert_eq!(mlp.state_dict(), before);
+        assert!(MLP::new(2, &[], 1, false, Activation::ReLU, &mut rng()).load_state_dict(&before).is_err());
+    }
 }
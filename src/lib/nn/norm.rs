@@ -0,0 +1,293 @@
+use crate::engine::{FloatDataScalar, Value};
+use crate::nn::models::Module;
+use crate::tensor::Tensor;
+use anyhow::{Result, bail};
+use std::cell::Cell;
+
+/// Added to the variance before taking its square root, as in torch
+const DEFAULT_EPS: FloatDataScalar = 1e-5;
+
+/// Normalizes each sample over its last dimension to zero mean and unit variance, then applies a learnable
+/// per-feature scale and shift
+#[derive(Debug)]
+pub struct LayerNorm {
+    /// `(features,)`, starting at ones
+    pub weight: Tensor,
+    /// `(features,)`, starting at zeros
+    pub bias: Tensor,
+    pub eps: FloatDataScalar,
+}
+
+impl LayerNorm {
+    pub fn new(features: usize) -> Result<Self> {
+        Ok(Self {
+            weight: Tensor::from_data(&vec![1.0; features], &[features])?,
+            bias: Tensor::from_data(&vec![0.0; features], &[features])?,
+            eps: DEFAULT_EPS,
+        })
+    }
+}
+
+impl Module for LayerNorm {
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        Ok(self.forward_tensor(&Tensor::from(data.to_vec()))?.values().to_vec())
+    }
+
+    /// Accepts any shape whose last dimension is `features`
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        let features = self.weight.numel();
+        let Some(last) = data.ndim().checked_sub(1).filter(|&last| data.shape()[last] == features) else {
+            bail!("LayerNorm expects a last dimension of {features}, got shape {:?}", data.shape());
+        };
+        let centered = data - &data.mean_dim(last, true)?;
+        let inv_std = data.var_dim(last, false, true)?.map(|var| (var + self.eps).powf(-0.5));
+        Ok(&(&centered * &inv_std) * &self.weight + &self.bias)
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        self.weight.values().iter().chain(self.bias.values()).cloned().collect()
+    }
+}
+
+/// Normalizes each channel of a `(batch, channels)` or `(batch, channels, length)` input with statistics over the
+/// batch (and length), then applies a learnable per-channel scale and shift.
+///
+/// In training mode the batch statistics are used, and folded into `running_mean` and `running_var` with weight
+/// `momentum`. In evaluation mode the running statistics are used instead, so single samples can be scored. The
+/// running variance is unbiased, the one used for normalizing is not, as in torch
+#[derive(Debug)]
+pub struct BatchNorm1d {
+    /// `(channels,)`, starting at ones
+    pub weight: Tensor,
+    /// `(channels,)`, starting at zeros
+    pub bias: Tensor,
+    /// `(channels,)` buffer, not a parameter
+    pub running_mean: Tensor,
+    /// `(channels,)` buffer, not a parameter
+    pub running_var: Tensor,
+    pub eps: FloatDataScalar,
+    pub momentum: FloatDataScalar,
+    training: Cell<bool>,
+}
+
+impl BatchNorm1d {
+    /// Starts in training mode, with `momentum` 0.1
+    pub fn new(channels: usize) -> Result<Self> {
+        Ok(Self {
+            weight: Tensor::from_data(&vec![1.0; channels], &[channels])?,
+            bias: Tensor::from_data(&vec![0.0; channels], &[channels])?,
+            running_mean: Tensor::from_data(&vec![0.0; channels], &[channels])?,
+            running_var: Tensor::from_data(&vec![1.0; channels], &[channels])?,
+            eps: DEFAULT_EPS,
+            momentum: 0.1,
+            training: Cell::new(true),
+        })
+    }
+
+    #[must_use]
+    pub fn is_training(&self) -> bool {
+        self.training.get()
+    }
+
+    /// Per-channel mean and biased variance of `data`, as `(channels, 1)` tensors, and the number of values behind each
+    fn batch_stats(data: &Tensor) -> Result<(Tensor, Tensor, usize)> {
+        let channels = data.shape()[1];
+        let per_channel = data.transpose(0, 1)?;
+        let per_channel = per_channel.reshape(&[channels, data.numel() / channels.max(1)])?;
+        let count = per_channel.shape()[1];
+        Ok((per_channel.mean_dim(1, true)?, per_channel.var_dim(1, false, true)?, count))
+    }
+}
+
+impl Module for BatchNorm1d {
+    /// Treats `data` as a batch of one `(channels,)` sample, which only works in evaluation mode
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        let input = Tensor::new(data.to_vec(), &[1, data.len()])?;
+        Ok(self.forward_tensor(&input)?.values().to_vec())
+    }
+
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        let channels = self.weight.numel();
+        if !matches!(data.ndim(), 2 | 3) || data.shape()[1] != channels {
+            bail!(
+                "BatchNorm1d expects a (batch, {channels}) or (batch, {channels}, length) input, got {:?}",
+                data.shape()
+            );
+        }
+        // Per-channel tensors broadcast against the input as (channels,) or (channels, 1)
+        let stat_shape: &[usize] = if data.ndim() == 2 { &[channels] } else { &[channels, 1] };
+
+        let (mean, var) = if self.training.get() {
+            let (mean, var, count) = Self::batch_stats(data)?;
+            if count < 2 {
+                bail!(
+                    "BatchNorm1d needs more than one value per channel in training mode, got shape {:?}",
+                    data.shape()
+                );
+            }
+            #[allow(clippy::cast_precision_loss)]
+            let unbiased = count as FloatDataScalar / (count - 1) as FloatDataScalar;
+            let m = self.momentum;
+            for (running, batch) in self.running_mean.values().iter().zip(mean.values()) {
+                running.borrow_mut().data = (1.0 - m).mul_add(running.data(), m * batch.data());
+            }
+            for (running, batch) in self.running_var.values().iter().zip(var.values()) {
+                running.borrow_mut().data = (1.0 - m).mul_add(running.data(), m * batch.data() * unbiased);
+            }
+            (mean, var)
+        } else {
+            // Copies, so that no gradient flows into the buffers
+            (
+                Tensor::from_data(&self.running_mean.data(), &[channels, 1])?,
+                Tensor::from_data(&self.running_var.data(), &[channels, 1])?,
+            )
+        };
+
+        let inv_std = var.map(|var| (var + self.eps).powf(-0.5));
+        let scale = &self.weight.reshape(stat_shape)? * &inv_std.reshape(stat_shape)?;
+        let centered = data - &mean.reshape(stat_shape)?;
+        Ok(&(&centered * &scale) + &self.bias.reshape(stat_shape)?)
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        self.weight.values().iter().chain(self.bias.values()).cloned().collect()
+    }
+
+    /// `running_mean.{channel}` and `running_var.{channel}`, so checkpoints reproduce evaluation mode
+    fn named_buffers(&self) -> Vec<(String, Value)> {
+        let buffers = [("running_mean", &self.running_mean), ("running_var", &self.running_var)];
+        buffers
+            .into_iter()
+            .flat_map(|(name, buffer)| {
+                buffer.values().iter().enumerate().map(move |(idx, value)| (format!("{name}.{idx}"), value.clone()))
+            })
+            .collect()
+    }
+
+    fn set_training(&self, training: bool) {
+        self.training.set(training);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_close;
+
+    #[allow(clippy::cast_precision_loss)]
+    fn input(shape: &[usize]) -> Tensor {
+        let numel: usize = shape.iter().product();
+        let data: Vec<FloatDataScalar> = (0..numel).map(|i| (i as FloatDataScalar * 0.37).sin() * 2.0 + 0.5).collect();
+        Tensor::from_data(&data, shape).unwrap()
+    }
+
+    #[test]
+    fn layer_norm_by_hand() -> Result<()> {
+        let norm = LayerNorm::new(4)?;
+        assert_eq!(norm.parameters().len(), 8);
+        let x = Tensor::from_data(&[1.0, 2.0, 3.0, 4.0, -1.0, -1.0, 1.0, 1.0], &[2, 4])?;
+        let y = norm.forward_tensor(&x)?;
+        // Mean 2.5 and variance 1.25 in the first row, mean 0 and variance 1 in the second
+        let std = (1.25f64 + 1e-5).sqrt();
+        let expected = [-1.5 / std, -0.5 / std, 0.5 / std, 1.5 / std];
+        y.data()[..4].iter().zip(expected).for_each(|(l, r)| assert_close!(*l, r));
+        let unit = 1.0 / (1.0f64 + 1e-5).sqrt();
+        y.data()[4..].iter().zip([-unit, -unit, unit, unit]).for_each(|(l, r)| assert_close!(*l, r));
+
+        let single = norm.forward(&x.values()[4..])?;
+        single.iter().zip(&y.values()[4..]).for_each(|(l, r)| assert_close!(l.data(), r.data()));
+        assert!(norm.forward_tensor(&input(&[2, 3])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn batch_norm_running_stats() -> Result<()> {
+        let norm = BatchNorm1d::new(2)?;
+        let x = Tensor::from_data(&[1.0, 10.0, 3.0, 20.0], &[2, 2])?;
+        let y = norm.forward_tensor(&x)?;
+        // Each channel is (-1, 1) after normalizing by its biased std
+        let unit = 1.0 / (1.0f64 + 1e-5).sqrt();
+        y.data().iter().zip([-unit, -unit, unit, unit]).for_each(|(l, r)| assert_close!(*l, r));
+
+        // Means 2 and 15, unbiased variances 2 and 50
+        let mean = norm.running_mean.data();
+        let var = norm.running_var.data();
+        assert_close!(mean[0], 0.2);
+        assert_close!(mean[1], 1.5);
+        assert_close!(var[0], 0.9 + 0.2);
+        assert_close!(var[1], 0.9 + 5.0);
+
+        // Evaluation mode uses the running statistics and leaves them alone
+        norm.eval();
+        let y = norm.forward(&[Value::from(0.2), Value::from(1.5)])?;
+        y.iter().for_each(|y| assert_close!(y.data(), 0.0));
+        assert_eq!(norm.running_mean.data(), mean);
+        assert!(norm.running_mean.grad().is_none());
+
+        norm.train();
+        assert!(norm.forward(&[Value::from(1.0), Value::from(2.0)]).is_err());
+        assert!(norm.forward_tensor(&input(&[2, 3])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::cast_possible_wrap)]
+    fn compare_torch_layer_norm() {
+        let norm = LayerNorm::new(5).unwrap();
+        // Non-trivial affine parameters
+        norm.weight.values().iter().zip([1.0, 1.1, 0.9, 1.5, 0.5]).for_each(|(w, v)| w.borrow_mut().data = v);
+        norm.bias.values().iter().zip([-0.3, 0.0, 0.2, 0.1, 0.4]).for_each(|(b, v)| b.borrow_mut().data = v);
+        let x = input(&[2, 3, 5]);
+        let y = norm.forward_tensor(&x).unwrap();
+        (&y * &y).sum().backward();
+
+        let to_tch = |t: &Tensor| {
+            let dims: Vec<i64> = t.shape().iter().map(|&d| d as i64).collect();
+            tch::Tensor::from_slice(&t.data()).reshape(dims).set_requires_grad(true)
+        };
+        let (xt, wt, bt) = (to_tch(&x), to_tch(&norm.weight), to_tch(&norm.bias));
+        let yt = xt.layer_norm([5], Some(&wt), Some(&bt), norm.eps, false);
+        (&yt * &yt).sum(None).backward();
+
+        let flat = |t: &tch::Tensor| Vec::<FloatDataScalar>::try_from(t.reshape([-1])).unwrap();
+        y.data().iter().zip(flat(&yt)).for_each(|(l, r)| assert_close!(*l, r));
+        x.grad().unwrap().iter().zip(flat(&xt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+        norm.weight.grad().unwrap().iter().zip(flat(&wt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+        norm.bias.grad().unwrap().iter().zip(flat(&bt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+    }
+
+    #[test]
+    #[allow(clippy::cast_possible_wrap)]
+    fn compare_torch_batch_norm() {
+        for shape in [&[6, 3][..], &[4, 3, 5][..]] {
+            let norm = BatchNorm1d::new(3).unwrap();
+            norm.weight.values().iter().zip([0.5, 1.0, 2.0]).for_each(|(w, v)| w.borrow_mut().data = v);
+            norm.bias.values().iter().zip([0.1, -0.2, 0.3]).for_each(|(b, v)| b.borrow_mut().data = v);
+            let x = input(shape);
+            let y = norm.forward_tensor(&x).unwrap();
+            (&y * &y).sum().backward();
+
+            let dims: Vec<i64> = shape.iter().map(|&d| d as i64).collect();
+            let xt = tch::Tensor::from_slice(&x.data()).reshape(dims).set_requires_grad(true);
+            let wt = tch::Tensor::from_slice(&[0.5, 1.0, 2.0]).set_requires_grad(true);
+            let bt = tch::Tensor::from_slice(&[0.1, -0.2, 0.3]).set_requires_grad(true);
+            let (rm, rv) = (tch::Tensor::from_slice(&[0.0; 3]), tch::Tensor::from_slice(&[1.0; 3]));
+            let yt = xt.batch_norm(Some(&wt), Some(&bt), Some(&rm), Some(&rv), true, 0.1, norm.eps, false);
+            (&yt * &yt).sum(None).backward();
+
+            let flat = |t: &tch::Tensor| Vec::<FloatDataScalar>::try_from(t.reshape([-1])).unwrap();
+            y.data().iter().zip(flat(&yt)).for_each(|(l, r)| assert_close!(*l, r));
+            x.grad().unwrap().iter().zip(flat(&xt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+            norm.weight.grad().unwrap().iter().zip(flat(&wt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+            norm.bias.grad().unwrap().iter().zip(flat(&bt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+            norm.running_mean.data().iter().zip(flat(&rm)).for_each(|(l, r)| assert_close!(*l, r));
+            norm.running_var.data().iter().zip(flat(&rv)).for_each(|(l, r)| assert_close!(*l, r));
+
+            // Evaluation mode against torch's, with the updated running statistics
+            norm.eval();
+            let y = norm.forward_tensor(&x).unwrap();
+            let yt = xt.batch_norm(Some(&wt), Some(&bt), Some(&rm), Some(&rv), false, 0.1, norm.eps, false);
+            y.data().iter().zip(flat(&yt)).for_each(|(l, r)| assert_close!(*l, r));
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//! Export of feed-forward stacks to ONNX, for serving crabgrad-trained models from other runtimes.
+//!
+//! The `ModelProto` is encoded by hand: protobuf is a sequence of fields, each a varint key `field << 3 | wire_type`
+//! followed by a varint, a little-endian `fixed32`, or a length-prefixed byte string for strings and nested messages.
+//! Only the handful of fields of `onnx.proto` needed here are written
+
+use crate::engine::FloatDataScalar;
+use crate::nn::{Activation, Module};
+use anyhow::{Context, Result, anyhow, bail};
+use std::path::Path;
+
+/// IR version 7 and opset 13 are read by every ONNX runtime released since 2020
+const IR_VERSION: u64 = 7;
+const OPSET_VERSION: u64 = 13;
+
+/// `TensorProto.DataType.FLOAT`
+const FLOAT: u64 = 1;
+/// `AttributeProto.AttributeType`
+const ATTRIBUTE_FLOAT: u64 = 1;
+const ATTRIBUTE_INT: u64 = 2;
+
+/// One step of a stack of dense layers and activations, see `Module::dense_ops`
+#[derive(Debug, Clone, PartialEq)]
+pub enum DenseOp {
+    /// `x W^T + b`, with `weight` the row-major `(out_dim, in_dim)` matrix `W`
+    Linear {
+        in_dim: usize,
+        out_dim: usize,
+        weight: Vec<FloatDataScalar>,
+        bias: Option<Vec<FloatDataScalar>>,
+    },
+    Activation(Activation),
+}
+
+/// A protobuf message under construction
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn raw_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push((value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u64, wire_type: u64) {
+        self.raw_varint(field << 3 | wire_type);
+    }
+
+    fn varint(mut self, field: u64, value: u64) -> Self {
+        self.key(field, 0);
+        self.raw_varint(value);
+        self
+    }
+
+    /// Negative values take all 10 bytes, as protobuf encodes `int64` in two's complement
+    fn int(self, field: u64, value: i64) -> Self {
+        self.varint(field, value as u64)
+    }
+
+    fn float(mut self, field: u64, value: f32) -> Self {
+        self.key(field, 5);
+        self.0.extend(value.to_le_bytes());
+        self
+    }
+
+    fn bytes(mut self, field: u64, bytes: &[u8]) -> Self {
+        self.key(field, 2);
+        self.raw_varint(bytes.len() as u64);
+        self.0.extend(bytes);
+        self
+    }
+
+    fn string(self, field: u64, value: &str) -> Self {
+        self.bytes(field, value.as_bytes())
+    }
+
+    fn message(self, field: u64, message: &Self) -> Self {
+        self.bytes(field, &message.0)
+    }
+}
+
+/// `ValueInfoProto` of a `(batch, dim)` float tensor, with the batch size left symbolic
+fn value_info(name: &str, dim: usize) -> Message {
+    let shape = Message::default()
+        .message(1, &Message::default().string(2, "batch"))
+        .message(1, &Message::default().varint(1, dim as u64));
+    let tensor_type = Message::default().varint(1, FLOAT).message(2, &shape);
+    Message::default().string(1, name).message(2, &Message::default().message(1, &tensor_type))
+}
+
+/// `TensorProto` holding `data` as little-endian `float`s
+#[allow(clippy::cast_possible_truncation)]
+fn initializer(name: &str, shape: &[usize], data: &[FloatDataScalar]) -> Message {
+    let raw: Vec<u8> = data.iter().flat_map(|&value| (value as f32).to_le_bytes()).collect();
+    let tensor = shape.iter().fold(Message::default(), |tensor, &dim| tensor.varint(1, dim as u64));
+    tensor.varint(2, FLOAT).string(8, name).bytes(9, &raw)
+}
+
+/// `NodeProto` of the default domain
+fn node(op_type: &str, inputs: &[&str], output: &str, attributes: &[Message]) -> Message {
+    let node = inputs.iter().fold(Message::default(), |node, input| node.string(1, input));
+    let node = node.string(2, output).string(3, &format!("{op_type}_{output}")).string(4, op_type);
+    attributes.iter().fold(node, |node, attribute| node.message(5, attribute))
+}
+
+fn int_attribute(name: &str, value: i64) -> Message {
+    Message::default().string(1, name).int(3, value).varint(20, ATTRIBUTE_INT)
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn float_attribute(name: &str, value: FloatDataScalar) -> Message {
+    Message::default().string(1, name).float(2, value as f32).varint(20, ATTRIBUTE_FLOAT)
+}
+
+/// Serialized `ModelProto` of `ops` applied in order to a `(batch, in_dim)` graph input named `input`, producing a
+/// `(batch, out_dim)` output named `output`.
+///
+/// Each `Linear` becomes a `Gemm` with `transB = 1`. The weight and bias initializers of the `n`th one are named
+/// `{2n}.weight` and `{2n}.bias`, as torch names an `nn.Sequential` alternating `nn.Linear` and activation modules and
+/// as `MLP::to_linear_tensors` does, whatever activations sit in between. Parameters are stored as `float`. `SiLU`
+/// becomes `x * Sigmoid(x)`, and `GELU` is rejected as opset 13 has no operator for it
+pub fn export_ops(ops: &[DenseOp]) -> Result<Vec<u8>> {
+    let mut nodes: Vec<(&str, Vec<String>, Vec<Message>)> = vec![];
+    let mut initializers = vec![];
+    let mut n_linear = 0;
+    let (mut in_dim, mut dim) = (None, None);
+    let mut current = "input".to_string();
+    for (idx, op) in ops.iter().enumerate() {
+        match op {
+            DenseOp::Linear { in_dim: linear_in, out_dim, weight, bias } => {
+                if let Some(dim) = dim
+                    && dim != *linear_in
+                {
+                    bail!("op {idx} expects {linear_in} inputs, but the previous layer has {dim} outputs");
+                }
+                if weight.len() != linear_in * out_dim || bias.as_ref().is_some_and(|bias| bias.len() != *out_dim) {
+                    bail!("op {idx} has parameters that do not match its ({out_dim}, {linear_in}) shape");
+                }
+                in_dim.get_or_insert(*linear_in);
+                dim = Some(*out_dim);
+                let name = 2 * n_linear;
+                n_linear += 1;
+                let mut inputs = vec![current, format!("{name}.weight")];
+                initializers.push(initializer(&inputs[1], &[*out_dim, *linear_in], weight));
+                if let Some(bias) = bias {
+                    inputs.push(format!("{name}.bias"));
+                    initializers.push(initializer(&inputs[2], &[*out_dim], bias));
+                }
+                nodes.push(("Gemm", inputs, vec![int_attribute("transB", 1)]));
+            }
+            DenseOp::Activation(Activation::Identity) => continue,
+            DenseOp::Activation(Activation::GELU) => bail!("GELU cannot be exported to ONNX opset {OPSET_VERSION}"),
+            DenseOp::Activation(Activation::SiLU) => {
+                nodes.push(("Sigmoid", vec![current.clone()], vec![]));
+                nodes.push(("Mul", vec![current, (nodes.len() - 1).to_string()], vec![]));
+            }
+            DenseOp::Activation(Activation::LeakyReLU(slope)) => {
+                nodes.push(("LeakyRelu", vec![current], vec![float_attribute("alpha", *slope)]));
+            }
+            DenseOp::Activation(Activation::Softmax) => {
+                nodes.push(("Softmax", vec![current], vec![int_attribute("axis", -1)]));
+            }
+            DenseOp::Activation(Activation::ReLU) => nodes.push(("Relu", vec![current], vec![])),
+            DenseOp::Activation(Activation::Tanh) => nodes.push(("Tanh", vec![current], vec![])),
+            DenseOp::Activation(Activation::Sigmoid) => nodes.push(("Sigmoid", vec![current], vec![])),
+        }
+        // Intermediate outputs are numbered by node rather than by op, since `SiLU` takes two nodes
+        current = (nodes.len() - 1).to_string();
+    }
+    let (Some(in_dim), Some(out_dim)) = (in_dim, dim) else {
+        bail!("an ONNX export needs at least one dense layer");
+    };
+
+    let last = nodes.len() - 1;
+    let mut graph = Message::default();
+    for (idx, (op_type, inputs, attributes)) in nodes.iter().enumerate() {
+        let output = if idx == last { "output".to_string() } else { idx.to_string() };
+        let inputs: Vec<&str> = inputs.iter().map(String::as_str).collect();
+        graph = graph.message(1, &node(op_type, &inputs, &output, attributes));
+    }
+    graph = graph.string(2, "crabgrad");
+    graph = initializers.iter().fold(graph, |graph, initializer| graph.message(5, initializer));
+    graph = graph.message(11, &value_info("input", in_dim)).message(12, &value_info("output", out_dim));
+
+    let model = Message::default()
+        .varint(1, IR_VERSION)
+        .string(2, "crabgrad")
+        .string(3, env!("CARGO_PKG_VERSION"))
+        .message(7, &graph)
+        .message(8, &Message::default().string(1, "").varint(2, OPSET_VERSION));
+    Ok(model.0)
+}
+
+/// Serialized `ModelProto` of a module that is a stack of dense layers and activations, such as an `MLP` or a
+/// `Sequential` of `Layer`s, `Activation`s and `Dropout`s. See `export_ops`
+pub fn export(module: &dyn Module) -> Result<Vec<u8>> {
+    let ops =
+        module.dense_ops().ok_or_else(|| anyhow!("only stacks of dense layers and activations can be exported"))?;
+    export_ops(&ops)
+}
+
+pub fn write(path: &Path, module: &dyn Module) -> Result<()> {
+    std::fs::write(path, export(module)?).with_context(|| format!("writing {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_close;
+    use crate::nn::models::MLP;
+    use crate::nn::{Dropout, Layer, LayerNorm, Sequential};
+    use crate::tensor::Tensor;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[derive(Debug, Clone, Copy)]
+    enum Field<'a> {
+        Varint(u64),
+        Fixed32(u32),
+        Bytes(&'a [u8]),
+    }
+
+    fn read_varint(bytes: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = bytes.split_first().expect("a complete varint");
+            *bytes = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        value
+    }
+
+    /// Every `(field, value)` of a message, in order
+    fn decode(mut bytes: &[u8]) -> Vec<(u64, Field<'_>)> {
+        let mut fields = vec![];
+        while !bytes.is_empty() {
+            let key = read_varint(&mut bytes);
+            let field = match key & 7 {
+                0 => Field::Varint(read_varint(&mut bytes)),
+                5 => {
+                    let (value, rest) = bytes.split_first_chunk::<4>().expect("4 bytes of fixed32");
+                    bytes = rest;
+                    Field::Fixed32(u32::from_le_bytes(*value))
+                }
+                2 => {
+                    let len = usize::try_from(read_varint(&mut bytes)).unwrap();
+                    let (value, rest) = bytes.split_at(len);
+                    bytes = rest;
+                    Field::Bytes(value)
+                }
+                wire_type => panic!("unexpected wire type {wire_type}"),
+            };
+            fields.push((key >> 3, field));
+        }
+        fields
+    }
+
+    fn repeated<'a>(message: &'a [u8], field: u64) -> impl Iterator<Item = Field<'a>> {
+        decode(message).into_iter().filter(move |(number, _)| *number == field).map(|(_, value)| value)
+    }
+
+    fn messages(message: &[u8], field: u64) -> Vec<&[u8]> {
+        repeated(message, field).map(|value| if let Field::Bytes(bytes) = value { bytes } else { panic!() }).collect()
+    }
+
+    fn strings(message: &[u8], field: u64) -> Vec<&str> {
+        messages(message, field).into_iter().map(|bytes| std::str::from_utf8(bytes).unwrap()).collect()
+    }
+
+    fn varints(message: &[u8], field: u64) -> Vec<u64> {
+        repeated(message, field).map(|value| if let Field::Varint(value) = value { value } else { panic!() }).collect()
+    }
+
+    fn graph(model: &[u8]) -> &[u8] {
+        messages(model, 7)[0]
+    }
+
+    fn op_types(model: &[u8]) -> Vec<&str> {
+        messages(graph(model), 1).into_iter().map(|node| strings(node, 4)[0]).collect()
+    }
+
+    /// Run the decoded graph on a `(batch, in_dim)` input, for the operators `export_ops` emits
+    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+    fn run(model: &[u8], input: &Tensor) -> Tensor {
+        let graph = graph(model);
+        let mut values: std::collections::HashMap<&str, Tensor> = std::collections::HashMap::new();
+        for initializer in messages(graph, 5) {
+            let shape: Vec<usize> = varints(initializer, 1).into_iter().map(|dim| dim as usize).collect();
+            assert_eq!(varints(initializer, 2), [FLOAT]);
+            let raw = messages(initializer, 9)[0];
+            let data: Vec<f64> =
+                raw.chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap()).into()).collect();
+            values.insert(strings(initializer, 8)[0], Tensor::from_data(&data, &shape).unwrap());
+        }
+        values.insert("input", input.clone());
+        for node in messages(graph, 1) {
+            let inputs: Vec<&Tensor> = strings(node, 1).into_iter().map(|name| &values[name]).collect();
+            let attributes: Vec<(&str, Field)> =
+                messages(node, 5).into_iter().map(|attr| (strings(attr, 1)[0], decode(attr)[1].1)).collect();
+            let out = match strings(node, 4)[0] {
+                "Gemm" => {
+                    assert!(matches!(attributes[..], [("transB", Field::Varint(1))]));
+                    let out = inputs[0].matmul(&inputs[1].transpose(0, 1).unwrap()).unwrap();
+                    inputs.get(2).map_or(out.clone(), |bias| &out + *bias)
+                }
+                "Relu" => Activation::ReLU.forward_tensor(inputs[0]).unwrap(),
+                "Tanh" => Activation::Tanh.forward_tensor(inputs[0]).unwrap(),
+                "Sigmoid" => Activation::Sigmoid.forward_tensor(inputs[0]).unwrap(),
+                "Mul" => inputs[0] * inputs[1],
+                "LeakyRelu" => {
+                    let [("alpha", Field::Fixed32(alpha))] = attributes[..] else { panic!("{attributes:?}") };
+                    Activation::LeakyReLU(f32::from_bits(alpha).into()).forward_tensor(inputs[0]).unwrap()
+                }
+                "Softmax" => {
+                    assert!(matches!(attributes[..], [("axis", Field::Varint(axis))] if axis as i64 == -1));
+                    Activation::Softmax.forward_tensor(inputs[0]).unwrap()
+                }
+                op_type => panic!("unexpected op {op_type}"),
+            };
+            values.insert(strings(node, 2)[0], out);
+        }
+        values.remove("output").expect("an output named output")
+    }
+
+    fn inputs() -> Tensor {
+        Tensor::from_data(&[0.3, -1.2, 2.5, 0.0, 1.0, -0.7], &[2, 3]).unwrap()
+    }
+
+    #[test]
+    fn mlp_graph() -> Result<()> {
+        let mlp = MLP::new(3, &[4, 4], 2, true, Activation::ReLU, &mut StdRng::seed_from_u64(0))
+            .with_output_activation(Activation::Softmax);
+        let model = export(&mlp)?;
+
+        assert_eq!(varints(&model, 1), [IR_VERSION]);
+        assert_eq!(strings(&model, 2), ["crabgrad"]);
+        let opset = messages(&model, 8)[0];
+        assert_eq!((strings(opset, 1), varints(opset, 2)), (vec![""], vec![OPSET_VERSION]));
+
+        assert_eq!(op_types(&model), ["Gemm", "Relu", "Gemm", "Relu", "Gemm", "Softmax"]);
+        let nodes = messages(graph(&model), 1);
+        let wiring: Vec<(Vec<&str>, Vec<&str>)> =
+            nodes.iter().map(|node| (strings(node, 1), strings(node, 2))).collect();
+        assert_eq!(wiring[0], (vec!["input", "0.weight", "0.bias"], vec!["0"]));
+        assert_eq!(wiring[1], (vec!["0"], vec!["1"]));
+        assert_eq!(wiring[4], (vec!["3", "4.weight", "4.bias"], vec!["4"]));
+        assert_eq!(wiring[5], (vec!["4"], vec!["output"]));
+
+        // Initializers hold the parameters in torch's `(out_dim, in_dim)` layout
+        let initializers = messages(graph(&model), 5);
+        let names: Vec<&str> = initializers.iter().map(|init| strings(init, 8)[0]).collect();
+        assert_eq!(names, ["0.weight", "0.bias", "2.weight", "2.bias", "4.weight", "4.bias"]);
+        assert_eq!(varints(initializers[2], 1), [4, 4]);
+        assert_eq!(varints(initializers[5], 1), [2]);
+        let data: Vec<f32> = initializers
+            .iter()
+            .flat_map(|init| messages(init, 9)[0].chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())))
+            .collect();
+        #[allow(clippy::cast_possible_truncation)]
+        let expected: Vec<f32> = mlp
+            .layers()
+            .iter()
+            .flat_map(|layer| {
+                let weights = layer.neurons.iter().flat_map(|neuron| &neuron.weights);
+                weights.chain(layer.neurons.iter().filter_map(|neuron| neuron.bias.as_ref()))
+            })
+            .map(|param| param.data() as f32)
+            .collect();
+        assert_eq!(data, expected);
+
+        // `(batch, dim)` graph input and output
+        for (field, name, dim) in [(11, "input", 3), (12, "output", 2)] {
+            let info = messages(graph(&model), field)[0];
+            assert_eq!(strings(info, 1), [name]);
+            let tensor_type = messages(messages(info, 2)[0], 1)[0];
+            assert_eq!(varints(tensor_type, 1), [FLOAT]);
+            let dims = messages(messages(tensor_type, 2)[0], 1);
+            assert_eq!((strings(dims[0], 2), varints(dims[1], 1)), (vec!["batch"], vec![dim]));
+        }
+
+        let (expected, actual) = (mlp.forward_tensor(&inputs())?, run(&model, &inputs()));
+        expected.data().iter().zip(actual.data()).for_each(|(l, r)| assert_close!(*l, r, 1e-5, 1e-5));
+        Ok(())
+    }
+
+    #[test]
+    fn names_match_safetensors() -> Result<()> {
+        // Identity hidden layers emit no activation op, but the names still skip one index per activation module
+        let mlp = MLP::new(3, &[4, 4], 2, true, Activation::Identity, &mut StdRng::seed_from_u64(0));
+        let model = export(&mlp)?;
+        assert_eq!(op_types(&model), ["Gemm", "Gemm", "Gemm"]);
+        let names: Vec<&str> = messages(graph(&model), 5).into_iter().map(|init| strings(init, 8)[0]).collect();
+        let expected: Vec<String> = mlp.to_linear_tensors().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn sequential_graph() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let model = Sequential::new()
+            .add(Layer::new(3, 5, false, Activation::Identity, &mut rng))
+            .add(Activation::LeakyReLU(0.1))
+            .add(Dropout::new(0.5, 0)?)
+            .add(Layer::new(5, 4, true, Activation::SiLU, &mut rng))
+            .add(Layer::new(4, 2, true, Activation::Tanh, &mut rng));
+        model.eval();
+        let bytes = export(&model)?;
+        assert_eq!(op_types(&bytes), ["Gemm", "LeakyRelu", "Gemm", "Sigmoid", "Mul", "Gemm", "Tanh"]);
+        // No bias input for the first layer, and `x * Sigmoid(x)` for SiLU
+        let nodes = messages(graph(&bytes), 1);
+        assert_eq!(strings(nodes[0], 1), ["input", "0.weight"]);
+        assert_eq!(strings(nodes[4], 1), ["2", "3"]);
+
+        let (expected, actual) = (model.forward_tensor(&inputs())?, run(&bytes, &inputs()));
+        expected.data().iter().zip(actual.data()).for_each(|(l, r)| assert_close!(*l, r, 1e-5, 1e-5));
+        Ok(())
+    }
+
+    #[test]
+    fn unsupported_modules() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let gelu = MLP::new(3, &[4], 2, true, Activation::GELU, &mut rng);
+        assert!(export(&gelu).is_err());
+        assert!(export(&Sequential::new().add(LayerNorm::new(3)?)).is_err());
+        assert!(export(&Sequential::new().add(Activation::ReLU)).is_err());
+        let mismatched = Sequential::new().add(Layer::new(3, 4, true, Activation::ReLU, &mut rng)).add(Layer::new(
+            5,
+            2,
+            true,
+            Activation::Identity,
+            &mut rng,
+        ));
+        assert!(export(&mismatched).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,528 @@
+use crate::engine::{FloatDataScalar, Value, dot};
+use crate::nn::models::Module;
+use crate::tensor::Tensor;
+use anyhow::{Result, bail};
+use rand::Rng;
+use rand_distr::{Distribution, Uniform};
+
+/// One step of a recurrent network: combines an input with the previous state into the next state.
+///
+/// The state is a flat vector whose first `hidden_size` values are the hidden state `h`, which is also the output of
+/// the step. The LSTM appends its cell state `c` after it. As a `Module`, a cell takes the input followed by the state
+/// and returns the next state
+pub trait RecurrentCell: Module {
+    fn input_size(&self) -> usize;
+
+    fn hidden_size(&self) -> usize;
+
+    fn state_size(&self) -> usize {
+        self.hidden_size()
+    }
+
+    fn step(&self, input: &[Value], state: &[Value]) -> Result<Vec<Value>>;
+}
+
+/// Weights in U(-1 / sqrt(hidden_size), 1 / sqrt(hidden_size)), as in torch, for `gates` stacked blocks of
+/// `hidden_size` rows
+fn cell_weights(
+    gates: usize,
+    input_size: usize,
+    hidden_size: usize,
+    bias: bool,
+    rng: &mut impl Rng,
+) -> Result<(Tensor, Tensor, Option<Tensor>, Option<Tensor>)> {
+    #[allow(clippy::cast_precision_loss)]
+    let bound = 1.0 / (hidden_size as FloatDataScalar).sqrt();
+    let uniform = Uniform::new_inclusive(-bound, bound)?;
+    let mut sample = |shape: &[usize]| -> Result<Tensor> {
+        let data: Vec<FloatDataScalar> = uniform.sample_iter(&mut *rng).take(shape.iter().product()).collect();
+        Tensor::from_data(&data, shape)
+    };
+    let rows = gates * hidden_size;
+    let weight_ih = sample(&[rows, input_size])?;
+    let weight_hh = sample(&[rows, hidden_size])?;
+    let (bias_ih, bias_hh) = if bias { (Some(sample(&[rows])?), Some(sample(&[rows])?)) } else { (None, None) };
+    Ok((weight_ih, weight_hh, bias_ih, bias_hh))
+}
+
+/// `weight · data + bias`, one value per row of `weight`
+fn affine(weight: &Tensor, bias: Option<&Tensor>, data: &[Value]) -> Result<Vec<Value>> {
+    let cols = weight.shape()[1];
+    if data.len() != cols {
+        bail!("expected {cols} values, got {}", data.len());
+    }
+    let out = weight.values().chunks_exact(cols.max(1)).map(|row| dot(row, data));
+    Ok(match bias {
+        Some(bias) => out.zip(bias.values()).map(|(out, b)| out + b).collect(),
+        None => out.collect(),
+    })
+}
+
+/// Split `data` into the input of a step and the state that follows it
+fn split_step<'a>(cell: &impl RecurrentCell, data: &'a [Value]) -> Result<(&'a [Value], &'a [Value])> {
+    if data.len() != cell.input_size() + cell.state_size() {
+        bail!(
+            "expected {} input values followed by {} state values, got {}",
+            cell.input_size(),
+            cell.state_size(),
+            data.len()
+        );
+    }
+    Ok(data.split_at(cell.input_size()))
+}
+
+/// Parameters shared by all three cells, in torch's order
+fn cell_parameters(
+    weight_ih: &Tensor,
+    weight_hh: &Tensor,
+    bias_ih: Option<&Tensor>,
+    bias_hh: Option<&Tensor>,
+) -> Vec<Value> {
+    [Some(weight_ih), Some(weight_hh), bias_ih, bias_hh]
+        .into_iter()
+        .flatten()
+        .flat_map(Tensor::values)
+        .cloned()
+        .collect()
+}
+
+/// Elman cell, `h' = tanh(W_ih x + b_ih + W_hh h + b_hh)`
+#[derive(Debug)]
+pub struct RNNCell {
+    /// `(hidden_size, input_size)`
+    pub weight_ih: Tensor,
+    /// `(hidden_size, hidden_size)`
+    pub weight_hh: Tensor,
+    /// `(hidden_size,)`
+    pub bias_ih: Option<Tensor>,
+    /// `(hidden_size,)`
+    pub bias_hh: Option<Tensor>,
+}
+
+impl RNNCell {
+    pub fn new(input_size: usize, hidden_size: usize, bias: bool, rng: &mut impl Rng) -> Result<Self> {
+        let (weight_ih, weight_hh, bias_ih, bias_hh) = cell_weights(1, input_size, hidden_size, bias, rng)?;
+        Ok(Self { weight_ih, weight_hh, bias_ih, bias_hh })
+    }
+}
+
+impl RecurrentCell for RNNCell {
+    fn input_size(&self) -> usize {
+        self.weight_ih.shape()[1]
+    }
+
+    fn hidden_size(&self) -> usize {
+        self.weight_hh.shape()[1]
+    }
+
+    fn step(&self, input: &[Value], state: &[Value]) -> Result<Vec<Value>> {
+        let from_input = affine(&self.weight_ih, self.bias_ih.as_ref(), input)?;
+        let from_hidden = affine(&self.weight_hh, self.bias_hh.as_ref(), state)?;
+        Ok(from_input.iter().zip(&from_hidden).map(|(x, h)| (x + h).tanh()).collect())
+    }
+}
+
+impl Module for RNNCell {
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        let (input, state) = split_step(self, data)?;
+        self.step(input, state)
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        cell_parameters(&self.weight_ih, &self.weight_hh, self.bias_ih.as_ref(), self.bias_hh.as_ref())
+    }
+}
+
+/// Gated recurrent unit, with torch's gate order `r, z, n`:
+/// `n = tanh(W_in x + b_in + r * (W_hn h + b_hn))` and `h' = (1 - z) * n + z * h`
+#[derive(Debug)]
+pub struct GRUCell {
+    /// `(3 * hidden_size, input_size)`
+    pub weight_ih: Tensor,
+    /// `(3 * hidden_size, hidden_size)`
+    pub weight_hh: Tensor,
+    /// `(3 * hidden_size,)`
+    pub bias_ih: Option<Tensor>,
+    /// `(3 * hidden_size,)`
+    pub bias_hh: Option<Tensor>,
+}
+
+impl GRUCell {
+    pub fn new(input_size: usize, hidden_size: usize, bias: bool, rng: &mut impl Rng) -> Result<Self> {
+        let (weight_ih, weight_hh, bias_ih, bias_hh) = cell_weights(3, input_size, hidden_size, bias, rng)?;
+        Ok(Self { weight_ih, weight_hh, bias_ih, bias_hh })
+    }
+}
+
+impl RecurrentCell for GRUCell {
+    fn input_size(&self) -> usize {
+        self.weight_ih.shape()[1]
+    }
+
+    fn hidden_size(&self) -> usize {
+        self.weight_hh.shape()[1]
+    }
+
+    fn step(&self, input: &[Value], state: &[Value]) -> Result<Vec<Value>> {
+        let hidden = self.hidden_size();
+        let from_input = affine(&self.weight_ih, self.bias_ih.as_ref(), input)?;
+        let from_hidden = affine(&self.weight_hh, self.bias_hh.as_ref(), state)?;
+        Ok((0..hidden)
+            .map(|j| {
+                let reset = (&from_input[j] + &from_hidden[j]).sigmoid();
+                let update = (&from_input[hidden + j] + &from_hidden[hidden + j]).sigmoid();
+                let candidate = (&from_input[2 * hidden + j] + reset * &from_hidden[2 * hidden + j]).tanh();
+                &candidate + update * (&state[j] - &candidate)
+            })
+            .collect())
+    }
+}
+
+impl Module for GRUCell {
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        let (input, state) = split_step(self, data)?;
+        self.step(input, state)
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        cell_parameters(&self.weight_ih, &self.weight_hh, self.bias_ih.as_ref(), self.bias_hh.as_ref())
+    }
+}
+
+/// Long short-term memory cell, with torch's gate order `i, f, g, o`: `c' = f * c + i * g` and `h' = o * tanh(c')`.
+///
+/// Its state is `h` followed by `c`
+#[derive(Debug)]
+pub struct LSTMCell {
+    /// `(4 * hidden_size, input_size)`
+    pub weight_ih: Tensor,
+    /// `(4 * hidden_size, hidden_size)`
+    pub weight_hh: Tensor,
+    /// `(4 * hidden_size,)`
+    pub bias_ih: Option<Tensor>,
+    /// `(4 * hidden_size,)`
+    pub bias_hh: Option<Tensor>,
+}
+
+impl LSTMCell {
+    pub fn new(input_size: usize, hidden_size: usize, bias: bool, rng: &mut impl Rng) -> Result<Self> {
+        let (weight_ih, weight_hh, bias_ih, bias_hh) = cell_weights(4, input_size, hidden_size, bias, rng)?;
+        Ok(Self { weight_ih, weight_hh, bias_ih, bias_hh })
+    }
+}
+
+impl RecurrentCell for LSTMCell {
+    fn input_size(&self) -> usize {
+        self.weight_ih.shape()[1]
+    }
+
+    fn hidden_size(&self) -> usize {
+        self.weight_hh.shape()[1]
+    }
+
+    fn state_size(&self) -> usize {
+        2 * self.hidden_size()
+    }
+
+    fn step(&self, input: &[Value], state: &[Value]) -> Result<Vec<Value>> {
+        let hidden = self.hidden_size();
+        if state.len() != 2 * hidden {
+            bail!("expected an LSTM state of {} values, got {}", 2 * hidden, state.len());
+        }
+        let (h, c) = state.split_at(hidden);
+        let from_input = affine(&self.weight_ih, self.bias_ih.as_ref(), input)?;
+        let from_hidden = affine(&self.weight_hh, self.bias_hh.as_ref(), h)?;
+        let gates: Vec<Value> = from_input.iter().zip(&from_hidden).map(|(x, h)| x + h).collect();
+
+        let next_c: Vec<Value> = (0..hidden)
+            .map(|j| {
+                let (input_gate, forget_gate) = (gates[j].sigmoid(), gates[hidden + j].sigmoid());
+                forget_gate * &c[j] + input_gate * gates[2 * hidden + j].tanh()
+            })
+            .collect();
+        let next_h = next_c.iter().enumerate().map(|(j, c)| gates[3 * hidden + j].sigmoid() * c.tanh());
+        Ok(next_h.collect::<Vec<_>>().into_iter().chain(next_c).collect())
+    }
+}
+
+impl Module for LSTMCell {
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        let (input, state) = split_step(self, data)?;
+        self.step(input, state)
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        cell_parameters(&self.weight_ih, &self.weight_hh, self.bias_ih.as_ref(), self.bias_hh.as_ref())
+    }
+}
+
+/// Unrolls a cell over a sequence, with backprop through time.
+///
+/// With `truncation` set to `k`, the state is cut from the graph every `k` steps, so gradients only flow back through
+/// at most `k` steps. With a `reverse` cell the network is bidirectional: the second cell reads the sequence from the
+/// end, and each output is the forward hidden state followed by the reverse one
+#[derive(Debug)]
+pub struct Recurrent<C: RecurrentCell> {
+    pub cell: C,
+    pub reverse: Option<C>,
+    pub truncation: Option<usize>,
+}
+
+/// One state per direction, or one output per step
+type States = Vec<Vec<Value>>;
+
+pub type RNN = Recurrent<RNNCell>;
+pub type GRU = Recurrent<GRUCell>;
+pub type LSTM = Recurrent<LSTMCell>;
+
+impl<C: RecurrentCell> Recurrent<C> {
+    #[must_use]
+    pub const fn new(cell: C) -> Self {
+        Self { cell, reverse: None, truncation: None }
+    }
+
+    /// Makes the network bidirectional, with `reverse` reading the sequence backwards
+    pub fn with_reverse(mut self, reverse: C) -> Result<Self> {
+        if (reverse.input_size(), reverse.hidden_size()) != (self.cell.input_size(), self.cell.hidden_size()) {
+            bail!("both directions need the same input and hidden sizes");
+        }
+        self.reverse = Some(reverse);
+        Ok(self)
+    }
+
+    pub fn with_truncation(mut self, steps: usize) -> Result<Self> {
+        if steps == 0 {
+            bail!("truncation length must be positive");
+        }
+        self.truncation = Some(steps);
+        Ok(self)
+    }
+
+    #[must_use]
+    pub const fn is_bidirectional(&self) -> bool {
+        self.reverse.is_some()
+    }
+
+    fn cells(&self) -> impl Iterator<Item = &C> {
+        std::iter::once(&self.cell).chain(&self.reverse)
+    }
+
+    /// Run one cell over `inputs` from `state`, returning the state after every step
+    fn unroll<'a>(
+        &self,
+        cell: &C,
+        inputs: impl Iterator<Item = &'a Vec<Value>>,
+        mut state: Vec<Value>,
+    ) -> Result<States> {
+        let mut states = vec![];
+        for (t, input) in inputs.enumerate() {
+            if self.truncation.is_some_and(|steps| t > 0 && t.is_multiple_of(steps)) {
+                state = state.iter().map(|value| Value::from(value.data())).collect();
+            }
+            state = cell.step(input, &state)?;
+            states.push(state.clone());
+        }
+        Ok(states)
+    }
+
+    /// Hidden states for every step of `inputs`, and the final state of each direction. Each direction starts from
+    /// the matching entry of `initial`, or from zeros
+    pub fn forward_sequence_from(
+        &self,
+        inputs: &[Vec<Value>],
+        initial: Option<&[Vec<Value>]>,
+    ) -> Result<(States, States)> {
+        let directions = self.cells().count();
+        if initial.is_some_and(|initial| initial.len() != directions) {
+            bail!("expected an initial state for each of the {directions} directions");
+        }
+        let initial_state = |direction: usize, cell: &C| {
+            initial.map_or_else(|| vec![Value::from(0.0); cell.state_size()], |initial| initial[direction].clone())
+        };
+
+        let hidden = self.cell.hidden_size();
+        let states = self.unroll(&self.cell, inputs.iter(), initial_state(0, &self.cell))?;
+        let mut outputs: Vec<Vec<Value>> = states.iter().map(|state| state[..hidden].to_vec()).collect();
+        let mut finals = vec![states.last().cloned().unwrap_or_else(|| initial_state(0, &self.cell))];
+
+        if let Some(reverse) = &self.reverse {
+            let states = self.unroll(reverse, inputs.iter().rev(), initial_state(1, reverse))?;
+            for (output, state) in outputs.iter_mut().zip(states.iter().rev()) {
+                output.extend_from_slice(&state[..hidden]);
+            }
+            finals.push(states.last().cloned().unwrap_or_else(|| initial_state(1, reverse)));
+        }
+        Ok((outputs, finals))
+    }
+
+    /// Hidden states for every step of `inputs`, starting from zeros
+    pub fn forward_sequence(&self, inputs: &[Vec<Value>]) -> Result<Vec<Vec<Value>>> {
+        Ok(self.forward_sequence_from(inputs, None)?.0)
+    }
+}
+
+impl<C: RecurrentCell> Module for Recurrent<C> {
+    /// Reads `data` as a sequence of `input_size` chunks, and returns the final hidden state of each direction, like
+    /// torch's `h_n`
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        let input_size = self.cell.input_size();
+        if input_size == 0 || !data.len().is_multiple_of(input_size) {
+            bail!("expected a sequence of {input_size}-value steps, got {} values", data.len());
+        }
+        let inputs: Vec<Vec<Value>> = data.chunks_exact(input_size).map(<[Value]>::to_vec).collect();
+        let (_, finals) = self.forward_sequence_from(&inputs, None)?;
+        Ok(finals.iter().flat_map(|state| state[..self.cell.hidden_size()].to_vec()).collect())
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        self.cells().flat_map(Module::parameters).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_close;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[allow(clippy::cast_precision_loss)]
+    fn sequence(steps: usize, input_size: usize) -> Vec<Vec<Value>> {
+        (0..steps)
+            .map(|t| {
+                (0..input_size).map(|i| Value::from(((t * input_size + i) as FloatDataScalar * 0.7).sin())).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rnn_cell_by_hand() -> Result<()> {
+        let cell = RNNCell::new(2, 1, true, &mut StdRng::seed_from_u64(0))?;
+        assert_eq!(cell.parameters().len(), 2 + 1 + 1 + 1);
+        cell.parameters().iter().zip([0.5, -0.25, 2.0, 0.1, 0.2]).for_each(|(p, v)| p.borrow_mut().data = v);
+        let out = cell.forward(&[Value::from(1.0), Value::from(2.0), Value::from(0.5)])?;
+        assert_close!(out[0].data(), (0.5f64 - 0.5 + 1.0 + 0.1 + 0.2).tanh());
+        assert!(cell.forward(&[Value::from(1.0)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn gru_cell_by_hand() -> Result<()> {
+        let cell = GRUCell::new(1, 1, false, &mut StdRng::seed_from_u64(0))?;
+        // Rows r, z, n of W_ih, then of W_hh
+        cell.parameters().iter().zip([1.0, -1.0, 0.5, 0.3, 0.2, 2.0]).for_each(|(p, v)| p.borrow_mut().data = v);
+        let (x, h) = (0.4, -0.6);
+        let sigmoid = |v: f64| 1.0 / (1.0 + (-v).exp());
+        let r = sigmoid(x + 0.3 * h);
+        let z = sigmoid(-x + 0.2 * h);
+        let n = (0.5f64 * x + r * 2.0 * h).tanh();
+        let out = cell.step(&[Value::from(x)], &[Value::from(h)])?;
+        assert_close!(out[0].data(), (1.0 - z) * n + z * h);
+        Ok(())
+    }
+
+    #[test]
+    fn sequence_shapes_and_bidirectional() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let lstm =
+            LSTM::new(LSTMCell::new(3, 4, true, &mut rng)?).with_reverse(LSTMCell::new(3, 4, true, &mut rng)?)?;
+        assert!(lstm.is_bidirectional());
+        assert_eq!(lstm.parameters().len(), 2 * (16 * 3 + 16 * 4 + 16 + 16));
+
+        let inputs = sequence(5, 3);
+        let (outputs, finals) = lstm.forward_sequence_from(&inputs, None)?;
+        assert_eq!(outputs.len(), 5);
+        assert!(outputs.iter().all(|output| output.len() == 8));
+        assert_eq!(finals.iter().map(Vec::len).collect::<Vec<_>>(), vec![8, 8]);
+        // The forward half of the last output and the reverse half of the first are the final hidden states
+        assert_eq!(outputs[4][..4], finals[0][..4]);
+        assert_eq!(outputs[0][4..], finals[1][..4]);
+
+        let flat: Vec<Value> = inputs.concat();
+        let h_n = lstm.forward(&flat)?;
+        assert_eq!(to_data(&h_n), to_data(&[&finals[0][..4], &finals[1][..4]].concat()));
+        assert!(lstm.forward(&flat[..4]).is_err());
+        Ok(())
+    }
+
+    fn to_data(values: &[Value]) -> Vec<FloatDataScalar> {
+        values.iter().map(Value::data).collect()
+    }
+
+    #[test]
+    fn truncation_cuts_gradients() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let inputs = sequence(6, 2);
+        for (truncation, reached) in [(None, vec![true; 6]), (Some(4), vec![false, false, false, false, true, true])] {
+            let mut gru = GRU::new(GRUCell::new(2, 3, true, &mut rng)?);
+            if let Some(steps) = truncation {
+                gru = gru.with_truncation(steps)?;
+            }
+            inputs.iter().flatten().for_each(Value::zero_grad);
+            let outputs = gru.forward_sequence(&inputs)?;
+            crate::engine::sum(&outputs[5]).backward();
+            let has_grad: Vec<bool> = inputs.iter().map(|input| input[0].grad().is_some()).collect();
+            assert_eq!(has_grad, reached);
+        }
+        assert!(GRU::new(GRUCell::new(2, 3, true, &mut rng)?).with_truncation(0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::cast_possible_wrap)]
+    fn compare_torch_lstm() {
+        let (steps, input_size, hidden) = (4, 3, 2);
+        for bidirectional in [false, true] {
+            let mut rng = StdRng::seed_from_u64(1);
+            let mut lstm = LSTM::new(LSTMCell::new(input_size, hidden, true, &mut rng).unwrap());
+            if bidirectional {
+                lstm = lstm.with_reverse(LSTMCell::new(input_size, hidden, true, &mut rng).unwrap()).unwrap();
+            }
+            let inputs = sequence(steps, input_size);
+            let (outputs, finals) = lstm.forward_sequence_from(&inputs, None).unwrap();
+            let loss = outputs.iter().flatten().fold(Value::from(0.0), |acc, out| acc + out * out);
+            loss.backward();
+
+            let to_tch = |t: &Tensor| {
+                let dims: Vec<i64> = t.shape().iter().map(|&d| d as i64).collect();
+                tch::Tensor::from_slice(&t.data()).reshape(dims).set_requires_grad(true)
+            };
+            let params: Vec<tch::Tensor> = lstm
+                .cells()
+                .flat_map(|cell| {
+                    [&cell.weight_ih, &cell.weight_hh, cell.bias_ih.as_ref().unwrap(), cell.bias_hh.as_ref().unwrap()]
+                })
+                .map(to_tch)
+                .collect();
+            let directions = if bidirectional { 2 } else { 1 };
+            let xt = tch::Tensor::from_slice(&to_data(&inputs.concat()))
+                .reshape([steps as i64, 1, input_size as i64])
+                .set_requires_grad(true);
+            let zeros = tch::Tensor::zeros([directions, 1, hidden as i64], (tch::Kind::Double, tch::Device::Cpu));
+            let (yt, hn, cn) = xt.lstm(
+                &[&zeros, &zeros],
+                &params.iter().collect::<Vec<_>>(),
+                true,
+                1,
+                0.0,
+                true,
+                bidirectional,
+                false,
+            );
+            (&yt * &yt).sum(None).backward();
+
+            let flat = |t: &tch::Tensor| Vec::<FloatDataScalar>::try_from(t.reshape([-1])).unwrap();
+            to_data(&outputs.concat()).iter().zip(flat(&yt)).for_each(|(l, r)| assert_close!(*l, r));
+            let h_n: Vec<Value> = finals.iter().flat_map(|state| state[..hidden].to_vec()).collect();
+            let c_n: Vec<Value> = finals.iter().flat_map(|state| state[hidden..].to_vec()).collect();
+            to_data(&h_n).iter().zip(flat(&hn)).for_each(|(l, r)| assert_close!(*l, r));
+            to_data(&c_n).iter().zip(flat(&cn)).for_each(|(l, r)| assert_close!(*l, r));
+
+            let x_grad: Vec<FloatDataScalar> = inputs.iter().flatten().map(|x| x.grad().unwrap()).collect();
+            x_grad.iter().zip(flat(&xt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+            let param_grad: Vec<FloatDataScalar> = lstm.parameters().iter().map(|p| p.grad().unwrap()).collect();
+            let torch_grad: Vec<FloatDataScalar> = params.iter().flat_map(|p| flat(&p.grad())).collect();
+            param_grad.iter().zip(torch_grad).for_each(|(l, r)| assert_close!(*l, r));
+        }
+    }
+}
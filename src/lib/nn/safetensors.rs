@@ -0,0 +1,356 @@
+//! Reading and writing the safetensors format, for moving weights between crabgrad and PyTorch.
+//!
+//! A file is an 8-byte little-endian header length, a JSON header mapping each tensor name to its dtype, shape and
+//! byte range, and then the raw little-endian data of every tensor back to back
+
+use crate::engine::FloatDataScalar;
+use crate::nn::models::MLP;
+use crate::nn::{Activation, Init};
+use crate::tensor::Tensor;
+use anyhow::{Context, Result, bail};
+use rand::SeedableRng;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Deserialize)]
+struct TensorInfo {
+    dtype: String,
+    shape: Vec<usize>,
+    data_offsets: [usize; 2],
+}
+
+/// Header and tensor data of named tensors, all written as `F64`
+#[must_use]
+pub fn serialize(tensors: &[(String, Tensor)]) -> Vec<u8> {
+    let mut header = serde_json::Map::new();
+    let mut offset = 0;
+    for (name, tensor) in tensors {
+        let end = offset + tensor.numel() * size_of::<FloatDataScalar>();
+        let info = serde_json::json!({ "dtype": "F64", "shape": tensor.shape(), "data_offsets": [offset, end] });
+        header.insert(name.clone(), info);
+        offset = end;
+    }
+    let mut header = serde_json::Value::Object(header).to_string().into_bytes();
+    // Padded with spaces so the data starts 8-byte aligned, as the reference implementation does
+    header.resize(header.len().next_multiple_of(8), b' ');
+
+    let mut out = (header.len() as u64).to_le_bytes().to_vec();
+    out.extend(header);
+    for (_, tensor) in tensors {
+        tensor.data().iter().for_each(|data| out.extend(data.to_le_bytes()));
+    }
+    out
+}
+
+/// Named tensors in the order of their data. `F32` data is widened to `f64`; other dtypes are rejected
+pub fn deserialize(bytes: &[u8]) -> Result<Vec<(String, Tensor)>> {
+    let Some((len, rest)) = bytes.split_first_chunk::<8>() else {
+        bail!("safetensors data is shorter than its header length");
+    };
+    let len = usize::try_from(u64::from_le_bytes(*len))?;
+    if len > rest.len() {
+        bail!("safetensors header of {len} bytes does not fit in {} bytes", rest.len());
+    }
+    let (header, buffer) = rest.split_at(len);
+    let mut header: BTreeMap<String, serde_json::Value> = serde_json::from_slice(header)?;
+    header.remove("__metadata__");
+
+    let mut infos = header
+        .into_iter()
+        .map(|(name, info)| {
+            Ok((name.clone(), TensorInfo::deserialize(info).with_context(|| format!("tensor {name}"))?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    infos.sort_by_key(|(_, info)| info.data_offsets);
+
+    let mut tensors = Vec::with_capacity(infos.len());
+    let mut offset = 0;
+    for (name, TensorInfo { dtype, shape, data_offsets: [begin, end] }) in infos {
+        // Tensors must tile the buffer exactly, in order
+        if begin != offset || end < begin || end > buffer.len() {
+            bail!("tensor {name} has invalid data offsets [{begin}, {end}]");
+        }
+        offset = end;
+        let data = &buffer[begin..end];
+        let size = match dtype.as_str() {
+            "F64" => 8,
+            "F32" => 4,
+            _ => bail!("tensor {name} has unsupported dtype {dtype}, expected F32 or F64"),
+        };
+        if data.len() != shape.iter().product::<usize>() * size {
+            bail!("tensor {name} of shape {shape:?} does not match its {} bytes of {dtype}", data.len());
+        }
+        let values: Vec<FloatDataScalar> = if size == 8 {
+            data.chunks_exact(8).map(|b| f64::from_le_bytes(b.try_into().expect("8 bytes"))).collect()
+        } else {
+            data.chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().expect("4 bytes")).into()).collect()
+        };
+        tensors.push((name, Tensor::from_data(&values, &shape)?));
+    }
+    if offset != buffer.len() {
+        bail!("{} bytes of safetensors data belong to no tensor", buffer.len() - offset);
+    }
+    Ok(tensors)
+}
+
+pub fn read(path: &Path) -> Result<Vec<(String, Tensor)>> {
+    let bytes = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    deserialize(&bytes).with_context(|| format!("loading safetensors {}", path.display()))
+}
+
+pub fn write(path: &Path, tensors: &[(String, Tensor)]) -> Result<()> {
+    std::fs::write(path, serialize(tensors)).with_context(|| format!("writing {}", path.display()))
+}
+
+/// Parts of a dotted name prefix, with the numeric ones parsed
+type PrefixKey<'a> = Vec<(Option<usize>, &'a str)>;
+
+/// `(weight, bias)` of each torch `nn.Linear` in `tensors`, in order of their name prefixes, with numeric parts
+/// compared as numbers so that `10.weight` comes after `2.weight`
+fn linear_tensors(tensors: &[(String, Tensor)]) -> Result<Vec<(&Tensor, Option<&Tensor>)>> {
+    let mut linears: BTreeMap<PrefixKey, (Option<&Tensor>, Option<&Tensor>)> = BTreeMap::new();
+    for (name, tensor) in tensors {
+        let (prefix, suffix) = name.rsplit_once('.').unwrap_or(("", name));
+        let key = if prefix.is_empty() { vec![] } else { prefix.split('.').map(|p| (p.parse().ok(), p)).collect() };
+        let linear = linears.entry(key).or_default();
+        match suffix {
+            "weight" => linear.0 = Some(tensor),
+            "bias" => linear.1 = Some(tensor),
+            _ => bail!("tensor {name} is not the weight or bias of a linear layer"),
+        }
+    }
+    linears
+        .into_iter()
+        .map(|(key, linear)| match linear {
+            (Some(weight), bias) => Ok((weight, bias)),
+            (None, _) => {
+                let prefix: Vec<&str> = key.iter().map(|(_, part)| *part).collect();
+                bail!("linear layer {} has a bias but no weight", prefix.join("."))
+            }
+        })
+        .collect()
+}
+
+impl MLP {
+    /// Weights and biases named as in a torch `nn.Sequential` that alternates `nn.Linear` and activation modules:
+    /// `0.weight`, `0.bias`, `2.weight`, `2.bias`, and so on
+    #[must_use]
+    pub fn to_linear_tensors(&self) -> Vec<(String, Tensor)> {
+        let mut tensors = vec![];
+        for (idx, layer) in self.layers().iter().enumerate() {
+            let in_dim = layer.neurons.first().map_or(0, |neuron| neuron.weights.len());
+            let weights = layer.neurons.iter().flat_map(|neuron| neuron.weights.clone()).collect();
+            let weight = Tensor::new(weights, &[layer.neurons.len(), in_dim]).expect("every neuron has in_dim weights");
+            tensors.push((format!("{}.weight", 2 * idx), weight));
+            let biases: Option<Vec<_>> = layer.neurons.iter().map(|neuron| neuron.bias.clone()).collect();
+            if let Some(biases) = biases {
+                tensors.push((format!("{}.bias", 2 * idx), Tensor::from(biases)));
+            }
+        }
+        tensors
+    }
+
+    /// Copy weights and biases in from torch `nn.Linear` tensors, matched to layers in order of their names. Nothing
+    /// is written unless every shape matches
+    pub fn load_linear_tensors(&self, tensors: &[(String, Tensor)]) -> Result<()> {
+        let linears = linear_tensors(tensors)?;
+        if linears.len() != self.layers().len() {
+            bail!("expected {} linear layers, got {}", self.layers().len(), linears.len());
+        }
+        let mut state = vec![];
+        for (idx, (layer, (weight, bias))) in self.layers().iter().zip(&linears).enumerate() {
+            let (out_dim, in_dim) = (layer.neurons.len(), layer.neurons.first().map_or(0, |n| n.weights.len()));
+            if weight.shape() != [out_dim, in_dim] {
+                bail!("layer {idx} expects a ({out_dim}, {in_dim}) weight, got {:?}", weight.shape());
+            }
+            let has_bias = layer.neurons.iter().any(|neuron| neuron.bias.is_some());
+            match bias {
+                Some(bias) if !has_bias => bail!("layer {idx} has no bias, got one of shape {:?}", bias.shape()),
+                Some(bias) if bias.shape() != [out_dim] => {
+                    bail!("layer {idx} expects a ({out_dim},) bias, got {:?}", bias.shape());
+                }
+                None if has_bias => bail!("layer {idx} expects a bias"),
+                _ => {}
+            }
+            let weights = layer.neurons.iter().flat_map(|neuron| &neuron.weights).zip(weight.data());
+            state.extend(weights.map(|(param, data)| (param.clone(), data)));
+            let biases = layer.neurons.iter().filter_map(|neuron| neuron.bias.as_ref());
+            state.extend(biases.zip(bias.map(|bias| bias.data()).unwrap_or_default()).map(|(p, d)| (p.clone(), d)));
+        }
+        state.into_iter().for_each(|(param, data)| param.borrow_mut().data = data);
+        Ok(())
+    }
+
+    /// Rebuild an `MLP` from torch `nn.Linear` tensors, using `activation` after every hidden layer and leaving the
+    /// output layer linear, as `MLP::new` does. Layer sizes and whether there are biases come from the tensors
+    pub fn from_linear_tensors(tensors: &[(String, Tensor)], activation: Activation) -> Result<Self> {
+        let linears = linear_tensors(tensors)?;
+        let Some(((first, _), (last, _))) = linears.first().zip(linears.last()) else {
+            bail!("no linear layers found");
+        };
+        let bias = linears[0].1.is_some();
+        if linears.iter().any(|(_, b)| b.is_some() != bias) {
+            bail!("either every linear layer or none must have a bias");
+        }
+        if let Some((weight, _)) = linears.iter().find(|(weight, _)| weight.ndim() != 2) {
+            bail!("linear weights must be 2-dimensional, got shape {:?}", weight.shape());
+        }
+        let hidden_dims: Vec<usize> =
+            linears[..linears.len() - 1].iter().map(|(weight, _)| weight.shape()[0]).collect();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mlp =
+            Self::with_init(first.shape()[1], &hidden_dims, last.shape()[0], bias, activation, Init::Zeros, &mut rng)?;
+        mlp.load_linear_tensors(tensors)?;
+        Ok(mlp)
+    }
+
+    /// Write the weights to `path` for a torch `nn.Sequential` of `nn.Linear` and activation modules, see
+    /// `to_linear_tensors`
+    pub fn save_safetensors(&self, path: &Path) -> Result<()> {
+        write(path, &self.to_linear_tensors())
+    }
+
+    /// See `load_linear_tensors`
+    pub fn load_safetensors(&self, path: &Path) -> Result<()> {
+        self.load_linear_tensors(&read(path)?)
+    }
+
+    /// See `from_linear_tensors`
+    pub fn from_safetensors(path: &Path, activation: Activation) -> Result<Self> {
+        Self::from_linear_tensors(&read(path)?, activation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_close;
+    use crate::engine::Value;
+    use crate::nn::Module;
+    use crate::nn::models::Classifier;
+    use rand::rngs::StdRng;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("crabgrad-{}-{name}", std::process::id()))
+    }
+
+    fn inputs() -> Tensor {
+        Tensor::from_data(&[0.3, -1.2, 2.5, 0.0, 1.0, -0.7], &[2, 3]).unwrap()
+    }
+
+    /// A safetensors file as torch would write it, with `F32` data
+    fn f32_file(tensors: &[(&str, &[usize], &[f32])]) -> Vec<u8> {
+        let mut header = serde_json::Map::new();
+        let mut data = vec![];
+        for (name, shape, values) in tensors {
+            let begin = data.len();
+            values.iter().for_each(|value| data.extend(value.to_le_bytes()));
+            header.insert(
+                (*name).to_string(),
+                serde_json::json!({ "dtype": "F32", "shape": shape, "data_offsets": [begin, data.len()] }),
+            );
+        }
+        header.insert("__metadata__".to_string(), serde_json::json!({ "format": "pt" }));
+        let header = serde_json::Value::Object(header).to_string();
+        [&(header.len() as u64).to_le_bytes()[..], header.as_bytes(), &data].concat()
+    }
+
+    #[test]
+    fn mlp_round_trip() -> Result<()> {
+        let mlp = MLP::new(3, &[4], 2, true, Activation::ReLU, &mut StdRng::seed_from_u64(0));
+        let names: Vec<String> = mlp.to_linear_tensors().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["0.weight", "0.bias", "2.weight", "2.bias"]);
+
+        let path = temp_path("mlp.safetensors");
+        mlp.save_safetensors(&path)?;
+        let bytes = std::fs::read(&path)?;
+        let header_len = usize::try_from(u64::from_le_bytes(bytes[..8].try_into()?))?;
+        assert_eq!((8 + header_len) % 8, 0);
+
+        let loaded = MLP::from_safetensors(&path, Activation::ReLU)?;
+        assert_eq!(loaded.forward_tensor(&inputs())?.data(), mlp.forward_tensor(&inputs())?.data());
+        assert_eq!(loaded.state_dict(), mlp.state_dict());
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn reads_torch_style_f32() -> Result<()> {
+        // Names sort numerically, so layer 10 comes after layer 2, and the bias is optional
+        let bytes = f32_file(&[
+            ("layers.10.weight", &[1, 2], &[0.5, -1.0]),
+            ("layers.2.weight", &[2, 3], &[1.0, 0.0, 0.0, 0.0, 1.0, 0.25]),
+        ]);
+        let tensors = deserialize(&bytes)?;
+        let mlp = MLP::from_linear_tensors(&tensors, Activation::Identity)?;
+        assert!(mlp.layers().iter().flat_map(|layer| &layer.neurons).all(|neuron| neuron.bias.is_none()));
+        let out = mlp.forward(&[Value::from(2.0), Value::from(3.0), Value::from(4.0)])?;
+        assert_close!(out[0].data(), 0.5 * 2.0 - (3.0 + 0.25 * 4.0));
+        assert_close!(mlp.score(&[(vec![Value::from(1.0); 3], 0)])?, 1.0);
+        Ok(())
+    }
+
+    #[test]
+    fn mismatches_are_rejected() -> Result<()> {
+        let mlp = MLP::new(3, &[4], 2, true, Activation::ReLU, &mut StdRng::seed_from_u64(0));
+        let before = mlp.state_dict();
+        let other = MLP::new(3, &[5], 2, true, Activation::ReLU, &mut StdRng::seed_from_u64(0));
+        assert!(mlp.load_linear_tensors(&other.to_linear_tensors()).is_err());
+        let no_bias = MLP::new(3, &[4], 2, false, Activation::ReLU, &mut StdRng::seed_from_u64(0));
+        assert!(mlp.load_linear_tensors(&no_bias.to_linear_tensors()).is_err());
+        assert!(mlp.load_linear_tensors(&mlp.to_linear_tensors()[..2]).is_err());
+        assert_eq!(mlp.state_dict(), before);
+
+        let running_mean = deserialize(&f32_file(&[("0.running_mean", &[2], &[0.0, 1.0])]))?;
+        assert!(MLP::from_linear_tensors(&running_mean, Activation::ReLU).is_err());
+        assert!(deserialize(&f32_file(&[("0.weight", &[3], &[0.0, 1.0])])).is_err());
+        let bytes = serialize(&mlp.to_linear_tensors());
+        assert!(deserialize(&bytes[..bytes.len() - 1]).is_err());
+        assert!(deserialize(&bytes[..4]).is_err());
+        let mut f16 = bytes.clone();
+        let dtype = f16.windows(3).position(|window| window == b"F64").expect("an F64 dtype");
+        f16[dtype + 1..dtype + 3].copy_from_slice(b"16");
+        assert!(deserialize(&f16).is_err());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(deserialize(&trailing).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn compare_torch_safetensors() {
+        // torch to crabgrad: a Sequential(Linear, ReLU, Linear) written by tch
+        let kind = (tch::Kind::Float, tch::Device::Cpu);
+        let (w0, b0) = (tch::Tensor::randn([4, 3], kind), tch::Tensor::randn([4], kind));
+        let (w2, b2) = (tch::Tensor::randn([2, 4], kind), tch::Tensor::randn([2], kind));
+        let path = temp_path("from-torch.safetensors");
+        tch::Tensor::write_safetensors(
+            &[("0.weight", &w0), ("0.bias", &b0), ("2.weight", &w2), ("2.bias", &b2)],
+            &path,
+        )
+        .unwrap();
+        let mlp = MLP::from_safetensors(&path, Activation::ReLU).unwrap();
+
+        let x = inputs();
+        let xt = tch::Tensor::from_slice(&x.data()).reshape([2, 3]).to_kind(tch::Kind::Float);
+        let yt = xt.linear(&w0, Some(&b0)).relu().linear(&w2, Some(&b2)).to_kind(tch::Kind::Double);
+        let flat = |t: &tch::Tensor| Vec::<FloatDataScalar>::try_from(t.reshape([-1])).unwrap();
+        let y = mlp.forward_tensor(&x).unwrap();
+        y.data().iter().zip(flat(&yt)).for_each(|(l, r)| assert_close!(*l, r, 1e-5, 1e-5));
+
+        // crabgrad to torch
+        mlp.save_safetensors(&path).unwrap();
+        let tensors: BTreeMap<String, tch::Tensor> =
+            tch::Tensor::read_safetensors(&path).unwrap().into_iter().collect();
+        assert_eq!(tensors["0.weight"].kind(), tch::Kind::Double);
+        let xt = xt.to_kind(tch::Kind::Double);
+        let yt = xt
+            .linear(&tensors["0.weight"], Some(&tensors["0.bias"]))
+            .relu()
+            .linear(&tensors["2.weight"], Some(&tensors["2.bias"]));
+        y.data().iter().zip(flat(&yt)).for_each(|(l, r)| assert_close!(*l, r));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,9 +1,10 @@
 use crate::engine::{DiscreteLabel, FloatDataScalar, Value};
-use crate::nn::cross_entropy_single;
+use crate::nn::loss::nll_loss;
 use crate::nn::models::Classifier;
 use crate::optim::Optim;
+use crate::tensor::Tensor;
 use crate::utils::init_logging;
-use anyhow::Result;
+use anyhow::{Result, bail};
 use indicatif::ProgressBar;
 use itertools::Itertools;
 use rand::SeedableRng;
@@ -32,6 +33,17 @@ impl<'a> Trainer<'a> {
         let mut train_data_labels = to_values(train_data_labels);
         let test_data_labels = test_data_labels.map(to_values);
 
+        // Batches are stacked into (batch, features) tensors, so ragged samples would be silently realigned
+        let Some(n_features) = train_data_labels.first().map(|(data, _)| data.len()) else {
+            bail!("cannot fit on an empty training set");
+        };
+        let splits = [("training", Some(&train_data_labels)), ("test", test_data_labels.as_ref())];
+        for (split, data_labels) in splits.into_iter().filter_map(|(split, data)| Some((split, data?))) {
+            if let Some(idx) = data_labels.iter().position(|(data, _)| data.len() != n_features) {
+                bail!("{split} sample {idx} has {} features, expected {n_features}", data_labels[idx].0.len());
+            }
+        }
+
         init_logging();
 
         let div = train_data_labels.len() as f64 / self.batch_size as f64;
@@ -47,17 +59,21 @@ impl<'a> Trainer<'a> {
         );
 
         let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        self.model.train();
         for e in 0..self.epochs {
             train_data_labels.shuffle(&mut rng);
             log::info!("{:-^20}", format!("Epoch {e}"));
             let bar = ProgressBar::new(batches_per_epoch.try_into()?);
             let batches = train_data_labels.iter().chunks(self.batch_size);
             for chunk in &batches {
-                let mut loss = Value::from(0.0);
-                for (data, label) in chunk {
-                    let logits: Vec<Value> = self.model.forward(data)?;
-                    loss = loss + cross_entropy_single(*label, &logits);
-                }
+                let (data, labels): (Vec<&Vec<Value>>, Vec<DiscreteLabel>) =
+                    chunk.map(|(data, label)| (data, *label)).unzip();
+                let batch =
+                    Tensor::new(data.iter().flat_map(|x| x.iter().cloned()).collect(), &[labels.len(), data[0].len()])?;
+
+                // Summed rather than averaged over the batch
+                let logits = self.model.forward_tensor(&batch)?;
+                let loss = nll_loss(&logits.log_softmax(1)?, &labels)?.sum();
 
                 self.optim.zero_grad();
                 loss.backward();
@@ -66,12 +82,37 @@ impl<'a> Trainer<'a> {
                 bar.inc(1);
             }
 
+            // Score without dropout and similar training-only behavior
+            self.model.eval();
             log::info!("Train acc: {}", self.model.score(&train_data_labels)?);
             if let Some(ref z) = test_data_labels {
                 log::info!("Test acc: {}", self.model.score(z)?);
             }
+            self.model.train();
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nn::{Activation, MLP, Module};
+    use crate::optim::SGD;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn ragged_samples_are_rejected() {
+        let model = MLP::new(4, &[], 2, true, Activation::ReLU, &mut StdRng::seed_from_u64(0));
+        let mut optim = SGD::new(&model.parameters(), 0.1);
+        let mut trainer = Trainer::new(&model, &mut optim, 1, 2);
+        // 3 + 5 features fill two rows of 4, which would otherwise be silently realigned
+        let ragged = vec![(vec![0.0; 4], 0), (vec![0.0; 3], 1), (vec![0.0; 5], 0)];
+        let err = trainer.fit(ragged, None::<Vec<_>>).unwrap_err();
+        assert!(err.to_string().contains("training sample 1"), "{err}");
+        let test = vec![(vec![0.0; 3], 0)];
+        assert!(trainer.fit(vec![(vec![0.0; 4], 0)], Some(test)).is_err());
+        assert!(trainer.fit(Vec::new(), None::<Vec<_>>).is_err());
+    }
+}
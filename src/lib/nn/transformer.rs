@@ -0,0 +1,421 @@
+use crate::engine::{FloatDataScalar, Value};
+use crate::nn::models::Module;
+use crate::nn::{Activation, Init, LayerNorm};
+use crate::tensor::Tensor;
+use anyhow::{Result, bail};
+use rand::Rng;
+
+/// `x Wᵀ + b` over the last dimension of `x`, for weights stored `(out_features, in_features)` as in torch
+fn linear(x: &Tensor, weight: &Tensor, bias: &Tensor) -> Result<Tensor> {
+    let (&[out_features, in_features], Some(&last)) = (weight.shape(), x.shape().last()) else {
+        bail!("linear expects a 2-dimensional weight and an input with at least one dimension");
+    };
+    if last != in_features {
+        bail!("linear expects a last dimension of {in_features}, got shape {:?}", x.shape());
+    }
+    let rows = x.reshape(&[x.numel() / in_features.max(1), in_features])?;
+    let out = &rows.matmul(&weight.transpose(0, 1)?)? + bias;
+    out.reshape(&[&x.shape()[..x.ndim() - 1], &[out_features]].concat())
+}
+
+/// `(seq, features)` inputs are treated as a batch of one. Returns the batched input, and whether it was unbatched
+fn as_batch(data: &Tensor, features: usize, name: &str) -> Result<(Tensor, bool)> {
+    match data.shape() {
+        &[_, _, last] if last == features => Ok((data.clone(), false)),
+        &[_, last] if last == features => Ok((data.unsqueeze(0)?, true)),
+        shape => bail!("{name} expects a (batch, seq, {features}) or (seq, {features}) input, got {shape:?}"),
+    }
+}
+
+fn unbatch(data: Tensor, unbatched: bool) -> Result<Tensor> {
+    if unbatched { data.reshape(&data.shape()[1..]) } else { Ok(data) }
+}
+
+/// Treats a flat slice as a `(seq, features)` sequence
+fn forward_flat(module: &impl Module, data: &[Value], features: usize) -> Result<Vec<Value>> {
+    if features == 0 || data.is_empty() || !data.len().is_multiple_of(features) {
+        bail!("expected a sequence of {features}-value steps, got {} values", data.len());
+    }
+    let input = Tensor::new(data.to_vec(), &[data.len() / features, features])?;
+    Ok(module.forward_tensor(&input)?.values().to_vec())
+}
+
+/// Scaled dot-product attention with `num_heads` heads over `(batch, seq, embed_dim)` inputs.
+///
+/// Queries, keys and values are projected, split into heads of `embed_dim / num_heads` features, attended separately,
+/// and the heads are concatenated and projected again. With `causal` set, each position only attends to itself and
+/// earlier positions. As a `Module` it is self-attention
+#[derive(Debug)]
+pub struct MultiHeadAttention {
+    /// `(3 * embed_dim, embed_dim)`, the query, key and value projections stacked
+    pub in_proj_weight: Tensor,
+    /// `(3 * embed_dim,)`
+    pub in_proj_bias: Tensor,
+    /// `(embed_dim, embed_dim)`
+    pub out_proj_weight: Tensor,
+    /// `(embed_dim,)`
+    pub out_proj_bias: Tensor,
+    pub num_heads: usize,
+    pub causal: bool,
+}
+
+impl MultiHeadAttention {
+    /// Xavier uniform projections and zero biases, as in torch
+    pub fn new(embed_dim: usize, num_heads: usize, rng: &mut impl Rng) -> Result<Self> {
+        if num_heads == 0 || !embed_dim.is_multiple_of(num_heads) {
+            bail!("embed_dim {embed_dim} must be divisible by num_heads {num_heads}");
+        }
+        let in_proj = Init::XavierUniform.sample(&[3 * embed_dim, embed_dim], rng)?;
+        let out_proj = Init::XavierUniform.sample(&[embed_dim, embed_dim], rng)?;
+        Ok(Self {
+            in_proj_weight: Tensor::from_data(&in_proj, &[3 * embed_dim, embed_dim])?,
+            in_proj_bias: Tensor::from_data(&vec![0.0; 3 * embed_dim], &[3 * embed_dim])?,
+            out_proj_weight: Tensor::from_data(&out_proj, &[embed_dim, embed_dim])?,
+            out_proj_bias: Tensor::from_data(&vec![0.0; embed_dim], &[embed_dim])?,
+            num_heads,
+            causal: false,
+        })
+    }
+
+    #[must_use]
+    pub const fn with_causal_mask(mut self) -> Self {
+        self.causal = true;
+        self
+    }
+
+    #[must_use]
+    pub fn embed_dim(&self) -> usize {
+        self.out_proj_weight.shape()[0]
+    }
+
+    /// `(batch, seq, embed_dim)` to `(batch * num_heads, seq, head_dim)`
+    fn split_heads(&self, x: &Tensor) -> Result<Tensor> {
+        let &[batch, seq, embed_dim] = x.shape() else { unreachable!("inputs are batched") };
+        let head_dim = embed_dim / self.num_heads;
+        x.reshape(&[batch, seq, self.num_heads, head_dim])?.permute(&[0, 2, 1, 3])?.reshape(&[
+            batch * self.num_heads,
+            seq,
+            head_dim,
+        ])
+    }
+
+    /// Attend from `query` to `key` and `value`, all `(batch, seq, embed_dim)` or `(seq, embed_dim)`; keys and values
+    /// share their length, which may differ from that of the queries.
+    ///
+    /// `key_padding_mask` holds one flag per key, laid out `(batch, key_len)`, and keys flagged `true` are ignored,
+    /// as in torch. A query with every key masked out gets NaN outputs
+    pub fn attend(
+        &self,
+        query: &Tensor,
+        key: &Tensor,
+        value: &Tensor,
+        key_padding_mask: Option<&[bool]>,
+    ) -> Result<Tensor> {
+        let embed_dim = self.embed_dim();
+        let (query, unbatched) = as_batch(query, embed_dim, "MultiHeadAttention")?;
+        let (key, _) = as_batch(key, embed_dim, "MultiHeadAttention")?;
+        let (value, _) = as_batch(value, embed_dim, "MultiHeadAttention")?;
+        let (batch, query_len, key_len) = (query.shape()[0], query.shape()[1], key.shape()[1]);
+        if key.shape()[0] != batch || value.shape()[..2] != key.shape()[..2] {
+            bail!(
+                "queries, keys and values need the same batch size, and keys and values the same length, got shapes \
+                 {:?}, {:?} and {:?}",
+                query.shape(),
+                key.shape(),
+                value.shape()
+            );
+        }
+        if let Some(mask) = key_padding_mask
+            && mask.len() != batch * key_len
+        {
+            bail!("expected a key padding mask of {} flags, got {}", batch * key_len, mask.len());
+        }
+
+        // The stacked projection, split into its query, key and value parts
+        let weights = self.in_proj_weight.split(embed_dim, 0)?;
+        let biases = self.in_proj_bias.split(embed_dim, 0)?;
+        let q = self.split_heads(&linear(&query, &weights[0], &biases[0])?)?;
+        let k = self.split_heads(&linear(&key, &weights[1], &biases[1])?)?;
+        let v = self.split_heads(&linear(&value, &weights[2], &biases[2])?)?;
+
+        #[allow(clippy::cast_precision_loss)]
+        let scale = 1.0 / ((embed_dim / self.num_heads) as FloatDataScalar).sqrt();
+        let scores = &q.bmm(&k.transpose(1, 2)?)? * scale;
+
+        // Masked scores are replaced by constant -inf, so they get zero weight and pass no gradient
+        let per_batch = self.num_heads * query_len * key_len;
+        let masked = |idx: usize| {
+            let (b, q, k) = (idx / per_batch, idx / key_len % query_len, idx % key_len);
+            (self.causal && k > q) || key_padding_mask.is_some_and(|mask| mask[b * key_len + k])
+        };
+        let scores = Tensor::new(
+            scores
+                .values()
+                .iter()
+                .enumerate()
+                .map(
+                    |(idx, score)| if masked(idx) { Value::from(FloatDataScalar::NEG_INFINITY) } else { score.clone() },
+                )
+                .collect(),
+            scores.shape(),
+        )?;
+
+        let heads = scores.softmax(2)?.bmm(&v)?;
+        let head_dim = embed_dim / self.num_heads;
+        let heads = heads.reshape(&[batch, self.num_heads, query_len, head_dim])?.permute(&[0, 2, 1, 3])?;
+        let out = linear(&heads.reshape(&[batch, query_len, embed_dim])?, &self.out_proj_weight, &self.out_proj_bias)?;
+        unbatch(out, unbatched)
+    }
+}
+
+impl Module for MultiHeadAttention {
+    /// A flat `(seq, embed_dim)` sequence
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        forward_flat(self, data, self.embed_dim())
+    }
+
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        self.attend(data, data, data, None)
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        [&self.in_proj_weight, &self.in_proj_bias, &self.out_proj_weight, &self.out_proj_bias]
+            .into_iter()
+            .flat_map(Tensor::values)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Position-wise feed-forward block, `linear2(activation(linear1(x)))`, applied to each position of a sequence
+/// independently
+#[derive(Debug)]
+pub struct FeedForward {
+    /// `(hidden_dim, embed_dim)`
+    pub weight1: Tensor,
+    /// `(hidden_dim,)`
+    pub bias1: Tensor,
+    /// `(embed_dim, hidden_dim)`
+    pub weight2: Tensor,
+    /// `(embed_dim,)`
+    pub bias2: Tensor,
+    pub activation: Activation,
+}
+
+impl FeedForward {
+    /// Kaiming normal weights, zero biases and a ReLU in between
+    pub fn new(embed_dim: usize, hidden_dim: usize, rng: &mut impl Rng) -> Result<Self> {
+        let weight1 = Init::KaimingNormal.sample(&[hidden_dim, embed_dim], rng)?;
+        let weight2 = Init::KaimingNormal.sample(&[embed_dim, hidden_dim], rng)?;
+        Ok(Self {
+            weight1: Tensor::from_data(&weight1, &[hidden_dim, embed_dim])?,
+            bias1: Tensor::from_data(&vec![0.0; hidden_dim], &[hidden_dim])?,
+            weight2: Tensor::from_data(&weight2, &[embed_dim, hidden_dim])?,
+            bias2: Tensor::from_data(&vec![0.0; embed_dim], &[embed_dim])?,
+            activation: Activation::ReLU,
+        })
+    }
+
+    #[must_use]
+    pub const fn with_activation(mut self, activation: Activation) -> Self {
+        self.activation = activation;
+        self
+    }
+}
+
+impl Module for FeedForward {
+    /// A single position
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        Ok(self.forward_tensor(&Tensor::from(data.to_vec()))?.values().to_vec())
+    }
+
+    /// Accepts any shape whose last dimension is `embed_dim`
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        let hidden = self.activation.forward_tensor(&linear(data, &self.weight1, &self.bias1)?)?;
+        linear(&hidden, &self.weight2, &self.bias2)
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        [&self.weight1, &self.bias1, &self.weight2, &self.bias2].into_iter().flat_map(Tensor::values).cloned().collect()
+    }
+}
+
+/// Self-attention followed by a feed-forward block, each wrapped in a residual connection and a `LayerNorm`.
+///
+/// By default the norm comes after each residual sum, as in the original transformer and torch's default. With
+/// `norm_first` it is applied to the input of each block instead, which tends to train more stably in deep stacks
+#[derive(Debug)]
+pub struct TransformerEncoderLayer {
+    pub self_attn: MultiHeadAttention,
+    pub feed_forward: FeedForward,
+    pub norm1: LayerNorm,
+    pub norm2: LayerNorm,
+    pub norm_first: bool,
+}
+
+impl TransformerEncoderLayer {
+    pub fn new(embed_dim: usize, num_heads: usize, hidden_dim: usize, rng: &mut impl Rng) -> Result<Self> {
+        Ok(Self {
+            self_attn: MultiHeadAttention::new(embed_dim, num_heads, rng)?,
+            feed_forward: FeedForward::new(embed_dim, hidden_dim, rng)?,
+            norm1: LayerNorm::new(embed_dim)?,
+            norm2: LayerNorm::new(embed_dim)?,
+            norm_first: false,
+        })
+    }
+
+    /// Each position only attends to itself and earlier ones, as in a language model
+    #[must_use]
+    pub const fn with_causal_mask(mut self) -> Self {
+        self.self_attn.causal = true;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_norm_first(mut self) -> Self {
+        self.norm_first = true;
+        self
+    }
+
+    /// `(batch, seq, embed_dim)` or `(seq, embed_dim)` to the same shape, ignoring keys flagged in
+    /// `key_padding_mask` as in `MultiHeadAttention::attend`
+    pub fn forward_masked(&self, data: &Tensor, key_padding_mask: Option<&[bool]>) -> Result<Tensor> {
+        let attend = |x: &Tensor| self.self_attn.attend(x, x, x, key_padding_mask);
+        if self.norm_first {
+            let x = data + &attend(&self.norm1.forward_tensor(data)?)?;
+            Ok(&x + &self.feed_forward.forward_tensor(&self.norm2.forward_tensor(&x)?)?)
+        } else {
+            let x = self.norm1.forward_tensor(&(data + &attend(data)?))?;
+            self.norm2.forward_tensor(&(&x + &self.feed_forward.forward_tensor(&x)?))
+        }
+    }
+}
+
+impl Module for TransformerEncoderLayer {
+    /// A flat `(seq, embed_dim)` sequence
+    fn forward(&self, data: &[Value]) -> Result<Vec<Value>> {
+        forward_flat(self, data, self.self_attn.embed_dim())
+    }
+
+    fn forward_tensor(&self, data: &Tensor) -> Result<Tensor> {
+        self.forward_masked(data, None)
+    }
+
+    fn parameters(&self) -> Vec<Value> {
+        let modules: [&dyn Module; 4] = [&self.self_attn, &self.feed_forward, &self.norm1, &self.norm2];
+        modules.into_iter().flat_map(Module::parameters).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_close;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[allow(clippy::cast_precision_loss)]
+    fn input(shape: &[usize]) -> Tensor {
+        let numel: usize = shape.iter().product();
+        let data: Vec<FloatDataScalar> = (0..numel).map(|i| (i as FloatDataScalar * 0.61).sin()).collect();
+        Tensor::from_data(&data, shape).unwrap()
+    }
+
+    #[test]
+    fn single_head_by_hand() -> Result<()> {
+        let attn = MultiHeadAttention::new(2, 1, &mut StdRng::seed_from_u64(0))?;
+        assert!(MultiHeadAttention::new(3, 2, &mut StdRng::seed_from_u64(0)).is_err());
+        // Identity projections, so the output is softmax(x xᵀ / sqrt(2)) x
+        let identity = [1.0, 0.0, 0.0, 1.0];
+        let in_proj: Vec<f64> = identity.iter().cycle().take(12).copied().collect();
+        attn.in_proj_weight.values().iter().zip(in_proj).for_each(|(w, v)| w.borrow_mut().data = v);
+        attn.out_proj_weight.values().iter().zip(identity).for_each(|(w, v)| w.borrow_mut().data = v);
+
+        let x = [[1.0, 0.0], [0.0, 2.0]];
+        let out = attn.forward(&x.iter().flatten().map(Value::from).collect::<Vec<_>>())?;
+        for (q, row) in x.iter().enumerate() {
+            let scores: Vec<f64> = x.iter().map(|k| (row[0] * k[0] + row[1] * k[1]) / 2f64.sqrt()).collect();
+            let total: f64 = scores.iter().map(|s| s.exp()).sum();
+            for dim in 0..2 {
+                let expected: f64 = scores.iter().zip(&x).map(|(s, v)| s.exp() / total * v[dim]).sum();
+                assert_close!(out[q * 2 + dim].data(), expected);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn masks_hide_keys() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let layer = TransformerEncoderLayer::new(4, 2, 8, &mut rng)?.with_causal_mask();
+        assert_eq!(layer.parameters().len(), (12 * 4 + 12 + 16 + 4) + (32 + 8 + 32 + 4) + 8 + 8);
+        let x = input(&[2, 5, 4]);
+        let out = layer.forward_tensor(&x)?;
+        assert_eq!(out.shape(), &[2, 5, 4]);
+
+        // Changing the last position leaves all earlier outputs alone
+        let mut changed = x.data();
+        changed[16..20].iter_mut().for_each(|v| *v += 1.0);
+        let changed_out = layer.forward_tensor(&Tensor::from_data(&changed, &[2, 5, 4])?)?;
+        assert_eq!(changed_out.data()[..16], out.data()[..16]);
+        assert_ne!(changed_out.data()[16..20], out.data()[16..20]);
+
+        // Without the causal mask, a padded key is hidden from every query
+        let layer = TransformerEncoderLayer::new(4, 2, 8, &mut rng)?.with_norm_first();
+        let mask: Vec<bool> = (0..10).map(|idx| idx == 4).collect();
+        let out = layer.forward_masked(&x, Some(&mask))?;
+        let changed_out = layer.forward_masked(&Tensor::from_data(&changed, &[2, 5, 4])?, Some(&mask))?;
+        assert_eq!(changed_out.data()[..16], out.data()[..16]);
+        assert_eq!(changed_out.data()[20..], out.data()[20..]);
+        assert!(layer.forward_masked(&x, Some(&mask[..5])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unbatched_matches_batched() -> Result<()> {
+        let layer = TransformerEncoderLayer::new(4, 2, 8, &mut StdRng::seed_from_u64(0))?;
+        let x = input(&[3, 4]);
+        let batched = layer.forward_tensor(&x.unsqueeze(0)?)?;
+        let unbatched = layer.forward_tensor(&x)?;
+        assert_eq!(unbatched.shape(), &[3, 4]);
+        assert_eq!(unbatched.data(), batched.data());
+        assert_eq!(layer.forward(x.values())?.len(), 12);
+        assert!(layer.forward_tensor(&input(&[3, 5])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::cast_possible_wrap)]
+    fn compare_torch_causal_attention() {
+        let (batch, seq, embed_dim, num_heads) = (2, 4, 6, 3);
+        let head_dim = (embed_dim / num_heads) as i64;
+        let attn =
+            MultiHeadAttention::new(embed_dim, num_heads, &mut StdRng::seed_from_u64(1)).unwrap().with_causal_mask();
+        attn.in_proj_bias.values().iter().enumerate().for_each(|(i, b)| b.borrow_mut().data = 0.01 * i as f64);
+        let x = input(&[batch, seq, embed_dim]);
+        let y = attn.forward_tensor(&x).unwrap();
+        (&y * &y).sum().backward();
+
+        let to_tch = |t: &Tensor| {
+            let dims: Vec<i64> = t.shape().iter().map(|&d| d as i64).collect();
+            tch::Tensor::from_slice(&t.data()).reshape(dims).set_requires_grad(true)
+        };
+        let (xt, wt, bt) = (to_tch(&x), to_tch(&attn.in_proj_weight), to_tch(&attn.in_proj_bias));
+        let (wo, bo) = (to_tch(&attn.out_proj_weight), to_tch(&attn.out_proj_bias));
+        let (b, s, e, h) = (batch as i64, seq as i64, embed_dim as i64, num_heads as i64);
+        let heads = |t: tch::Tensor| t.reshape([b, s, h, head_dim]).permute([0, 2, 1, 3]);
+        let qkv = xt.linear(&wt, Some(&bt)).split(e, -1);
+        let (q, k, v) = (heads(qkv[0].shallow_clone()), heads(qkv[1].shallow_clone()), heads(qkv[2].shallow_clone()));
+        let mask = tch::Tensor::ones([s, s], (tch::Kind::Bool, tch::Device::Cpu)).triu(1);
+        let scores = (q.matmul(&k.transpose(-2, -1)) / (head_dim as f64).sqrt()).masked_fill(&mask, f64::NEG_INFINITY);
+        let out = scores.softmax(-1, tch::Kind::Double).matmul(&v).permute([0, 2, 1, 3]).reshape([b, s, e]);
+        let yt = out.linear(&wo, Some(&bo));
+        (&yt * &yt).sum(None).backward();
+
+        let flat = |t: &tch::Tensor| Vec::<FloatDataScalar>::try_from(t.reshape([-1])).unwrap();
+        y.data().iter().zip(flat(&yt)).for_each(|(l, r)| assert_close!(*l, r));
+        x.grad().unwrap().iter().zip(flat(&xt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+        attn.in_proj_weight.grad().unwrap().iter().zip(flat(&wt.grad())).for_each(|(l, r)| assert_close!(*l, r));
+        attn.out_proj_bias.grad().unwrap().iter().zip(flat(&bo.grad())).for_each(|(l, r)| assert_close!(*l, r));
+    }
+}
@@ -0,0 +1,392 @@
+use crate::engine::{FloatDataScalar, Value, dot, to_vec};
+use anyhow::{Result, bail};
+
+/// Integration scheme for `odeint` and `odeint_adjoint`
+#[derive(Debug, Clone, Copy)]
+pub enum Method {
+    /// Explicit Euler, taking equal steps no longer than `step_size` between consecutive output times
+    Euler { step_size: FloatDataScalar },
+    /// Classic fourth-order Runge-Kutta, with the same stepping as `Euler`
+    RK4 { step_size: FloatDataScalar },
+    /// Adaptive Dormand-Prince 5(4). A step is accepted when its error estimate is within
+    /// `atol + rtol * |y|` in root-mean-square over the state
+    DormandPrince { rtol: FloatDataScalar, atol: FloatDataScalar },
+}
+
+const MAX_STEPS: usize = 100_000;
+
+/// Butcher tableau of an explicit Runge-Kutta method. `b_err` is the difference between the solution weights and
+/// those of an embedded lower-order method, for adaptive step size control
+struct Tableau {
+    c: &'static [FloatDataScalar],
+    a: &'static [&'static [FloatDataScalar]],
+    b: &'static [FloatDataScalar],
+    b_err: Option<&'static [FloatDataScalar]>,
+}
+
+const EULER: Tableau = Tableau { c: &[0.0], a: &[], b: &[1.0], b_err: None };
+
+const RK4: Tableau = Tableau {
+    c: &[0.0, 0.5, 0.5, 1.0],
+    a: &[&[0.5], &[0.0, 0.5], &[0.0, 0.0, 1.0]],
+    b: &[1.0 / 6.0, 1.0 / 3.0, 1.0 / 3.0, 1.0 / 6.0],
+    b_err: None,
+};
+
+const DORMAND_PRINCE: Tableau = Tableau {
+    c: &[0.0, 1.0 / 5.0, 3.0 / 10.0, 4.0 / 5.0, 8.0 / 9.0, 1.0, 1.0],
+    a: &[
+        &[1.0 / 5.0],
+        &[3.0 / 40.0, 9.0 / 40.0],
+        &[44.0 / 45.0, -56.0 / 15.0, 32.0 / 9.0],
+        &[19372.0 / 6561.0, -25360.0 / 2187.0, 64448.0 / 6561.0, -212.0 / 729.0],
+        &[9017.0 / 3168.0, -355.0 / 33.0, 46732.0 / 5247.0, 49.0 / 176.0, -5103.0 / 18656.0],
+        &[35.0 / 384.0, 0.0, 500.0 / 1113.0, 125.0 / 192.0, -2187.0 / 6784.0, 11.0 / 84.0],
+    ],
+    b: &[35.0 / 384.0, 0.0, 500.0 / 1113.0, 125.0 / 192.0, -2187.0 / 6784.0, 11.0 / 84.0, 0.0],
+    b_err: Some(&[
+        35.0 / 384.0 - 5179.0 / 57600.0,
+        0.0,
+        500.0 / 1113.0 - 7571.0 / 16695.0,
+        125.0 / 192.0 - 393.0 / 640.0,
+        -2187.0 / 6784.0 + 92097.0 / 339_200.0,
+        11.0 / 84.0 - 187.0 / 2100.0,
+        -1.0 / 40.0,
+    ]),
+};
+
+/// Integrate `dy/dt = f(t, y, params)` from `y0` at `ts[0]`, returning the state at every time in `ts` (so the
+/// first entry is `y0` itself). `ts` must be strictly increasing or strictly decreasing.
+///
+/// Gradients reach `y0` and `params` by backpropagating through every step, so memory grows with the number of
+/// steps. For `DormandPrince` the step sizes themselves are treated as constants.
+pub fn odeint<F>(
+    f: F,
+    y0: &[Value],
+    params: &[Value],
+    ts: &[FloatDataScalar],
+    method: Method,
+) -> Result<Vec<Vec<Value>>>
+where
+    F: Fn(FloatDataScalar, &[Value], &[Value]) -> Vec<Value>,
+{
+    integrate(&|t, y: &[Value]| f(t, y, params), y0.to_vec(), ts, method, false)
+}
+
+/// Same as `odeint`, but the forward pass runs on raw floats and gradients come from the adjoint method: a second
+/// ODE for `dL/dy` and `dL/dparams` is integrated backwards in time with the same `method`. Memory no longer grows
+/// with the number of steps, at the cost of extra evaluations of `f` during the backward pass.
+///
+/// As with `implicit::fixed_point`, `f` is only called on fresh leaf values, and the returned states have
+/// `y0` and `params` as their only ancestors.
+pub fn odeint_adjoint<F>(
+    f: F,
+    y0: &[Value],
+    params: &[Value],
+    ts: &[FloatDataScalar],
+    method: Method,
+) -> Result<Vec<Vec<Value>>>
+where
+    F: Fn(FloatDataScalar, &[Value], &[Value]) -> Vec<Value> + 'static,
+{
+    let n = y0.len();
+    let theta = to_vec(params);
+    let forward_theta = theta.clone();
+    let states = integrate(&|t, y: &[Value]| f(t, y, &leaves(&forward_theta)), leaves(&to_vec(y0)), ts, method, true)?;
+    let states: Vec<Vec<FloatDataScalar>> = states.iter().map(|y| to_vec(y)).collect();
+    let flat: Vec<FloatDataScalar> = states.concat();
+
+    let ts = ts.to_vec();
+    let backward = move |_prev_nodes: &[Value], output_grads: &[FloatDataScalar]| {
+        let p = theta.len();
+        let output_grads: Vec<&[FloatDataScalar]> = output_grads.chunks(n).collect();
+
+        // Augmented state [y, a, a_params] with a = dL/dy, integrated backwards from each output time to the previous:
+        // dy/dt = f,  da/dt = -a^T df/dy,  da_params/dt = -a^T df/dparams
+        let dynamics = |t: FloatDataScalar, z: &[Value]| {
+            let z = to_vec(z);
+            let (y, theta_leaves) = (leaves(&z[..n]), leaves(&theta));
+            let dy = f(t, &y, &theta_leaves);
+            dot(&dy, &leaves(&z[n..2 * n])).backward();
+            let negated_grad = |v: &Value| Value::from(-v.grad().unwrap_or(0.0));
+            dy.iter()
+                .map(|v| Value::from(v.data()))
+                .chain(y.iter().map(negated_grad))
+                .chain(theta_leaves.iter().map(negated_grad))
+                .collect::<Vec<_>>()
+        };
+
+        let last = ts.len() - 1;
+        let mut adjoint = output_grads[last].to_vec();
+        let mut adjoint_params = vec![0.0; p];
+        for idx in (1..=last).rev() {
+            // Restart from the stored forward state, rather than one reconstructed by integrating backwards
+            let z0: Vec<FloatDataScalar> = states[idx].iter().chain(&adjoint).chain(&adjoint_params).copied().collect();
+            let z = match integrate(&dynamics, leaves(&z0), &[ts[idx], ts[idx - 1]], method, true) {
+                Ok(z) => to_vec(&z[1]),
+                Err(e) => {
+                    log::warn!("adjoint integration failed, gradients are NaN: {e}");
+                    return vec![FloatDataScalar::NAN; n + p];
+                }
+            };
+            adjoint = z[n..2 * n].iter().zip(output_grads[idx - 1]).map(|(a, g)| a + g).collect();
+            adjoint_params = z[2 * n..].to_vec();
+        }
+        adjoint.into_iter().chain(adjoint_params).collect()
+    };
+
+    let prev_nodes = y0.iter().chain(params).cloned().collect();
+    let outputs = Value::custom_multi(&flat, prev_nodes, "odeint_adjoint", backward);
+    Ok(outputs.chunks(n.max(1)).map(<[Value]>::to_vec).collect())
+}
+
+fn leaves(data: &[FloatDataScalar]) -> Vec<Value> {
+    data.iter().map(Value::from).collect()
+}
+
+/// Shared stepping loop. With `detach`, the state is replaced by fresh leaves after every step, so no graph is kept
+fn integrate(
+    f: &dyn Fn(FloatDataScalar, &[Value]) -> Vec<Value>,
+    y0: Vec<Value>,
+    ts: &[FloatDataScalar],
+    method: Method,
+    detach: bool,
+) -> Result<Vec<Vec<Value>>> {
+    if ts.is_empty() {
+        bail!("ts must contain at least the initial time");
+    }
+    let increasing = ts.windows(2).all(|w| w[1] > w[0]);
+    let decreasing = ts.windows(2).all(|w| w[1] < w[0]);
+    if !increasing && !decreasing {
+        bail!("ts must be strictly monotonic: {ts:?}");
+    }
+
+    let mut y = y0;
+    let mut states = vec![y.clone()];
+    let mut n_steps = 0;
+    // Adaptive step size, carried over between output times
+    let mut h_adaptive = (ts[ts.len() - 1] - ts[0]) * 1e-2;
+
+    for window in ts.windows(2) {
+        let (t_start, t_end) = (window[0], window[1]);
+        match method {
+            Method::Euler { step_size } | Method::RK4 { step_size } => {
+                if step_size <= 0.0 {
+                    bail!("step_size must be positive, got {step_size}");
+                }
+                let tableau = if matches!(method, Method::Euler { .. }) { &EULER } else { &RK4 };
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                let n = ((t_end - t_start).abs() / step_size).ceil().max(1.0) as usize;
+                #[allow(clippy::cast_precision_loss)]
+                let h = (t_end - t_start) / n as FloatDataScalar;
+                for step in 0..n {
+                    #[allow(clippy::cast_precision_loss)]
+                    let t = h.mul_add(step as FloatDataScalar, t_start);
+                    y = rk_step(tableau, f, t, &y, h).0;
+                    if detach {
+                        y = leaves(&to_vec(&y));
+                    }
+                }
+            }
+            Method::DormandPrince { rtol, atol } => {
+                let mut t = t_start;
+                while (t_end - t).abs() > 0.0 {
+                    n_steps += 1;
+                    if n_steps > MAX_STEPS {
+                        bail!("Dormand-Prince took more than {MAX_STEPS} steps");
+                    }
+                    // Never step past the next output time
+                    let h = if h_adaptive.abs() >= (t_end - t).abs() { t_end - t } else { h_adaptive };
+                    let (y_next, error) = rk_step(&DORMAND_PRINCE, f, t, &y, h);
+                    let error = error.expect("Dormand-Prince has an error estimate");
+
+                    #[allow(clippy::cast_precision_loss)]
+                    let rms = (y
+                        .iter()
+                        .zip(&y_next)
+                        .zip(&error)
+                        .map(|((y0, y1), e)| (e / rtol.mul_add(y0.data().abs().max(y1.data().abs()), atol)).powi(2))
+                        .sum::<FloatDataScalar>()
+                        / y.len().max(1) as FloatDataScalar)
+                        .sqrt();
+                    if !rms.is_finite() {
+                        bail!("Dormand-Prince error estimate is not finite at t = {t}");
+                    }
+
+                    if rms <= 1.0 {
+                        t = if h == t_end - t { t_end } else { t + h };
+                        y = if detach { leaves(&to_vec(&y_next)) } else { y_next };
+                    }
+                    let factor = if rms == 0.0 { 10.0 } else { (0.9 * rms.powf(-0.2)).clamp(0.2, 10.0) };
+                    h_adaptive = h * factor;
+                }
+            }
+        }
+        states.push(y.clone());
+    }
+    Ok(states)
+}
+
+/// One explicit Runge-Kutta step. Returns the new state and, if the tableau has one, the error estimate per component
+fn rk_step(
+    tableau: &Tableau,
+    f: &dyn Fn(FloatDataScalar, &[Value]) -> Vec<Value>,
+    t: FloatDataScalar,
+    y: &[Value],
+    h: FloatDataScalar,
+) -> (Vec<Value>, Option<Vec<FloatDataScalar>>) {
+    let mut ks: Vec<Vec<Value>> = Vec::with_capacity(tableau.b.len());
+    ks.push(f(t, y));
+    for (c, a) in tableau.c[1..].iter().zip(tableau.a) {
+        let y_stage = combine(y, &ks, a, h);
+        ks.push(f(h.mul_add(*c, t), &y_stage));
+    }
+    let y_next = combine(y, &ks, tableau.b, h);
+    let error = tableau.b_err.map(|b_err| {
+        (0..y.len()).map(|d| h * ks.iter().zip(b_err).fold(0.0, |acc, (k, b)| b.mul_add(k[d].data(), acc))).collect()
+    });
+    (y_next, error)
+}
+
+/// `y + h * sum_j coeffs[j] * ks[j]`, skipping zero coefficients
+fn combine(y: &[Value], ks: &[Vec<Value>], coeffs: &[FloatDataScalar], h: FloatDataScalar) -> Vec<Value> {
+    let used: Vec<(usize, FloatDataScalar)> =
+        coeffs.iter().enumerate().filter(|(_, c)| **c != 0.0).map(|(j, c)| (j, h * c)).collect();
+    let weights: Vec<Value> = used.iter().map(|(_, w)| Value::from(*w)).collect();
+    y.iter()
+        .enumerate()
+        .map(|(d, yd)| {
+            let slopes: Vec<Value> = used.iter().map(|(j, _)| ks[*j][d].clone()).collect();
+            yd + dot(&weights, &slopes)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_close;
+    use crate::engine::sum;
+    use crate::optim::{Optim, SGD};
+
+    fn decay(_t: FloatDataScalar, y: &[Value], params: &[Value]) -> Vec<Value> {
+        vec![-1.0 * &params[0] * &y[0]]
+    }
+
+    /// x'' = -omega^2 x, as the first-order system (x, v)
+    fn oscillator(_t: FloatDataScalar, y: &[Value], params: &[Value]) -> Vec<Value> {
+        vec![y[1].clone(), -1.0 * params[0].powi(2) * &y[0]]
+    }
+
+    #[test]
+    fn euler_matches_closed_form() -> Result<()> {
+        // n Euler steps of size h give y0 * (1 - k h)^n exactly
+        let (y0, k) = ([Value::from(2.0)], [Value::from(0.5)]);
+        let ys = odeint(decay, &y0, &k, &[0.0, 1.0], Method::Euler { step_size: 0.1 })?;
+        let y1 = &ys[1][0];
+        assert_close!(y1.data(), 2.0 * 0.95f64.powi(10));
+
+        y1.backward();
+        assert_close!(y0[0].grad().unwrap(), 0.95f64.powi(10));
+        assert_close!(k[0].grad().unwrap(), 2.0 * 10.0 * 0.95f64.powi(9) * -0.1);
+        Ok(())
+    }
+
+    #[test]
+    fn exponential_decay() -> Result<()> {
+        let ts = [0.0, 0.5, 1.0, 2.0];
+        for method in [Method::RK4 { step_size: 0.01 }, Method::DormandPrince { rtol: 1e-9, atol: 1e-12 }] {
+            let (y0, k) = ([Value::from(2.0)], [Value::from(0.7)]);
+            let ys = odeint(decay, &y0, &k, &ts, method)?;
+            for (t, y) in ts.iter().zip(&ys) {
+                assert_close!(y[0].data(), 2.0 * (-0.7 * t).exp(), 1e-7, 1e-9);
+            }
+
+            ys[3][0].backward();
+            assert_close!(y0[0].grad().unwrap(), (-1.4f64).exp(), 1e-7, 1e-9);
+            assert_close!(k[0].grad().unwrap(), -2.0 * 2.0 * (-1.4f64).exp(), 1e-7, 1e-9);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn harmonic_oscillator() -> Result<()> {
+        let (x0, v0, omega) = (1.0, 0.5, 2.0);
+        let t = 3.0;
+        let ts = [0.0, t];
+        let method = Method::DormandPrince { rtol: 1e-10, atol: 1e-12 };
+
+        let y0 = [Value::from(x0), Value::from(v0)];
+        let params = [Value::from(omega)];
+        let ys = odeint(oscillator, &y0, &params, &ts, method)?;
+
+        let (s, c) = (omega * t).sin_cos();
+        assert_close!(ys[1][0].data(), x0 * c + v0 / omega * s, 1e-7, 1e-9);
+        assert_close!(ys[1][1].data(), -x0 * omega * s + v0 * c, 1e-7, 1e-9);
+
+        ys[1][0].backward();
+        assert_close!(y0[0].grad().unwrap(), c, 1e-7, 1e-9);
+        assert_close!(y0[1].grad().unwrap(), s / omega, 1e-7, 1e-9);
+        let dx_domega = -x0 * t * s + v0 * (t * c / omega - s / omega.powi(2));
+        assert_close!(params[0].grad().unwrap(), dx_domega, 1e-6, 1e-9);
+        Ok(())
+    }
+
+    #[test]
+    fn adjoint_matches_direct_backprop() -> Result<()> {
+        let ts = [0.0, 0.7, 1.5, 2.0];
+        for method in [Method::RK4 { step_size: 0.01 }, Method::DormandPrince { rtol: 1e-10, atol: 1e-12 }] {
+            let loss = |ys: &[Vec<Value>]| sum(&ys.iter().map(|y| &y[0] * &y[1]).collect::<Vec<_>>());
+
+            let y0 = [Value::from(1.0), Value::from(0.5)];
+            let params = [Value::from(2.0)];
+            let ys = odeint(oscillator, &y0, &params, &ts, method)?;
+            loss(&ys).backward();
+
+            let y0_adj = [Value::from(1.0), Value::from(0.5)];
+            let params_adj = [Value::from(2.0)];
+            let ys_adj = odeint_adjoint(oscillator, &y0_adj, &params_adj, &ts, method)?;
+            loss(&ys_adj).backward();
+
+            for (y, y_adj) in ys.iter().flatten().zip(ys_adj.iter().flatten()) {
+                assert_close!(y.data(), y_adj.data(), 1e-7, 1e-9);
+            }
+            for (v, v_adj) in y0.iter().chain(&params).zip(y0_adj.iter().chain(&params_adj)) {
+                assert_close!(v.grad().unwrap(), v_adj.grad().unwrap(), 1e-5, 1e-8);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fit_decay_rate() -> Result<()> {
+        // Recover k = 1.3 from observations of y0 * exp(-k t)
+        let ts = [0.0, 0.25, 0.5, 1.0];
+        let observed: Vec<FloatDataScalar> = ts.iter().map(|t: &FloatDataScalar| (-1.3 * t).exp()).collect();
+
+        let k = [Value::from(0.5)];
+        let mut optim = SGD::new(&k, 0.5);
+        for _ in 0..200 {
+            let ys = odeint_adjoint(decay, &[Value::from(1.0)], &k, &ts, Method::RK4 { step_size: 0.05 })?;
+            let errors: Vec<Value> = ys.iter().zip(&observed).map(|(y, obs)| (&y[0] - *obs).powi(2)).collect();
+            optim.zero_grad();
+            sum(&errors).backward();
+            optim.step();
+        }
+        assert_close!(k[0].data(), 1.3, 1e-4, 1e-6);
+        Ok(())
+    }
+
+    #[test]
+    fn backwards_in_time_and_bad_ts() -> Result<()> {
+        let ys = odeint(decay, &[Value::from(1.0)], &[Value::from(1.0)], &[1.0, 0.0], Method::RK4 { step_size: 0.01 })?;
+        assert_close!(ys[1][0].data(), 1.0f64.exp(), 1e-7, 1e-9);
+
+        assert!(
+            odeint(decay, &[Value::from(1.0)], &[Value::from(1.0)], &[0.0, 1.0, 0.5], Method::RK4 { step_size: 0.1 })
+                .is_err()
+        );
+        assert!(odeint(decay, &[Value::from(1.0)], &[Value::from(1.0)], &[], Method::RK4 { step_size: 0.1 }).is_err());
+        Ok(())
+    }
+}
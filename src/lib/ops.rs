@@ -2,6 +2,110 @@
 /// Thus, need to finish dealing with LHS before dealing with RHS
 #[macro_export]
 macro_rules! impl_binary_op {
+    // Elementwise op between broadcastable Tensors, reusing the scalar op on each pair of Values.
+    // Scalar operands (f64 or Value) are wrapped as 0-dimensional tensors and broadcast like any other shape
+    (@tensor $trait:ident, $method:ident, $func:ident, $operator:tt) =>
+    (
+        // Method-call style
+        impl Tensor {
+            fn $func(&self, rhs: &Tensor) -> Tensor {
+                match self.zip_with(rhs, |lhs, rhs| lhs $operator rhs) {
+                    Ok(result) => result,
+                    Err(e) => panic!("{e}"),
+                }
+            }
+        }
+
+        // Operations between two Tensor
+        impl $trait<Tensor> for Tensor {
+            type Output = Tensor;
+            #[inline]
+            fn $method(self, rhs: Tensor) -> Self::Output {
+                self.$func(&rhs)
+            }
+        }
+        impl $trait<&Tensor> for Tensor {
+            type Output = Tensor;
+            #[inline]
+            fn $method(self, rhs: &Tensor) -> Self::Output {
+                self.$func(rhs)
+            }
+        }
+        impl<'a> $trait<&Tensor> for &'a Tensor {
+            type Output = Tensor;
+            #[inline]
+            fn $method(self, rhs: &Tensor) -> Self::Output {
+                self.$func(rhs)
+            }
+        }
+        impl<'a> $trait<Tensor> for &'a Tensor {
+            type Output = Tensor;
+            #[inline]
+            fn $method(self, rhs: Tensor) -> Self::Output {
+                self.$func(&rhs)
+            }
+        }
+
+        // Tensor on LHS, scalar on RHS
+        impl $trait<f64> for Tensor {
+            type Output = Tensor;
+            #[inline]
+            fn $method(self, rhs: f64) -> Self::Output {
+                self.$func(&Tensor::scalar(rhs))
+            }
+        }
+        impl $trait<f64> for &Tensor {
+            type Output = Tensor;
+            #[inline]
+            fn $method(self, rhs: f64) -> Self::Output {
+                self.$func(&Tensor::scalar(rhs))
+            }
+        }
+        impl $trait<&Value> for Tensor {
+            type Output = Tensor;
+            #[inline]
+            fn $method(self, rhs: &Value) -> Self::Output {
+                self.$func(&Tensor::scalar(rhs.clone()))
+            }
+        }
+        impl $trait<&Value> for &Tensor {
+            type Output = Tensor;
+            #[inline]
+            fn $method(self, rhs: &Value) -> Self::Output {
+                self.$func(&Tensor::scalar(rhs.clone()))
+            }
+        }
+
+        // Scalar on LHS, Tensor on RHS
+        impl $trait<Tensor> for f64 {
+            type Output = Tensor;
+            #[inline]
+            fn $method(self, rhs: Tensor) -> Self::Output {
+                Tensor::scalar(self).$func(&rhs)
+            }
+        }
+        impl $trait<&Tensor> for f64 {
+            type Output = Tensor;
+            #[inline]
+            fn $method(self, rhs: &Tensor) -> Self::Output {
+                Tensor::scalar(self).$func(rhs)
+            }
+        }
+        impl $trait<Tensor> for &Value {
+            type Output = Tensor;
+            #[inline]
+            fn $method(self, rhs: Tensor) -> Self::Output {
+                Tensor::scalar(self.clone()).$func(&rhs)
+            }
+        }
+        impl $trait<&Tensor> for &Value {
+            type Output = Tensor;
+            #[inline]
+            fn $method(self, rhs: &Tensor) -> Self::Output {
+                Tensor::scalar(self.clone()).$func(rhs)
+            }
+        }
+    );
     ($self:ident, $rhs:ident, $trait:ident, $method:ident, $func:ident, $operator:tt, $body:tt) =>
     (
 
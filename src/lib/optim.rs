@@ -2,6 +2,8 @@ use crate::engine::{FloatDataScalar, Value};
 
 pub trait Optim {
     fn zero_grad(&self);
+    /// Update every parameter that received a gradient. Parameters without one, such as `Embedding` rows that were
+    /// not looked up since `zero_grad`, are left untouched, along with any optimizer state kept for them
     fn step(&mut self);
 }
 
@@ -32,7 +34,8 @@ impl Optim for SGD {
     #[inline]
     fn step(&mut self) {
         for p in &self.parameters {
-            p.borrow_mut().data = self.lr.mul_add(-p.grad().expect("step without grad"), p.data());
+            let Some(grad) = p.grad() else { continue };
+            p.borrow_mut().data = self.lr.mul_add(-grad, p.data());
         }
     }
 }
@@ -46,7 +49,8 @@ pub struct AdamW {
     weight_decay: f64,
     momentum: Vec<FloatDataScalar>,
     velocity: Vec<FloatDataScalar>,
-    time_step: i32,
+    /// Steps taken by each parameter, which differ once some parameters skip steps without a gradient
+    time_steps: Vec<i32>,
 }
 
 impl AdamW {
@@ -62,7 +66,7 @@ impl AdamW {
             weight_decay,
             momentum: vec![0.0; n],
             velocity: vec![0.0; n],
-            time_step: 0,
+            time_steps: vec![0; n],
         }
     }
 }
@@ -77,17 +81,18 @@ impl Optim for AdamW {
 
     #[inline]
     fn step(&mut self) {
-        self.time_step += 1;
         for (idx, p) in self.parameters.iter().enumerate() {
-            let grad = p.grad().expect("step without grad");
+            let Some(grad) = p.grad() else { continue };
+            self.time_steps[idx] += 1;
+            let time_step = self.time_steps[idx];
             let mom = self.beta1.mul_add(self.momentum[idx], (1.0 - self.beta1) * grad);
             self.momentum[idx] = mom;
 
             let vel = self.beta2.mul_add(self.velocity[idx], (1.0 - self.beta2) * grad.powi(2));
             self.velocity[idx] = vel;
 
-            let first_moment = mom / (1.0 - self.beta1.powi(self.time_step));
-            let second_moment = vel / (1.0 - self.beta2.powi(self.time_step));
+            let first_moment = mom / (1.0 - self.beta1.powi(time_step));
+            let second_moment = vel / (1.0 - self.beta2.powi(time_step));
 
             let old_val = p.data();
             p.borrow_mut().data = self
@@ -109,7 +114,7 @@ impl Optim for AdamW {
 //             weight_decay: 0.01,
 //             momentum: vec![],
 //             velocity: vec![],
-//             time_step: 0,
+//             time_steps: vec![],
 //         }
 //     }
 // }
@@ -0,0 +1,511 @@
+use crate::engine::{FloatDataScalar, Op, Value};
+use anyhow::{Result, bail};
+use core::f64;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+/// Output flavor for `export`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// Plain text, e.g. `relu(2*x + 2 + x)`
+    Infix,
+    Latex,
+    /// A `fn f(...) -> f64` that recomputes the graph
+    Rust,
+    /// A `def f(...)` that recomputes the graph
+    Python,
+}
+
+// Binding strength of the outermost operator of a rendered expression
+const SUM: u8 = 1;
+const PRODUCT: u8 = 2;
+const NEG: u8 = 3;
+const POWER: u8 = 4;
+const ATOM: u8 = 5;
+
+#[derive(Debug, Clone)]
+struct Rendered {
+    text: String,
+    prec: u8,
+    /// Set when this node is `-1 * operand`, so that a parent `a + (-1 * b)` can print as `a - b`
+    negated: Option<Box<Rendered>>,
+}
+
+impl Rendered {
+    fn new(text: String, prec: u8) -> Self {
+        Self { text, prec, negated: None }
+    }
+}
+
+/// Render the graph ending at `root` as a formula.
+///
+/// `inputs` names the values that should appear as variables; any other leaf is printed as a numeric literal.
+/// A named value that is not a leaf is treated as an input too, which cuts the graph at that point.
+/// Intermediate nodes used more than once are bound to temporaries (`t0`, `t1`, ...) instead of being repeated.
+///
+/// Fails if a node's op does not match its number of ancestors, as can happen for nodes built with `Value::with_op`
+pub fn export(root: &Value, inputs: &[(&Value, &str)], syntax: Syntax) -> Result<String> {
+    #[allow(clippy::mutable_key_type)]
+    let names: HashMap<Value, &str> = inputs.iter().map(|(v, name)| ((*v).clone(), *name)).collect();
+
+    #[allow(clippy::mutable_key_type)]
+    let mut visited = HashSet::new();
+    let mut topo = Vec::new();
+    build_topo(root, &names, &mut visited, &mut topo);
+
+    #[allow(clippy::mutable_key_type)]
+    let mut n_uses: HashMap<Value, usize> = HashMap::new();
+    for node in &topo {
+        if !names.contains_key(node) {
+            for prev in node.prev_nodes() {
+                *n_uses.entry(prev).or_default() += 1;
+            }
+        }
+    }
+
+    let mut uses_math = false;
+    let mut assignments: Vec<(String, String)> = vec![];
+    #[allow(clippy::mutable_key_type)]
+    let mut rendered: HashMap<Value, Rendered> = HashMap::new();
+    for node in &topo {
+        let expr = if let Some(name) = names.get(node) {
+            Rendered::new((*name).to_string(), ATOM)
+        } else {
+            let args: Vec<Rendered> = node.prev_nodes().iter().map(|p| rendered[p].clone()).collect();
+            render_op(node, &args, syntax, &mut uses_math)?
+        };
+
+        let shared = n_uses.get(node).copied().unwrap_or(0) > 1;
+        let expr = if shared && node != root && !names.contains_key(node) && node.op() != Op::Leaf {
+            let temp = temp_name(assignments.len(), syntax);
+            assignments.push((temp.clone(), expr.text));
+            Rendered::new(temp, ATOM)
+        } else {
+            expr
+        };
+        rendered.insert(node.clone(), expr);
+    }
+
+    let body = &rendered[root].text;
+    let mut out = String::new();
+    match syntax {
+        Syntax::Infix => {
+            for (temp, expr) in &assignments {
+                writeln!(out, "{temp} = {expr}").unwrap();
+            }
+            out.push_str(body);
+        }
+        Syntax::Latex => {
+            for (temp, expr) in &assignments {
+                writeln!(out, "{temp} = {expr} \\\\").unwrap();
+            }
+            out.push_str(body);
+        }
+        Syntax::Rust => {
+            let args = inputs.iter().map(|(_, name)| format!("{name}: f64")).collect::<Vec<_>>().join(", ");
+            writeln!(out, "fn f({args}) -> f64 {{").unwrap();
+            for (temp, expr) in &assignments {
+                writeln!(out, "    let {temp} = {expr};").unwrap();
+            }
+            write!(out, "    {body}\n}}").unwrap();
+        }
+        Syntax::Python => {
+            if uses_math {
+                out.push_str("import math\n\n\n");
+            }
+            let args = inputs.iter().map(|(_, name)| *name).collect::<Vec<_>>().join(", ");
+            writeln!(out, "def f({args}):").unwrap();
+            for (temp, expr) in &assignments {
+                writeln!(out, "    {temp} = {expr}").unwrap();
+            }
+            write!(out, "    return {body}").unwrap();
+        }
+    }
+    Ok(out)
+}
+
+/// Same traversal as `engine::build_topo_recursive`, except named values are not expanded
+#[allow(clippy::mutable_key_type)]
+fn build_topo(node: &Value, names: &HashMap<Value, &str>, visited: &mut HashSet<Value>, topo: &mut Vec<Value>) {
+    if !visited.contains(node) {
+        visited.insert(node.clone());
+        if !names.contains_key(node) {
+            for prev in node.prev_nodes() {
+                build_topo(&prev, names, visited, topo);
+            }
+        }
+        topo.push(node.clone());
+    }
+}
+
+fn temp_name(idx: usize, syntax: Syntax) -> String {
+    match syntax {
+        Syntax::Latex => format!("t_{{{idx}}}"),
+        _ => format!("t{idx}"),
+    }
+}
+
+fn literal(data: FloatDataScalar, syntax: Syntax) -> Rendered {
+    let text = match syntax {
+        Syntax::Infix | Syntax::Latex => format!("{data}"),
+        Syntax::Rust => {
+            if data.is_nan() {
+                "f64::NAN".to_string()
+            } else if data.is_infinite() {
+                if data > 0.0 { "f64::INFINITY" } else { "f64::NEG_INFINITY" }.to_string()
+            } else {
+                format!("{data:?}_f64")
+            }
+        }
+        Syntax::Python => {
+            if data.is_finite() {
+                format!("{data:?}")
+            } else {
+                format!("float('{data}')")
+            }
+        }
+    };
+    let prec = if data.is_sign_negative() { NEG } else { ATOM };
+    Rendered::new(text, prec)
+}
+
+/// Wrap in parentheses when `expr` binds less tightly than `min_prec`
+fn paren(expr: &Rendered, min_prec: u8, syntax: Syntax) -> String {
+    if expr.prec >= min_prec {
+        expr.text.clone()
+    } else if syntax == Syntax::Latex {
+        format!("\\left({}\\right)", expr.text)
+    } else {
+        format!("({})", expr.text)
+    }
+}
+
+fn is_constant(node: &Value, value: FloatDataScalar) -> bool {
+    node.op() == Op::Leaf && node.data() == value
+}
+
+fn render_op(node: &Value, args: &[Rendered], syntax: Syntax, uses_math: &mut bool) -> Result<Rendered> {
+    let prev = node.prev_nodes();
+    let rendered = match (node.op(), args) {
+        (Op::Leaf, []) => literal(node.data(), syntax),
+        (Op::Add, [a, b]) => match &b.negated {
+            Some(inner) => Rendered::new(format!("{} - {}", a.text, paren(inner, PRODUCT, syntax)), SUM),
+            None => Rendered::new(format!("{} + {}", a.text, paren(b, SUM, syntax)), SUM),
+        },
+        (Op::Mul, [a, b]) => {
+            if is_constant(&prev[0], -1.0) {
+                negate(b, syntax)
+            } else if is_constant(&prev[1], -1.0) {
+                negate(a, syntax)
+            } else {
+                join_product(args, syntax)
+            }
+        }
+        (Op::Sum, terms) => join_sum(terms, syntax),
+        (Op::Prod, factors) => join_product(factors, syntax),
+        (Op::Dot, _) => {
+            let (lhs, rhs) = args.split_at(args.len() / 2);
+            let terms: Vec<Rendered> =
+                lhs.iter().zip(rhs).map(|(l, r)| join_product(&[l.clone(), r.clone()], syntax)).collect();
+            join_sum(&terms, syntax)
+        }
+        (Op::Mean, terms) => {
+            let total = join_sum(terms, syntax);
+            #[allow(clippy::cast_precision_loss)]
+            let count = literal(terms.len() as FloatDataScalar, syntax);
+            match syntax {
+                Syntax::Infix => {
+                    let terms = terms.iter().map(|t| t.text.as_str()).collect::<Vec<_>>().join(", ");
+                    Rendered::new(format!("mean({terms})"), ATOM)
+                }
+                Syntax::Latex => Rendered::new(format!("\\frac{{{}}}{{{}}}", total.text, count.text), ATOM),
+                Syntax::Rust | Syntax::Python => {
+                    Rendered::new(format!("{} / {}", paren(&total, PRODUCT, syntax), count.text), PRODUCT)
+                }
+            }
+        }
+        (Op::Pow, [base, exponent]) => {
+            // `Value::exp` is built as `e.pow(x)`
+            if is_constant(&prev[0], f64::consts::E) {
+                let text = match syntax {
+                    Syntax::Infix => format!("exp({})", exponent.text),
+                    Syntax::Latex => format!("e^{{{}}}", exponent.text),
+                    Syntax::Rust => format!("{}.exp()", paren(exponent, ATOM, syntax)),
+                    Syntax::Python => {
+                        *uses_math = true;
+                        format!("math.exp({})", exponent.text)
+                    }
+                };
+                return Ok(Rendered::new(text, if syntax == Syntax::Latex { POWER } else { ATOM }));
+            }
+            render_pow(base, exponent, "powf", syntax)
+        }
+        (Op::Powi(exponent), [base]) => {
+            let prec = if exponent < 0 { NEG } else { ATOM };
+            render_pow(base, &Rendered::new(exponent.to_string(), prec), "powi", syntax)
+        }
+        (Op::Powf(exponent), [base]) => render_pow(base, &literal(exponent, syntax), "powf", syntax),
+        (Op::Div, [a, b]) => match syntax {
+            Syntax::Infix => {
+                Rendered::new(format!("{}/{}", paren(a, PRODUCT, syntax), paren(b, POWER, syntax)), PRODUCT)
+            }
+            Syntax::Latex => Rendered::new(format!("\\frac{{{}}}{{{}}}", a.text, b.text), ATOM),
+            Syntax::Rust | Syntax::Python => {
+                Rendered::new(format!("{} / {}", paren(a, PRODUCT, syntax), paren(b, POWER, syntax)), PRODUCT)
+            }
+        },
+        (Op::Log, [a]) => {
+            let text = match syntax {
+                Syntax::Infix => format!("log({})", a.text),
+                Syntax::Latex => format!("\\log\\left({}\\right)", a.text),
+                Syntax::Rust => format!("{}.ln()", paren(a, ATOM, syntax)),
+                Syntax::Python => {
+                    *uses_math = true;
+                    format!("math.log({})", a.text)
+                }
+            };
+            Rendered::new(text, ATOM)
+        }
+        (Op::ReLU, [a]) => {
+            let text = match syntax {
+                Syntax::Infix => format!("relu({})", a.text),
+                Syntax::Latex => format!("\\operatorname{{relu}}\\left({}\\right)", a.text),
+                Syntax::Rust => format!("{}.max(0.0)", paren(a, ATOM, syntax)),
+                Syntax::Python => format!("max({}, 0.0)", a.text),
+            };
+            Rendered::new(text, ATOM)
+        }
+        (Op::Tanh, [a]) => {
+            let text = match syntax {
+                Syntax::Infix => format!("tanh({})", a.text),
+                Syntax::Latex => format!("\\tanh\\left({}\\right)", a.text),
+                Syntax::Rust => format!("{}.tanh()", paren(a, ATOM, syntax)),
+                Syntax::Python => {
+                    *uses_math = true;
+                    format!("math.tanh({})", a.text)
+                }
+            };
+            Rendered::new(text, ATOM)
+        }
+        (Op::Sigmoid, [a]) => match syntax {
+            Syntax::Infix => Rendered::new(format!("sigmoid({})", a.text), ATOM),
+            Syntax::Latex => Rendered::new(format!("\\sigma\\left({}\\right)", a.text), ATOM),
+            Syntax::Rust => Rendered::new(format!("1.0 / (1.0 + (-{}).exp())", paren(a, ATOM, syntax)), PRODUCT),
+            Syntax::Python => {
+                *uses_math = true;
+                Rendered::new(format!("1.0 / (1.0 + math.exp(-{}))", paren(a, ATOM, syntax)), PRODUCT)
+            }
+        },
+        (Op::Custom(name), args) => {
+            let args = args.iter().map(|a| a.text.as_str()).collect::<Vec<_>>().join(", ");
+            let text = match syntax {
+                Syntax::Latex => format!("\\operatorname{{{name}}}\\left({args}\\right)"),
+                _ => format!("{name}({args})"),
+            };
+            Rendered::new(text, ATOM)
+        }
+        (Op::Output(idx), [hub]) => match syntax {
+            Syntax::Latex => Rendered::new(format!("{{{}}}_{{{idx}}}", hub.text), ATOM),
+            _ => Rendered::new(format!("{}[{idx}]", paren(hub, ATOM, syntax)), ATOM),
+        },
+        (op, _) => bail!("cannot export a {op:?} node with {} ancestors", args.len()),
+    };
+    Ok(rendered)
+}
+
+/// `rust_method` is the `f64` method used for the Rust snippet, since the exponent's type differs
+fn render_pow(base: &Rendered, exponent: &Rendered, rust_method: &str, syntax: Syntax) -> Rendered {
+    match syntax {
+        Syntax::Infix => {
+            Rendered::new(format!("{}^{}", paren(base, ATOM, syntax), paren(exponent, POWER, syntax)), POWER)
+        }
+        Syntax::Latex => Rendered::new(format!("{{{}}}^{{{}}}", paren(base, ATOM, syntax), exponent.text), POWER),
+        Syntax::Rust => Rendered::new(format!("{}.{rust_method}({})", paren(base, ATOM, syntax), exponent.text), ATOM),
+        Syntax::Python => {
+            Rendered::new(format!("{} ** {}", paren(base, ATOM, syntax), paren(exponent, POWER, syntax)), POWER)
+        }
+    }
+}
+
+fn join_sum(terms: &[Rendered], syntax: Syntax) -> Rendered {
+    match terms {
+        [] => literal(0.0, syntax),
+        [term] => term.clone(),
+        [first, rest @ ..] => {
+            let mut text = first.text.clone();
+            for term in rest {
+                match &term.negated {
+                    Some(inner) => write!(text, " - {}", paren(inner, PRODUCT, syntax)).unwrap(),
+                    None => write!(text, " + {}", paren(term, SUM, syntax)).unwrap(),
+                }
+            }
+            Rendered::new(text, SUM)
+        }
+    }
+}
+
+fn join_product(factors: &[Rendered], syntax: Syntax) -> Rendered {
+    let sep = match syntax {
+        Syntax::Infix => "*",
+        Syntax::Latex => " \\cdot ",
+        Syntax::Rust | Syntax::Python => " * ",
+    };
+    match factors {
+        [] => literal(1.0, syntax),
+        [factor] => factor.clone(),
+        [first, rest @ ..] => {
+            let mut text = paren(first, PRODUCT, syntax);
+            for factor in rest {
+                write!(text, "{sep}{}", paren(factor, POWER, syntax)).unwrap();
+            }
+            Rendered::new(text, PRODUCT)
+        }
+    }
+}
+
+fn negate(operand: &Rendered, syntax: Syntax) -> Rendered {
+    let mut negated = Rendered::new(format!("-{}", paren(operand, POWER, syntax)), NEG);
+    negated.negated = Some(Box::new(operand.clone()));
+    negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{dot, mean, prod, sum};
+
+    #[test]
+    fn infix_simple() {
+        let x = Value::from(-4.0);
+        let y = Value::from(2.0);
+        let z = (2 * &x + 2 + &y).relu();
+        assert_eq!(export(&z, &[(&x, "x"), (&y, "y")], Syntax::Infix).unwrap(), "relu(2*x + 2 + y)");
+    }
+
+    #[test]
+    fn infix_sub_div_exp_log() {
+        let a = Value::from(3.0);
+        let b = Value::from(0.5);
+        let c = (&a - &b).log() / b.exp();
+        assert_eq!(export(&c, &[(&a, "a"), (&b, "b")], Syntax::Infix).unwrap(), "log(a - b)/exp(b)");
+        assert_eq!(
+            export(&c, &[(&a, "a"), (&b, "b")], Syntax::Latex).unwrap(),
+            "\\frac{\\log\\left(a - b\\right)}{e^{b}}"
+        );
+    }
+
+    #[test]
+    fn shared_subexpression_becomes_temporary() {
+        let x = Value::from(-4.0);
+        let z = 2 * &x + 2 + &x;
+        let q = &z.relu() + &z * &x;
+        let h = (&z * &z).relu();
+        let y = h + &q + &q * &x;
+        assert_eq!(
+            export(&y, &[(&x, "x")], Syntax::Infix).unwrap(),
+            "t0 = 2*x + 2 + x\nt1 = relu(t0) + t0*x\nrelu(t0*t0) + t1 + t1*x"
+        );
+    }
+
+    #[test]
+    fn unnamed_leaves_are_literals() {
+        let x = Value::from(1.5);
+        let y = &x * Value::from(-2.0) + 0.25;
+        assert_eq!(export(&y, &[], Syntax::Infix).unwrap(), "1.5*(-2) + 0.25");
+        assert_eq!(export(&y, &[(&x, "x")], Syntax::Infix).unwrap(), "x*(-2) + 0.25");
+    }
+
+    #[test]
+    fn latex() {
+        let x = Value::from(2.0);
+        let y = Value::from(3.0);
+        let z = (&x * &y).powi(2) + x.log();
+        assert_eq!(
+            export(&z, &[(&x, "x"), (&y, "y")], Syntax::Latex).unwrap(),
+            "{\\left(x \\cdot y\\right)}^{2} + \\log\\left(x\\right)"
+        );
+    }
+
+    #[test]
+    fn rust_snippet() {
+        let x = Value::from(2.0);
+        let z = &x * &x;
+        let y = (&z + 1.0).relu() + z.powf(0.5) + x.powi(-2);
+        assert_eq!(
+            export(&y, &[(&x, "x")], Syntax::Rust).unwrap(),
+            "fn f(x: f64) -> f64 {\n    let t0 = x * x;\n    (t0 + 1.0_f64).max(0.0) + t0.powf(0.5_f64) + x.powi(-2)\n}"
+        );
+    }
+
+    #[test]
+    fn python_snippet() {
+        let x = Value::from(2.0);
+        let y = Value::from(1.0);
+        let z = (&x - &y).exp() + y.pow(x.clone());
+        assert_eq!(
+            export(&z, &[(&x, "x"), (&y, "y")], Syntax::Python).unwrap(),
+            "import math\n\n\ndef f(x, y):\n    return math.exp(x - y) + y ** x"
+        );
+    }
+
+    #[test]
+    fn tanh_and_sigmoid() {
+        let x = Value::from(0.5);
+        let y = (&x * 2.0).tanh() + x.sigmoid();
+        let inputs = [(&x, "x")];
+        assert_eq!(export(&y, &inputs, Syntax::Infix).unwrap(), "tanh(x*2) + sigmoid(x)");
+        assert_eq!(
+            export(&y, &inputs, Syntax::Latex).unwrap(),
+            "\\tanh\\left(x \\cdot 2\\right) + \\sigma\\left(x\\right)"
+        );
+        assert_eq!(
+            export(&y, &inputs, Syntax::Rust).unwrap(),
+            "fn f(x: f64) -> f64 {\n    (x * 2.0_f64).tanh() + 1.0 / (1.0 + (-x).exp())\n}"
+        );
+        assert_eq!(
+            export(&y, &inputs, Syntax::Python).unwrap(),
+            "import math\n\n\ndef f(x):\n    return math.tanh(x * 2.0) + 1.0 / (1.0 + math.exp(-x))"
+        );
+    }
+
+    #[test]
+    fn fused_nodes() {
+        let w = [Value::from(1.0), Value::from(2.0)];
+        let x = [Value::from(3.0), Value::from(4.0)];
+        let names = [(&w[0], "w0"), (&w[1], "w1"), (&x[0], "x0"), (&x[1], "x1")];
+        let y = dot(&w, &x) - prod(&x) + mean(&w);
+        assert_eq!(export(&y, &names, Syntax::Infix).unwrap(), "w0*x0 + w1*x1 - x0*x1 + mean(w0, w1)");
+        assert_eq!(
+            export(&y, &names, Syntax::Python).unwrap(),
+            "def f(w0, w1, x0, x1):\n    return w0 * x0 + w1 * x1 - x0 * x1 + (w0 + w1) / 2.0"
+        );
+        assert_eq!(export(&sum(&x).log(), &names, Syntax::Latex).unwrap(), "\\log\\left(x0 + x1\\right)");
+    }
+
+    #[test]
+    fn custom_nodes() {
+        let a = Value::from(1.0);
+        let b = Value::from(2.0);
+        let outputs = Value::custom_multi(&[0.0, 0.0], vec![a.clone(), b.clone()], "solve", |_, _| vec![0.0, 0.0]);
+        let y = &outputs[0] + &outputs[1] * Value::custom(0.0, vec![a.clone()], "g", |_, _| vec![0.0]);
+        assert_eq!(export(&y, &[(&a, "a"), (&b, "b")], Syntax::Infix).unwrap(), "t0 = solve(a, b)\nt0[0] + t0[1]*g(a)");
+    }
+
+    #[test]
+    fn named_intermediate_cuts_graph() {
+        let x = Value::from(2.0);
+        let hidden = (&x * 3.0).relu();
+        let y = &hidden * &hidden + &x;
+        assert_eq!(export(&y, &[(&x, "x"), (&hidden, "h")], Syntax::Infix).unwrap(), "h*h + x");
+    }
+
+    #[test]
+    fn hand_built_nodes() {
+        let x = Value::from(2.0);
+        let unrecorded = Value::new(4.0, Some(vec![x.clone()]), None);
+        assert_eq!(export(&unrecorded, &[(&x, "x")], Syntax::Infix).unwrap(), "op(x)");
+        let mismatched = Value::with_op(4.0, Some(vec![x.clone()]), None, Op::Add);
+        assert!(export(&mismatched, &[(&x, "x")], Syntax::Infix).is_err());
+        assert!(export(&(&mismatched + 1.0), &[(&x, "x")], Syntax::Rust).is_err());
+    }
+}
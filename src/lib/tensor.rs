@@ -0,0 +1,885 @@
+use crate::engine::{FloatDataScalar, Value, dot, mean, sum, to_vec};
+use crate::impl_binary_op;
+use crate::kernels::{gemm, transpose};
+use crate::nn::loss::{log_softmax, logsumexp};
+use anyhow::{Result, bail};
+use std::ops::{Add, Div, Mul, Sub};
+
+/// An n-dimensional array of `Value`s in row-major order.
+///
+/// Autograd still happens on the scalar `Value`s; a `Tensor` only tracks how they are laid out. Broadcasting repeats
+/// `Value` handles rather than copying them, so an operand element used by several outputs accumulates all of their
+/// grads, and backward reduces gradients back to each operand's original shape without any extra bookkeeping.
+#[derive(Debug, Clone)]
+pub struct Tensor {
+    data: Vec<Value>,
+    shape: Vec<usize>,
+}
+
+impl Tensor {
+    pub fn new(data: Vec<Value>, shape: &[usize]) -> Result<Self> {
+        let numel: usize = shape.iter().product();
+        if data.len() != numel {
+            bail!("{} values do not fit shape {shape:?}, which holds {numel}", data.len());
+        }
+        Ok(Self { data, shape: shape.to_vec() })
+    }
+
+    /// Build a tensor of fresh leaf values
+    pub fn from_data(data: &[FloatDataScalar], shape: &[usize]) -> Result<Self> {
+        Self::new(data.iter().map(Value::from).collect(), shape)
+    }
+
+    /// A 0-dimensional tensor holding a single value, which broadcasts against any shape
+    #[must_use]
+    pub fn scalar<T: Into<Value>>(value: T) -> Self {
+        Self { data: vec![value.into()], shape: vec![] }
+    }
+
+    #[must_use]
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    #[must_use]
+    pub fn ndim(&self) -> usize {
+        self.shape.len()
+    }
+
+    #[must_use]
+    pub fn numel(&self) -> usize {
+        self.data.len()
+    }
+
+    /// The underlying values, in row-major order
+    #[must_use]
+    pub fn values(&self) -> &[Value] {
+        &self.data
+    }
+
+    #[must_use]
+    pub fn data(&self) -> Vec<FloatDataScalar> {
+        to_vec(&self.data)
+    }
+
+    /// Gradient in row-major order, with the same shape as `self`. `None` if no element has received a grad
+    #[must_use]
+    pub fn grad(&self) -> Option<Vec<FloatDataScalar>> {
+        let grads: Vec<_> = self.data.iter().map(Value::grad).collect();
+        grads.iter().any(Option::is_some).then(|| grads.into_iter().map(|g| g.unwrap_or(0.0)).collect())
+    }
+
+    pub fn zero_grad(&self) {
+        self.data.iter().for_each(Value::zero_grad);
+    }
+
+    /// # Panics
+    /// If the tensor does not hold exactly one element. Reduce it first, e.g. with `sum`
+    pub fn backward(&self) {
+        assert_eq!(self.numel(), 1, "backward requires a single element, but the tensor has shape {:?}", self.shape);
+        self.data[0].backward();
+    }
+
+    #[must_use]
+    pub fn sum(&self) -> Value {
+        sum(&self.data)
+    }
+
+    #[must_use]
+    pub fn mean(&self) -> Value {
+        mean(&self.data)
+    }
+
+    /// Apply `f` to each element, keeping the shape
+    #[must_use]
+    pub fn map(&self, f: impl Fn(&Value) -> Value) -> Self {
+        Self { data: self.data.iter().map(f).collect(), shape: self.shape.clone() }
+    }
+
+    /// Repeat elements along broadcast dimensions to reach `shape`
+    pub fn broadcast_to(&self, shape: &[usize]) -> Result<Self> {
+        if broadcast_shapes(&self.shape, shape)? != shape {
+            bail!("cannot broadcast shape {:?} to {shape:?}", self.shape);
+        }
+        let data = source_indices(&self.shape, shape).into_iter().map(|idx| self.data[idx].clone()).collect();
+        Ok(Self { data, shape: shape.to_vec() })
+    }
+
+    /// Broadcast `self` and `rhs` against each other, then combine them elementwise with `f`
+    pub fn zip_with(&self, rhs: &Self, f: impl Fn(&Value, &Value) -> Value) -> Result<Self> {
+        let shape = broadcast_shapes(&self.shape, &rhs.shape)?;
+        let lhs_idx = source_indices(&self.shape, &shape);
+        let rhs_idx = source_indices(&rhs.shape, &shape);
+        let data = lhs_idx.into_iter().zip(rhs_idx).map(|(l, r)| f(&self.data[l], &rhs.data[r])).collect();
+        Ok(Self { data, shape })
+    }
+
+    /// Matrix product of an `m x k` and a `k x n` tensor.
+    ///
+    /// Computed on raw floats with a blocked kernel, and differentiated as a whole: the backward pass computes
+    /// `dA = dC·Bᵀ` and `dB = Aᵀ·dC` directly, instead of going through `m * n * k` scalar nodes
+    pub fn matmul(&self, rhs: &Self) -> Result<Self> {
+        let (&[m, k], &[k2, n]) = (self.shape(), rhs.shape()) else {
+            bail!("matmul expects 2-dimensional tensors, got shapes {:?} and {:?}", self.shape, rhs.shape);
+        };
+        if k != k2 {
+            bail!("matmul shape mismatch: {:?} and {:?}", self.shape, rhs.shape);
+        }
+        Ok(batched_matmul(self, rhs, 1, m, k, n, vec![m, n]))
+    }
+
+    /// Batched matrix product of a `b x m x k` and a `b x k x n` tensor. See `matmul`
+    pub fn bmm(&self, rhs: &Self) -> Result<Self> {
+        let (&[b, m, k], &[b2, k2, n]) = (self.shape(), rhs.shape()) else {
+            bail!("bmm expects 3-dimensional tensors, got shapes {:?} and {:?}", self.shape, rhs.shape);
+        };
+        if b != b2 || k != k2 {
+            bail!("bmm shape mismatch: {:?} and {:?}", self.shape, rhs.shape);
+        }
+        Ok(batched_matmul(self, rhs, b, m, k, n, vec![b, m, n]))
+    }
+}
+
+/// `batch` independent `m x k` by `k x n` products, as a single multi-output node whose ancestors are the elements
+/// of `lhs` followed by those of `rhs`
+fn batched_matmul(lhs: &Tensor, rhs: &Tensor, batch: usize, m: usize, k: usize, n: usize, shape: Vec<usize>) -> Tensor {
+    let (a, b) = (lhs.data(), rhs.data());
+    let mut c = vec![0.0; batch * m * n];
+    for idx in 0..batch {
+        gemm(&a[idx * m * k..][..m * k], &b[idx * k * n..][..k * n], &mut c[idx * m * n..][..m * n], m, k, n);
+    }
+
+    let backward = move |prev_nodes: &[Value], output_grads: &[FloatDataScalar]| {
+        let data = to_vec(prev_nodes);
+        let (a, b) = data.split_at(batch * m * k);
+        let mut grad_a = vec![0.0; a.len()];
+        let mut grad_b = vec![0.0; b.len()];
+        for idx in 0..batch {
+            let (a, b, grad_c) =
+                (&a[idx * m * k..][..m * k], &b[idx * k * n..][..k * n], &output_grads[idx * m * n..][..m * n]);
+            gemm(grad_c, &transpose(b, k, n), &mut grad_a[idx * m * k..][..m * k], m, n, k);
+            gemm(&transpose(a, m, k), grad_c, &mut grad_b[idx * k * n..][..k * n], k, m, n);
+        }
+        grad_a.extend(grad_b);
+        grad_a
+    };
+    let prev_nodes = lhs.data.iter().chain(&rhs.data).cloned().collect();
+    Tensor { data: Value::custom_multi(&c, prev_nodes, "matmul", backward), shape }
+}
+
+/// Shape manipulation. These only rearrange `Value` handles, so their backward is free: each element's grad flows
+/// straight back to the element it came from, and elements that are repeated (e.g. by `gather`) accumulate
+impl Tensor {
+    fn values_at(&self, indices: impl IntoIterator<Item = usize>) -> Vec<Value> {
+        indices.into_iter().map(|idx| self.data[idx].clone()).collect()
+    }
+
+    fn select_flat(&self, indices: impl IntoIterator<Item = usize>, shape: Vec<usize>) -> Self {
+        Self { data: self.values_at(indices), shape }
+    }
+
+    /// Same elements in the same row-major order, viewed with a new shape
+    pub fn reshape(&self, shape: &[usize]) -> Result<Self> {
+        if shape.iter().product::<usize>() != self.numel() {
+            bail!("cannot reshape {:?} to {shape:?}", self.shape);
+        }
+        Ok(Self { data: self.data.clone(), shape: shape.to_vec() })
+    }
+
+    /// Merge dimensions `start_dim..=end_dim` into one
+    pub fn flatten(&self, start_dim: usize, end_dim: usize) -> Result<Self> {
+        check_dim(end_dim, self.ndim())?;
+        if start_dim > end_dim {
+            bail!("flatten start_dim {start_dim} is after end_dim {end_dim}");
+        }
+        let merged = self.shape[start_dim..=end_dim].iter().product();
+        let shape = [&self.shape[..start_dim], &[merged], &self.shape[end_dim + 1..]].concat();
+        self.reshape(&shape)
+    }
+
+    /// Insert a dimension of size 1 at `dim`
+    pub fn unsqueeze(&self, dim: usize) -> Result<Self> {
+        check_dim(dim, self.ndim() + 1)?;
+        let shape = [&self.shape[..dim], &[1], &self.shape[dim..]].concat();
+        self.reshape(&shape)
+    }
+
+    /// Reorder dimensions, so that dimension `axis` of the result is dimension `dims[axis]` of `self`
+    pub fn permute(&self, dims: &[usize]) -> Result<Self> {
+        let mut seen = vec![false; self.ndim()];
+        for &dim in dims {
+            check_dim(dim, self.ndim())?;
+            if std::mem::replace(&mut seen[dim], true) {
+                bail!("permute dims {dims:?} repeat dimension {dim}");
+            }
+        }
+        if dims.len() != self.ndim() {
+            bail!("permute dims {dims:?} do not cover all {} dimensions", self.ndim());
+        }
+        let strides = strides(&self.shape);
+        let shape: Vec<usize> = dims.iter().map(|&dim| self.shape[dim]).collect();
+        let src_strides: Vec<usize> = dims.iter().map(|&dim| strides[dim]).collect();
+        Ok(self.select_flat(strided_indices(&shape, &src_strides, 0), shape))
+    }
+
+    /// Swap two dimensions
+    pub fn transpose(&self, dim0: usize, dim1: usize) -> Result<Self> {
+        check_dim(dim0, self.ndim())?;
+        check_dim(dim1, self.ndim())?;
+        let mut dims: Vec<usize> = (0..self.ndim()).collect();
+        dims.swap(dim0, dim1);
+        self.permute(&dims)
+    }
+
+    /// Elements `start..end` along `dim`
+    pub fn slice(&self, dim: usize, start: usize, end: usize) -> Result<Self> {
+        check_dim(dim, self.ndim())?;
+        if start > end || end > self.shape[dim] {
+            bail!("slice {start}..{end} out of range for dimension {dim} of shape {:?}", self.shape);
+        }
+        let strides = strides(&self.shape);
+        let mut shape = self.shape.clone();
+        shape[dim] = end - start;
+        Ok(self.select_flat(strided_indices(&shape, &strides, start * strides[dim]), shape))
+    }
+
+    /// Split into chunks of `size` along `dim`. The last chunk is smaller if `size` does not divide the dimension
+    pub fn split(&self, size: usize, dim: usize) -> Result<Vec<Self>> {
+        check_dim(dim, self.ndim())?;
+        if size == 0 {
+            bail!("split size must be positive");
+        }
+        let len = self.shape[dim];
+        (0..len).step_by(size).map(|start| self.slice(dim, start, (start + size).min(len))).collect()
+    }
+
+    /// Pick the entries `indices` along `dim`, in order. Indices may repeat
+    pub fn index_select(&self, dim: usize, indices: &[usize]) -> Result<Self> {
+        check_dim(dim, self.ndim())?;
+        let size = self.shape[dim];
+        if let Some(idx) = indices.iter().find(|&&idx| idx >= size) {
+            bail!("index {idx} out of range for dimension {dim} of shape {:?}", self.shape);
+        }
+        let (outer, inner) = outer_inner(&self.shape, dim);
+        let flat = (0..outer)
+            .flat_map(|o| indices.iter().flat_map(move |&idx| (0..inner).map(move |i| (o * size + idx) * inner + i)));
+        let mut shape = self.shape.clone();
+        shape[dim] = indices.len();
+        Ok(self.select_flat(flat.collect::<Vec<_>>(), shape))
+    }
+
+    /// Pick one entry along `dim` for every position, like `torch.gather`.
+    ///
+    /// `index` is laid out row-major with the shape of `self`, except that `dim` has length
+    /// `index.len() / (numel / shape[dim])`, which is also the shape of the result. E.g. for a `(batch, classes)`
+    /// tensor, `gather(1, labels)` picks `self[b][labels[b]]` for each row
+    pub fn gather(&self, dim: usize, index: &[usize]) -> Result<Self> {
+        check_dim(dim, self.ndim())?;
+        let size = self.shape[dim];
+        let (outer, inner) = outer_inner(&self.shape, dim);
+        if outer * inner == 0 || !index.len().is_multiple_of(outer * inner) {
+            bail!("{} indices do not fit shape {:?} along dimension {dim}", index.len(), self.shape);
+        }
+        if let Some(idx) = index.iter().find(|&&idx| idx >= size) {
+            bail!("index {idx} out of range for dimension {dim} of shape {:?}", self.shape);
+        }
+        let k = index.len() / (outer * inner);
+        let flat = index.iter().enumerate().map(|(pos, &idx)| (pos / (k * inner) * size + idx) * inner + pos % inner);
+        let mut shape = self.shape.clone();
+        shape[dim] = k;
+        Ok(self.select_flat(flat.collect::<Vec<_>>(), shape))
+    }
+
+    /// Join tensors along an existing dimension. All other dimensions must match
+    pub fn concat(tensors: &[Self], dim: usize) -> Result<Self> {
+        let Some(first) = tensors.first() else {
+            bail!("cannot concatenate an empty list of tensors");
+        };
+        check_dim(dim, first.ndim())?;
+        for tensor in tensors {
+            let mismatch = tensor.ndim() != first.ndim()
+                || tensor.shape.iter().zip(&first.shape).enumerate().any(|(axis, (a, b))| axis != dim && a != b);
+            if mismatch {
+                bail!("cannot concatenate shapes {:?} and {:?} along dimension {dim}", first.shape, tensor.shape);
+            }
+        }
+        let (outer, _) = outer_inner(&first.shape, dim);
+        let mut data = Vec::with_capacity(tensors.iter().map(Self::numel).sum());
+        for o in 0..outer {
+            for tensor in tensors {
+                let chunk = tensor.numel() / outer;
+                data.extend_from_slice(&tensor.data[o * chunk..(o + 1) * chunk]);
+            }
+        }
+        let mut shape = first.shape.clone();
+        shape[dim] = tensors.iter().map(|tensor| tensor.shape[dim]).sum();
+        Ok(Self { data, shape })
+    }
+
+    /// Join tensors of the same shape along a new dimension `dim`
+    pub fn stack(tensors: &[Self], dim: usize) -> Result<Self> {
+        if let Some(tensor) = tensors.iter().find(|tensor| tensor.shape != tensors[0].shape) {
+            bail!("cannot stack shapes {:?} and {:?}", tensors[0].shape, tensor.shape);
+        }
+        let unsqueezed: Vec<Self> = tensors.iter().map(|tensor| tensor.unsqueeze(dim)).collect::<Result<_>>()?;
+        Self::concat(&unsqueezed, dim)
+    }
+}
+
+/// Reductions along a dimension. With `keepdim`, the reduced dimension is kept with size 1, so that the result
+/// broadcasts against `self`; otherwise it is removed
+impl Tensor {
+    /// Flat indices of each 1-dimensional lane along `dim`, with lanes in row-major order of the other dimensions
+    fn lanes(&self, dim: usize) -> Result<Vec<Vec<usize>>> {
+        check_dim(dim, self.ndim())?;
+        let size = self.shape[dim];
+        let (outer, inner) = outer_inner(&self.shape, dim);
+        Ok((0..outer * inner)
+            .map(|lane| (0..size).map(|idx| (lane / inner * size + idx) * inner + lane % inner).collect())
+            .collect())
+    }
+
+    fn reduced_shape(&self, dim: usize, keepdim: bool) -> Vec<usize> {
+        let mut shape = self.shape.clone();
+        if keepdim {
+            shape[dim] = 1;
+        } else {
+            shape.remove(dim);
+        }
+        shape
+    }
+
+    /// Reduce each lane along `dim` to a single value with `f`
+    pub fn reduce(&self, dim: usize, keepdim: bool, f: impl Fn(&[Value]) -> Value) -> Result<Self> {
+        let data = self.lanes(dim)?.into_iter().map(|lane| f(&self.values_at(lane))).collect();
+        Ok(Self { data, shape: self.reduced_shape(dim, keepdim) })
+    }
+
+    /// Replace each lane along `dim` with `f` of it, which must return as many values as it is given
+    pub fn map_lanes(&self, dim: usize, f: impl Fn(&[Value]) -> Vec<Value>) -> Result<Self> {
+        let mut data = self.data.clone();
+        for lane in self.lanes(dim)? {
+            let mapped = f(&self.values_at(lane.iter().copied()));
+            if mapped.len() != lane.len() {
+                bail!("lane function returned {} values for a lane of {}", mapped.len(), lane.len());
+            }
+            for (idx, value) in lane.into_iter().zip(mapped) {
+                data[idx] = value;
+            }
+        }
+        Ok(Self { data, shape: self.shape.clone() })
+    }
+
+    pub fn sum_dim(&self, dim: usize, keepdim: bool) -> Result<Self> {
+        self.reduce(dim, keepdim, sum)
+    }
+
+    pub fn mean_dim(&self, dim: usize, keepdim: bool) -> Result<Self> {
+        self.reduce(dim, keepdim, mean)
+    }
+
+    /// Variance along `dim`, dividing by `n - 1` if `unbiased`, else by `n`
+    #[allow(clippy::cast_precision_loss)]
+    pub fn var_dim(&self, dim: usize, unbiased: bool, keepdim: bool) -> Result<Self> {
+        self.reduce(dim, keepdim, |lane| {
+            let lane_mean = mean(lane);
+            let centered: Vec<Value> = lane.iter().map(|v| v - &lane_mean).collect();
+            let dof = lane.len().saturating_sub(usize::from(unbiased));
+            dot(&centered, &centered) / dof as FloatDataScalar
+        })
+    }
+
+    pub fn std_dim(&self, dim: usize, unbiased: bool, keepdim: bool) -> Result<Self> {
+        Ok(self.var_dim(dim, unbiased, keepdim)?.map(|var| var.powf(0.5)))
+    }
+
+    /// Maximum along `dim` and its index within each lane. The gradient flows only to the selected element
+    pub fn max_dim(&self, dim: usize, keepdim: bool) -> Result<(Self, Vec<usize>)> {
+        self.select_along(dim, keepdim, |candidate, best| candidate > best)
+    }
+
+    /// Minimum along `dim` and its index within each lane. See `max_dim`
+    pub fn min_dim(&self, dim: usize, keepdim: bool) -> Result<(Self, Vec<usize>)> {
+        self.select_along(dim, keepdim, |candidate, best| candidate < best)
+    }
+
+    fn select_along(
+        &self,
+        dim: usize,
+        keepdim: bool,
+        better: fn(FloatDataScalar, FloatDataScalar) -> bool,
+    ) -> Result<(Self, Vec<usize>)> {
+        if self.shape.get(dim) == Some(&0) {
+            bail!("cannot select from an empty dimension {dim}");
+        }
+        let lanes = self.lanes(dim)?;
+        let indices: Vec<usize> = lanes
+            .iter()
+            .map(|lane| {
+                (1..lane.len()).fold(0, |best, idx| {
+                    if better(self.data[lane[idx]].data(), self.data[lane[best]].data()) { idx } else { best }
+                })
+            })
+            .collect();
+        let flat = lanes.iter().zip(&indices).map(|(lane, &idx)| lane[idx]);
+        Ok((self.select_flat(flat.collect::<Vec<_>>(), self.reduced_shape(dim, keepdim)), indices))
+    }
+
+    pub fn logsumexp_dim(&self, dim: usize, keepdim: bool) -> Result<Self> {
+        self.reduce(dim, keepdim, logsumexp)
+    }
+
+    pub fn log_softmax(&self, dim: usize) -> Result<Self> {
+        self.map_lanes(dim, log_softmax)
+    }
+
+    pub fn softmax(&self, dim: usize) -> Result<Self> {
+        Ok(self.log_softmax(dim)?.map(Value::exp))
+    }
+}
+
+/// Number of elements before and after `dim`, i.e. the number of `dim`-slices and the stride of `dim`
+pub(crate) fn outer_inner(shape: &[usize], dim: usize) -> (usize, usize) {
+    (shape[..dim].iter().product(), shape[dim + 1..].iter().product())
+}
+
+impl From<Vec<Value>> for Tensor {
+    /// A 1-dimensional tensor
+    fn from(data: Vec<Value>) -> Self {
+        let shape = vec![data.len()];
+        Self { data, shape }
+    }
+}
+
+/// Shape resulting from broadcasting `lhs` against `rhs`, following NumPy: shapes are aligned from the right, and
+/// each pair of dimensions must either match or contain a 1
+pub fn broadcast_shapes(lhs: &[usize], rhs: &[usize]) -> Result<Vec<usize>> {
+    let ndim = lhs.len().max(rhs.len());
+    let dim = |shape: &[usize], axis: usize| (axis + shape.len()).checked_sub(ndim).map_or(1, |axis| shape[axis]);
+    (0..ndim)
+        .map(|axis| match (dim(lhs, axis), dim(rhs, axis)) {
+            (l, r) if l == r || r == 1 => Ok(l),
+            (1, r) => Ok(r),
+            _ => bail!("shapes {lhs:?} and {rhs:?} cannot be broadcast together"),
+        })
+        .collect()
+}
+
+/// Row-major strides of `shape`
+pub(crate) fn strides(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for axis in (1..shape.len()).rev() {
+        strides[axis - 1] = strides[axis] * shape[axis];
+    }
+    strides
+}
+
+/// For each element of `out_shape`, the flat index of the element of `shape` that broadcasts onto it
+fn source_indices(shape: &[usize], out_shape: &[usize]) -> Vec<usize> {
+    // Broadcast dimensions, including the missing leading ones, get a stride of 0
+    let offset = out_shape.len() - shape.len();
+    let mut src_strides = vec![0; out_shape.len()];
+    for (axis, (stride, &size)) in strides(shape).into_iter().zip(shape).enumerate() {
+        if size != 1 {
+            src_strides[axis + offset] = stride;
+        }
+    }
+    strided_indices(out_shape, &src_strides, 0)
+}
+
+/// Walk `out_shape` in row-major order, returning `base + sum(position[axis] * src_strides[axis])` for each element
+pub(crate) fn strided_indices(out_shape: &[usize], src_strides: &[usize], base: usize) -> Vec<usize> {
+    let numel: usize = out_shape.iter().product();
+    let mut indices = Vec::with_capacity(numel);
+    let mut position = vec![0; out_shape.len()];
+    let mut src = base;
+    for _ in 0..numel {
+        indices.push(src);
+        // Advance the multi-index like an odometer, updating the source offset incrementally
+        for axis in (0..out_shape.len()).rev() {
+            position[axis] += 1;
+            src += src_strides[axis];
+            if position[axis] < out_shape[axis] {
+                break;
+            }
+            src -= src_strides[axis] * position[axis];
+            position[axis] = 0;
+        }
+    }
+    indices
+}
+
+fn check_dim(dim: usize, ndim: usize) -> Result<()> {
+    if dim >= ndim {
+        bail!("dimension {dim} out of range for a tensor with {ndim} dimensions");
+    }
+    Ok(())
+}
+
+impl_binary_op!(@tensor Add, add, _add, +);
+impl_binary_op!(@tensor Sub, sub, _sub, -);
+impl_binary_op!(@tensor Mul, mul, _mul, *);
+impl_binary_op!(@tensor Div, div, _div, /);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_close;
+
+    #[test]
+    fn broadcast_shape_rules() -> Result<()> {
+        assert_eq!(broadcast_shapes(&[2, 3], &[3])?, vec![2, 3]);
+        assert_eq!(broadcast_shapes(&[4, 1, 3], &[2, 1])?, vec![4, 2, 3]);
+        assert_eq!(broadcast_shapes(&[], &[5, 1])?, vec![5, 1]);
+        assert_eq!(broadcast_shapes(&[1], &[0])?, vec![0]);
+        assert!(broadcast_shapes(&[2, 3], &[2]).is_err());
+        assert!(broadcast_shapes(&[3, 1], &[1, 2, 2]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn add_bias_row() -> Result<()> {
+        let x = Tensor::from_data(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3])?;
+        let bias = Tensor::from_data(&[10.0, 20.0, 30.0], &[3])?;
+        let y = &x + &bias;
+        assert_eq!(y.shape(), &[2, 3]);
+        assert_eq!(y.data(), vec![11.0, 22.0, 33.0, 14.0, 25.0, 36.0]);
+
+        // Each bias element is used by both rows, so its grad is reduced over the batch
+        (&y * &y).sum().backward();
+        assert_eq!(bias.grad().unwrap(), vec![2.0 * (11.0 + 14.0), 2.0 * (22.0 + 25.0), 2.0 * (33.0 + 36.0)]);
+        assert_eq!(x.grad().unwrap(), vec![22.0, 44.0, 66.0, 28.0, 50.0, 72.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn scalar_operands() -> Result<()> {
+        let x = Tensor::from_data(&[1.0, 2.0, 4.0], &[3])?;
+        let scale = Value::from(3.0);
+        let y = 1.0 / (&x * &scale - 0.5);
+        assert_eq!(y.shape(), &[3]);
+        assert_close!(y.values()[2].data(), 1.0 / 11.5);
+
+        y.sum().backward();
+        let expected: FloatDataScalar = x.data().iter().map(|x| -x / 3.0f64.mul_add(*x, -0.5).powi(2)).sum();
+        assert_close!(scale.grad().unwrap(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn broadcast_to_and_shape_errors() -> Result<()> {
+        let x = Tensor::from_data(&[1.0, 2.0], &[2, 1])?;
+        let y = x.broadcast_to(&[3, 2, 4])?;
+        assert_eq!(y.shape(), &[3, 2, 4]);
+        assert_eq!(y.data()[..8], [1.0, 1.0, 1.0, 1.0, 2.0, 2.0, 2.0, 2.0]);
+        assert!(x.broadcast_to(&[4]).is_err());
+        assert!(x.broadcast_to(&[1]).is_err());
+        assert!(Tensor::from_data(&[1.0, 2.0, 3.0], &[2, 2]).is_err());
+        assert!(x.zip_with(&Tensor::from_data(&[1.0, 2.0, 3.0], &[3, 1])?, |a, b| a + b).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot be broadcast")]
+    fn incompatible_operator_panics() {
+        let _ = Tensor::from(vec![Value::from(1.0), Value::from(2.0)]) + Tensor::from(vec![Value::from(1.0); 3]);
+    }
+
+    #[test]
+    fn matmul_values_and_grads() -> Result<()> {
+        let a = Tensor::from_data(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3])?;
+        let b = Tensor::from_data(&[0.5, -1.0, 2.0, 0.0, -0.5, 1.5], &[3, 2])?;
+        let c = a.matmul(&b)?;
+        assert_eq!(c.shape(), &[2, 2]);
+        assert_eq!(c.data(), vec![3.0, 3.5, 9.0, 5.0]);
+
+        // Only the bottom-right output takes part: dA row 1 = B column 1, dB column 1 = A row 1
+        (&c.values()[3] * 2.0).backward();
+        assert_eq!(a.grad().unwrap(), vec![0.0, 0.0, 0.0, -2.0, 0.0, 3.0]);
+        assert_eq!(b.grad().unwrap(), vec![0.0, 8.0, 0.0, 10.0, 0.0, 12.0]);
+
+        assert!(a.matmul(&a).is_err());
+        assert!(a.matmul(&Tensor::from_data(&[1.0, 2.0, 3.0], &[3])?).is_err());
+        assert!(a.bmm(&b).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn matmul_with_itself() -> Result<()> {
+        // The same values appear on both sides of the product, so both contributions must accumulate
+        let a = Tensor::from_data(&[1.0, 2.0, 3.0, 4.0], &[2, 2])?;
+        a.matmul(&a)?.sum().backward();
+        // With J the all-ones matrix, d sum(A·A) / dA = J·Aᵀ + Aᵀ·J
+        assert_eq!(a.grad().unwrap(), vec![3.0 + 4.0, 7.0 + 4.0, 3.0 + 6.0, 7.0 + 6.0]);
+        Ok(())
+    }
+
+    /// A crabgrad tensor and a tch leaf tensor holding the same values
+    macro_rules! leaf_pair {
+        ($shape:expr, $offset:expr) => {{
+            let shape: &[usize] = $shape;
+            #[allow(clippy::cast_precision_loss)]
+            let data: Vec<FloatDataScalar> = (0..shape.iter().product::<usize>())
+                .map(|i| (i as FloatDataScalar).mul_add(0.37, $offset).sin() + 1.5)
+                .collect();
+            #[allow(clippy::cast_possible_wrap)]
+            let dims: Vec<i64> = shape.iter().map(|&d| d as i64).collect();
+            let theirs = tch::Tensor::from_slice(&data).reshape(dims).set_requires_grad(true);
+            (Tensor::from_data(&data, shape).unwrap(), theirs)
+        }};
+    }
+
+    /// Compare shapes and values, then backprop the sum of squares through both and compare the grads of the leaves
+    macro_rules! assert_matches_torch {
+        ($ours:expr, $theirs:expr, [$(($leaf:expr, $leaf_t:expr)),* $(,)?]) => {{
+            let (ours, theirs): (&Tensor, &tch::Tensor) = (&$ours, &$theirs);
+            #[allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+            let theirs_shape: Vec<usize> = theirs.size().iter().map(|&d| d as usize).collect();
+            assert_eq!(ours.shape(), theirs_shape);
+            let flat = |t: &tch::Tensor| Vec::<FloatDataScalar>::try_from(t.reshape([-1])).unwrap();
+            ours.data().iter().zip(flat(theirs)).for_each(|(l, r)| assert_close!(*l, r));
+
+            (ours * ours).sum().backward();
+            (theirs * theirs).sum(None).backward();
+            $(
+                let (leaf, leaf_t): (&Tensor, &tch::Tensor) = (&$leaf, &$leaf_t);
+                let grads_t = flat(&leaf_t.grad());
+                assert_eq!(leaf.numel(), grads_t.len());
+                leaf.grad().unwrap().iter().zip(grads_t).for_each(|(l, r)| assert_close!(*l, r));
+            )*
+        }};
+    }
+
+    #[test]
+    fn compare_torch_matmul_bmm() {
+        let (a, at) = leaf_pair!(&[5, 7], 0.0);
+        let (b, bt) = leaf_pair!(&[7, 3], 1.0);
+        assert_matches_torch!(a.matmul(&b).unwrap(), at.matmul(&bt), [(a, at), (b, bt)]);
+
+        let (a, at) = leaf_pair!(&[3, 4, 2], 2.0);
+        let (b, bt) = leaf_pair!(&[3, 2, 5], 3.0);
+        assert_matches_torch!(a.bmm(&b).unwrap(), at.bmm(&bt), [(a, at), (b, bt)]);
+    }
+
+    #[test]
+    fn compare_torch_mixed_rank() {
+        let cases: [(&[usize], &[usize]); 5] =
+            [(&[2, 3], &[3]), (&[4, 1, 3], &[2, 1]), (&[3], &[2, 4, 1]), (&[], &[2, 2]), (&[2, 1, 3], &[1, 4, 1])];
+        for (lhs_shape, rhs_shape) in cases {
+            let (a, at) = leaf_pair!(lhs_shape, 0.0);
+            let (b, bt) = leaf_pair!(rhs_shape, 1.0);
+            let y = (&a * &b - &a / &b) * (&b + 2.0);
+            let yt = (&at * &bt - &at / &bt) * (&bt + 2.0);
+            assert_matches_torch!(y, yt, [(a, at), (b, bt)]);
+        }
+    }
+
+    #[test]
+    fn compare_torch_reshape_flatten_unsqueeze() {
+        let (a, at) = leaf_pair!(&[2, 3, 4], 0.0);
+        // Scale by position so that a wrong element order shows up in the grads
+        let (w, wt) = leaf_pair!(&[6, 4], 1.0);
+        assert_matches_torch!(&a.reshape(&[6, 4]).unwrap() * &w, at.reshape([6, 4]) * &wt, [(a, at), (w, wt)]);
+
+        let (a, at) = leaf_pair!(&[2, 3, 4, 1], 0.0);
+        let (w, wt) = leaf_pair!(&[2, 12, 1], 1.0);
+        assert_matches_torch!(&a.flatten(1, 2).unwrap() * &w, at.flatten(1, 2) * &wt, [(a, at), (w, wt)]);
+
+        let (a, at) = leaf_pair!(&[3, 2], 0.0);
+        let y = a.unsqueeze(1).unwrap() * a.unsqueeze(0).unwrap();
+        assert_matches_torch!(y, at.unsqueeze(1) * at.unsqueeze(0), [(a, at)]);
+    }
+
+    #[test]
+    fn compare_torch_permute_transpose() {
+        let (a, at) = leaf_pair!(&[2, 3, 4], 0.0);
+        let (w, wt) = leaf_pair!(&[4, 2, 3], 1.0);
+        assert_matches_torch!(&a.permute(&[2, 0, 1]).unwrap() * &w, at.permute([2, 0, 1]) * &wt, [(a, at), (w, wt)]);
+
+        let (a, at) = leaf_pair!(&[2, 3, 4], 0.0);
+        let (w, wt) = leaf_pair!(&[4, 3, 2], 1.0);
+        assert_matches_torch!(&a.transpose(0, 2).unwrap() * &w, at.transpose(0, 2) * &wt, [(a, at), (w, wt)]);
+    }
+
+    #[test]
+    fn compare_torch_slice_split() {
+        let (a, at) = leaf_pair!(&[3, 5, 2], 0.0);
+        assert_matches_torch!(a.slice(1, 1, 4).unwrap(), at.narrow(1, 1, 3), [(a, at)]);
+
+        let (a, at) = leaf_pair!(&[7, 2], 0.0);
+        let chunks = a.split(3, 0).unwrap();
+        let chunks_t = at.split(3, 0);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[2].shape(), &[1, 2]);
+        let y = &chunks[0] * &chunks[1] + &chunks[2];
+        let yt = &chunks_t[0] * &chunks_t[1] + &chunks_t[2];
+        assert_matches_torch!(y, yt, [(a, at)]);
+    }
+
+    #[test]
+    fn compare_torch_index_select_gather() {
+        let (a, at) = leaf_pair!(&[3, 4], 0.0);
+        let indices = [3, 0, 3, 1, 3];
+        let indices_t = tch::Tensor::from_slice(&[3i64, 0, 3, 1, 3]);
+        assert_matches_torch!(a.index_select(1, &indices).unwrap(), at.index_select(1, &indices_t), [(a, at)]);
+
+        let (a, at) = leaf_pair!(&[3, 4, 2], 0.0);
+        let index = [2, 0, 1, 1, 0, 0, 2, 2, 1, 0, 0, 2];
+        let index_t = tch::Tensor::from_slice(&index.map(|i| i as i64)).reshape([3, 2, 2]);
+        assert_matches_torch!(a.gather(1, &index).unwrap(), at.gather(1, &index_t, false), [(a, at)]);
+
+        // Picking one class per row, as a loss would
+        let (logits, logits_t) = leaf_pair!(&[4, 3], 0.0);
+        let labels = [2, 0, 0, 1];
+        let labels_t = tch::Tensor::from_slice(&[2i64, 0, 0, 1]).unsqueeze(1);
+        let picked = logits.gather(1, &labels).unwrap();
+        assert_eq!(picked.shape(), &[4, 1]);
+        assert_matches_torch!(picked, logits_t.gather(1, &labels_t, false), [(logits, logits_t)]);
+    }
+
+    #[test]
+    fn compare_torch_concat_stack() {
+        let (a, at) = leaf_pair!(&[2, 3, 2], 0.0);
+        let (b, bt) = leaf_pair!(&[2, 1, 2], 1.0);
+        let y = Tensor::concat(&[a.clone(), b.clone(), a.clone()], 1).unwrap();
+        let yt = tch::Tensor::cat(&[&at, &bt, &at], 1);
+        assert_matches_torch!(y, yt, [(a, at), (b, bt)]);
+
+        let (a, at) = leaf_pair!(&[2, 3], 0.0);
+        let (b, bt) = leaf_pair!(&[2, 3], 1.0);
+        for dim in 0..=2 {
+            a.zero_grad();
+            b.zero_grad();
+            let _ = at.grad().zero_();
+            let _ = bt.grad().zero_();
+            let y = Tensor::stack(&[a.clone(), b.clone()], dim).unwrap();
+            #[allow(clippy::cast_possible_wrap)]
+            let yt = tch::Tensor::stack(&[&at, &bt], dim as i64);
+            assert_matches_torch!(y, yt, [(a, at), (b, bt)]);
+        }
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn shape_ops_by_hand() -> Result<()> {
+        let a = Tensor::from_data(&(0..6).map(|i| i as FloatDataScalar).collect::<Vec<_>>(), &[2, 3])?;
+        assert_eq!(a.transpose(0, 1)?.data(), vec![0.0, 3.0, 1.0, 4.0, 2.0, 5.0]);
+        assert_eq!(a.slice(1, 1, 3)?.data(), vec![1.0, 2.0, 4.0, 5.0]);
+        assert_eq!(a.index_select(1, &[2, 2, 0])?.data(), vec![2.0, 2.0, 0.0, 5.0, 5.0, 3.0]);
+        assert_eq!(a.gather(1, &[2, 0])?.data(), vec![2.0, 3.0]);
+        assert_eq!(a.gather(0, &[1, 0, 1])?.data(), vec![3.0, 1.0, 5.0]);
+        assert_eq!(
+            Tensor::concat(&[a.clone(), a.slice(1, 0, 1)?], 1)?.data(),
+            vec![0.0, 1.0, 2.0, 0.0, 3.0, 4.0, 5.0, 3.0]
+        );
+        assert_eq!(Tensor::stack(&[a.clone(), a.clone()], 2)?.data()[..4], [0.0, 0.0, 1.0, 1.0]);
+
+        let b = Tensor::from_data(&(0..24).map(|i| i as FloatDataScalar).collect::<Vec<_>>(), &[2, 3, 4])?;
+        let p = b.permute(&[2, 0, 1])?;
+        assert_eq!(p.shape(), &[4, 2, 3]);
+        // p[1][1][2] = b[1][2][1]
+        assert_eq!(p.values()[6 + 3 + 2].data(), 21.0);
+
+        // Repeated picks accumulate into the same element
+        a.index_select(1, &[2, 2, 0])?.sum().backward();
+        assert_eq!(a.grad().unwrap(), vec![1.0, 0.0, 2.0, 1.0, 0.0, 2.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn reductions_by_hand() -> Result<()> {
+        let a = Tensor::from_data(&[1.0, 5.0, 3.0, 4.0, 2.0, 6.0], &[2, 3])?;
+        assert_eq!(a.sum_dim(0, false)?.data(), vec![5.0, 7.0, 9.0]);
+        assert_eq!(a.sum_dim(1, true)?.shape(), &[2, 1]);
+        assert_eq!(a.mean_dim(1, false)?.data(), vec![3.0, 4.0]);
+        assert_eq!(a.var_dim(1, true, false)?.data(), vec![4.0, 4.0]);
+        assert_eq!(a.var_dim(1, false, false)?.data(), vec![8.0 / 3.0, 8.0 / 3.0]);
+
+        let (max, argmax) = a.max_dim(1, false)?;
+        assert_eq!((max.data(), argmax), (vec![5.0, 6.0], vec![1, 2]));
+        let (min, argmin) = a.min_dim(0, true)?;
+        assert_eq!((min.shape(), min.data(), argmin), (&[1, 3][..], vec![1.0, 2.0, 3.0], vec![0, 1, 0]));
+
+        // Softmax over each row sums to one, and the max-shifted logsumexp is unchanged by large offsets
+        let probs = a.softmax(1)?;
+        for row in probs.sum_dim(1, false)?.data() {
+            assert_close!(row, 1.0);
+        }
+        let shifted = Tensor::from_data(&a.data().iter().map(|x| x + 1000.0).collect::<Vec<_>>(), &[2, 3])?;
+        let lse = a.logsumexp_dim(1, false)?.data();
+        for (l, r) in lse.iter().zip(shifted.logsumexp_dim(1, false)?.data()) {
+            assert_close!(l + 1000.0, r);
+        }
+
+        // Normalizing by a keepdim reduction broadcasts back over the reduced dimension
+        let centered = &a - &a.mean_dim(0, true)?;
+        assert_eq!(centered.data(), vec![-1.5, 1.5, -1.5, 1.5, -1.5, 1.5]);
+
+        assert!(a.sum_dim(2, false).is_err());
+        assert!(Tensor::from_data(&[], &[2, 0])?.max_dim(1, false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn compare_torch_reductions() {
+        let (a, at) = leaf_pair!(&[3, 4, 2], 0.0);
+        let y = &a.sum_dim(1, false).unwrap() * &a.mean_dim(1, false).unwrap();
+        let yt = at.sum_dim_intlist(1, false, tch::Kind::Double) * at.mean_dim(1, false, tch::Kind::Double);
+        assert_matches_torch!(y, yt, [(a, at)]);
+
+        for unbiased in [true, false] {
+            let (a, at) = leaf_pair!(&[3, 4, 2], 1.0);
+            let y = a.var_dim(2, unbiased, true).unwrap() + a.std_dim(0, unbiased, false).unwrap();
+            let yt = at.var_dim(2, unbiased, true) + at.std_dim(0, unbiased, false);
+            assert_matches_torch!(y, yt, [(a, at)]);
+        }
+
+        let (a, at) = leaf_pair!(&[3, 4, 2], 2.0);
+        let ((max, argmax), (max_t, argmax_t)) = (a.max_dim(1, true).unwrap(), at.max_dim(1, true));
+        assert_eq!(
+            argmax,
+            Vec::<i64>::try_from(argmax_t.reshape([-1])).unwrap().iter().map(|&i| i as usize).collect::<Vec<_>>()
+        );
+        assert_matches_torch!(max, max_t, [(a, at)]);
+
+        let (a, at) = leaf_pair!(&[3, 4, 2], 3.0);
+        let ((min, argmin), (min_t, argmin_t)) = (a.min_dim(0, false).unwrap(), at.min_dim(0, false));
+        assert_eq!(
+            argmin,
+            Vec::<i64>::try_from(argmin_t.reshape([-1])).unwrap().iter().map(|&i| i as usize).collect::<Vec<_>>()
+        );
+        assert_matches_torch!(min, min_t, [(a, at)]);
+
+        let (a, at) = leaf_pair!(&[3, 4, 2], 4.0);
+        assert_matches_torch!(a.logsumexp_dim(1, false).unwrap(), at.logsumexp([1], false), [(a, at)]);
+
+        let (a, at) = leaf_pair!(&[3, 4, 2], 5.0);
+        let (w, wt) = leaf_pair!(&[3, 4, 2], 6.0);
+        let y = &a.softmax(1).unwrap() * &w + a.log_softmax(2).unwrap();
+        let yt = at.softmax(1, tch::Kind::Double) * &wt + at.log_softmax(2, tch::Kind::Double);
+        assert_matches_torch!(y, yt, [(a, at), (w, wt)]);
+    }
+
+    #[test]
+    fn shape_op_errors() -> Result<()> {
+        let a = Tensor::from_data(&[1.0; 6], &[2, 3])?;
+        assert!(a.reshape(&[4]).is_err());
+        assert!(a.flatten(1, 0).is_err());
+        assert!(a.flatten(0, 2).is_err());
+        assert!(a.unsqueeze(3).is_err());
+        assert!(a.permute(&[0, 0]).is_err());
+        assert!(a.permute(&[1]).is_err());
+        assert!(a.transpose(0, 2).is_err());
+        assert!(a.slice(1, 2, 4).is_err());
+        assert!(a.slice(1, 2, 1).is_err());
+        assert!(a.split(0, 0).is_err());
+        assert!(a.index_select(0, &[2]).is_err());
+        assert!(a.gather(1, &[0, 1, 2]).is_err());
+        assert!(a.gather(1, &[0, 3]).is_err());
+        assert!(Tensor::concat(&[], 0).is_err());
+        assert!(Tensor::concat(&[a.clone(), a.transpose(0, 1)?], 0).is_err());
+        assert!(Tensor::stack(&[a.clone(), a.reshape(&[3, 2])?], 0).is_err());
+        assert_eq!(Tensor::concat(&[a.clone(), a.clone()], 1)?.shape(), &[2, 6]);
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+//! `Trainer` sets up the global logger, so training runs in its own test binary rather than next to the unit tests
+
+use anyhow::Result;
+use crabgrad::nn::models::Classifier;
+use crabgrad::nn::{Activation, Conv2d, Conv2dConfig, Layer, MaxPool2d, Module, Sequential, Trainer};
+use crabgrad::optim::SGD;
+use crabgrad::{FloatDataScalar, Tensor, Value};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+/// 6x6 images of a single vertical (label 0) or horizontal (label 1) bar
+fn bars() -> Vec<(Vec<FloatDataScalar>, usize)> {
+    let mut data_labels = vec![];
+    for pos in 0..6 {
+        for label in 0..2 {
+            let mut image = vec![0.0; 36];
+            for along in 0..6 {
+                let (row, col) = if label == 0 { (along, pos) } else { (pos, along) };
+                image[row * 6 + col] = 1.0;
+            }
+            data_labels.push((image, label));
+        }
+    }
+    data_labels
+}
+
+#[test]
+fn conv_stack_trains_in_sequential() -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let model = Sequential::new()
+        .add(Conv2d::new(1, 4, (3, 3), Conv2dConfig::default(), &mut rng)?.with_input_shape((1, 6, 6)))
+        .add(Activation::ReLU)
+        .add(MaxPool2d::new((2, 2)).with_input_shape((4, 4, 4)))
+        .add(Layer::new(4 * 2 * 2, 2, true, Activation::Identity, &mut rng));
+
+    // Samples one at a time agree with the batched rows the trainer uses
+    let sample: Vec<Value> = bars()[0].0.iter().map(Value::from).collect();
+    let single: Vec<FloatDataScalar> = model.forward(&sample)?.iter().map(Value::data).collect();
+    let batch = Tensor::new(sample, &[1, 36])?;
+    assert_eq!(single, model.forward_tensor(&batch)?.data());
+
+    let mut optim = SGD::new(&model.parameters(), 0.05);
+    Trainer::new(&model, &mut optim, 20, 4).fit(bars(), Some(bars()))?;
+
+    let data_labels: Vec<(Vec<Value>, usize)> =
+        bars().into_iter().map(|(image, label)| (image.iter().map(Value::from).collect(), label)).collect();
+    assert_eq!(model.score(&data_labels)?, 1.0);
+    Ok(())
+}